@@ -3,17 +3,20 @@ use std::rc::Rc;
 use std::cell::RefCell;
 use std::error::Error;
 use std::env;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
-use buffer::Buffer;
+use buffer::{Buffer, TabStyle};
+use movement::Movement;
 use res::Resources;
-use lsp::LanguageServer;
+use lsp::{self, LanguageServer, ServerCapabilities};
+use keymap::{Keymap, Key, Resolved};
 use mode;
+use popup;
+use traffic::Direction;
 
 use winit::Event;
 use regex::Regex;
 
-use super::ConfigError;
 
 
 #[derive(Debug,Clone,PartialEq,Eq,Hash)]
@@ -25,12 +28,77 @@ pub struct State {
     pub last_buffer: usize,
     pub current_buffer: usize,
     pub clipstacks: HashMap<ClipstackId, Vec<String>>,
+    /// registers last filled by q, which hold keys written like <Esc> rather than plain text
+    pub recorded: HashSet<ClipstackId>,
     pub should_quit: bool,
-    pub language_servers: Vec<(Regex, Rc<RefCell<LanguageServer>>)>,
-    pub status_text: Option<String>
+    /// every language server in the config, whether it is running or not
+    pub language_servers: Vec<lsp::ServerSlot>,
+    pub status_text: Option<String>,
+    pub search_history: Vec<String>,
+    pub search_forward: bool,
+    /// the last search, which has its matches highlighted
+    pub search_regex: Option<Regex>,
+    pub last_change: Option<mode::LastChange>,
+    /// the register being recorded into with q, and the keys sent to the mode so far
+    pub recording: Option<(ClipstackId, Vec<Key>)>,
+    /// the register last played with @, for @@
+    pub last_macro: Option<ClipstackId>,
+    /// how many macros are playing inside each other
+    pub macro_depth: usize,
+    /// places jumped away from, as (buffer, line anchor, column), for Ctrl-O/Ctrl-I
+    jumps: Vec<(usize, usize, usize)>,
+    /// where Ctrl-O/Ctrl-I have walked to in `jumps`, which is its length when they haven't been used
+    jump_pos: usize,
+    /// the language server's hover text from K, shown by the cursor until the next key
+    pub hover: Option<String>,
+    /// a mode to switch to, from something that can't return one, like a language server response
+    pub next_mode: Option<Box<mode::Mode>>
 }
 
+// how many jumps are remembered
+const MAX_JUMPS: usize = 100;
+// how much of the hover text fits in its panel
+const HOVER_LINES: usize = 30;
+// what :lsp log calls its buffers, before the server's name
+const LOG_BUFFER_PREFIX: &str = "lsp log: ";
+
 impl State {
+    /// the command line and one empty buffer
+    pub fn new(res: Rc<RefCell<Resources>>, language_servers: Vec<lsp::ServerSlot>) -> State {
+        let buf = Rc::new(RefCell::new(Buffer::new(res.clone())));
+                //env::args().nth(1).map_or_else(|| Buffer::new(res.clone()),
+                //|p| Buffer::load(Path::new(&p), res.clone()).expect("open file"))  ));
+        let cmd = Rc::new(RefCell::new(Buffer::new(res.clone())));
+        { cmd.borrow_mut().show_cursor = false; }
+        State {
+            bufs: vec![cmd, buf],
+            current_buffer: 1, last_buffer: 1,
+            clipstacks: HashMap::new(), recorded: HashSet::new(), res,
+            should_quit: false,
+            language_servers,
+            status_text: None,
+            search_history: Vec::new(),
+            search_forward: true,
+            search_regex: None,
+            last_change: None,
+            recording: None,
+            last_macro: None,
+            macro_depth: 0,
+            jumps: Vec::new(),
+            jump_pos: 0,
+            hover: None,
+            next_mode: None
+        }
+    }
+
+    /// a state with `text` in its buffer, for tests of things that need one
+    #[cfg(test)]
+    pub fn with_text(text: &str) -> State {
+        let st = State::new(Rc::new(RefCell::new(Resources::without_font(None))), Vec::new());
+        st.buf().borrow_mut().set_text(text);
+        st
+    }
+
     pub fn buf(&self) -> Rc<RefCell<Buffer>> {
         self.bufs[self.current_buffer].clone()
     }
@@ -40,6 +108,7 @@ impl State {
     }
 
     pub fn push_clip(&mut self, id: &ClipstackId, s: String) {
+        self.recorded.remove(id);
         let mut stack = self.clipstacks.entry(id.clone()).or_insert(Vec::new());
         stack.push(s);
     }
@@ -49,31 +118,675 @@ impl State {
     }
 
     pub fn pop_clip(&mut self, id: &ClipstackId) -> Option<String> {
+        self.recorded.remove(id);
         self.clipstacks.get_mut(id).and_then(|sk| sk.pop())
     }
 
+    // the last search's regex, compiled again if :noh has cleared it
+    fn last_search(&self) -> Result<Regex, Box<Error>> {
+        match (&self.search_regex, self.search_history.last()) {
+            (&Some(ref re), _) => Ok(re.clone()),
+            (&None, Some(p)) => Ok(Regex::new(p)?),
+            (&None, None) => Err(Box::new(mode::CommandError::InvalidCommand(Some("no previous search"))))
+        }
+    }
+
+    /// movements that depend on the editor state, like repeating the last search, are turned into
+    /// ones that a Buffer can carry out by itself. Search patterns are compiled here, so a bad one
+    /// is an error
+    pub fn resolve_movement(&self, mv: &Movement) -> Result<Movement, Box<Error>> {
+        match mv {
+            &Movement::SearchNext(reverse) => Ok(Movement::Match(self.last_search()?, self.search_forward != reverse)),
+            &Movement::Search { ref pattern, forward } => Ok(Movement::Match(match self.search_regex {
+                Some(ref re) if re.as_str() == pattern => re.clone(),
+                _ => Regex::new(pattern)?
+            }, forward)),
+            &Movement::Mark(c, _) => match self.mark_buffer(c) {
+                Some(ix) if ix == self.current_buffer => Ok(mv.clone()),
+                Some(_) => Err(Box::new(mode::CommandError::InvalidCommand(Some("mark is in another buffer")))),
+                None => Err(Box::new(mode::CommandError::InvalidCommand(Some("mark not set"))))
+            },
+            &Movement::Rep(n, ref m) => Ok(Movement::Rep(n, Box::new(self.resolve_movement(m)?))),
+            _ => Ok(mv.clone())
+        }
+    }
+
     pub fn move_to_buffer(&mut self, ix: usize) {
+        if ix != self.current_buffer { self.push_jump(); }
         self.last_buffer = self.current_buffer;
         self.current_buffer = ix;
+        self.bufs[ix].borrow_mut().hidden = false;
+    }
+
+    /// set a mark at the cursor. Uppercase marks are global, so they are taken out of any other
+    /// buffer that has them
+    pub fn set_mark(&mut self, c: char) {
+        if c.is_uppercase() {
+            for b in self.bufs.iter() { b.borrow_mut().remove_mark(c); }
+        }
+        self.mutate_buf(|b| { let cur = b.curr_loc(); b.set_mark(c, cur) });
+    }
+
+    /// the buffer that has a mark, which for lowercase marks can only be the current one
+    pub fn mark_buffer(&self, c: char) -> Option<usize> {
+        if c.is_uppercase() {
+            self.bufs.iter().position(|b| b.borrow().mark(c).is_some())
+        } else if self.buf().borrow().mark(c).is_some() {
+            Some(self.current_buffer)
+        } else {
+            None
+        }
+    }
+
+    // a jump list entry for where the cursor is now
+    fn here(&mut self) -> (usize, usize, usize) {
+        let ix = self.current_buffer;
+        self.mutate_buf(|b| {
+            let (col, line) = b.curr_loc();
+            (ix, b.add_line_anchor(line), col)
+        })
+    }
+
+    fn drop_jumps(&mut self, from: usize) {
+        for (b, a, _) in self.jumps.drain(from..) {
+            if let Some(b) = self.bufs.get(b) { b.borrow_mut().remove_line_anchor(a); }
+        }
+    }
+
+    /// remember the cursor position in the jump list, before making a big jump. Anything that was
+    /// walked back over with Ctrl-O is forgotten
+    pub fn push_jump(&mut self) {
+        let pos = self.jump_pos;
+        self.drop_jumps(pos);
+        let (ix, line) = (self.current_buffer, self.buf().borrow().cursor_line);
+        // only keep the latest jump from each line
+        let bufs = &self.bufs;
+        self.jumps.retain(|&(b, a, _)| {
+            let same = b == ix && bufs[b].borrow().line_anchor(a) == Some(line);
+            if same { bufs[b].borrow_mut().remove_line_anchor(a); }
+            !same
+        });
+        if self.jumps.len() >= MAX_JUMPS {
+            let (b, a, _) = self.jumps.remove(0);
+            self.bufs[b].borrow_mut().remove_line_anchor(a);
+        }
+        let j = self.here();
+        self.jumps.push(j);
+        self.jump_pos = self.jumps.len();
+    }
+
+    /// go back (Ctrl-O) or forward (Ctrl-I) through the jump list `count` times, skipping places
+    /// whose line has been deleted
+    pub fn jump(&mut self, back: bool, count: usize) {
+        if back && self.jump_pos == self.jumps.len() && self.jump_pos > 0 {
+            // remember where the walk started, so Ctrl-I can come back to it
+            let j = self.here();
+            self.jumps.push(j);
+        }
+        let mut target = None;
+        let mut pos = self.jump_pos;
+        let mut left = count;
+        while left > 0 {
+            let next = if back { pos.checked_sub(1) } else if pos + 1 < self.jumps.len() { Some(pos + 1) } else { None };
+            pos = match next { Some(p) => p, None => break };
+            let (b, a, col) = self.jumps[pos];
+            if let Some(line) = self.bufs[b].borrow().line_anchor(a) {
+                target = Some((pos, b, col, line));
+                left -= 1;
+            }
+        }
+        if let Some((pos, b, col, line)) = target {
+            self.jump_pos = pos;
+            if b != self.current_buffer {
+                self.last_buffer = self.current_buffer;
+                self.current_buffer = b;
+                self.bufs[b].borrow_mut().hidden = false;
+            }
+            self.mutate_buf(|buf| buf.place_cursor(col, line));
+        }
     }
     
-    pub fn language_server_for_file_type(&mut self, file_ext: &str) -> Result<Option<Rc<RefCell<LanguageServer>>>, Box<Error>> {
-        for (ref test, ref lsp) in self.language_servers.iter() {
-            if test.is_match(file_ext) {
-                return Ok(Some(lsp.clone()));
-            }
-        }
-        if let Some(cfgs) = self.res.borrow().config.as_ref().and_then(|c| c.get("language-server")).and_then(|c| c.as_array()) {
-            for cfg in cfgs {
-                let test = Regex::new(cfg.get("file-extention").ok_or(ConfigError::Missing("language server file extention regex"))?.as_str().ok_or(ConfigError::Invalid("language server file extention regex"))?)?;
-                if test.is_match(file_ext) {
-                    let lsp = Rc::new(RefCell::new(LanguageServer::new(&cfg)?));
-                    self.language_servers.push((test, lsp.clone()));
-                    return Ok(Some(lsp.clone()));
+    /// store the diagnostics from a publishDiagnostics notification, from the language server at
+    /// `server` in `language_servers`, in the buffer they are for
+    pub fn publish_diagnostics(&mut self, server: usize, params: &::json::JsonValue) {
+        let path = match params["uri"].as_str().and_then(lsp::uri_to_path) {
+            Some(p) => p,
+            None => return
+        };
+        for b in self.bufs.iter() {
+            let mut b = b.borrow_mut();
+            if b.fs_loc.as_ref() == Some(&path) {
+                b.set_diagnostics(server, &params["diagnostics"]);
+            }
+        }
+    }
+
+    /// the buffer that has a file open, loading it into a new one if there isn't one
+    pub fn open_file(&mut self, path: &Path) -> Result<usize, Box<Error>> {
+        match self.bufs.iter().position(|b| b.borrow().fs_loc.as_ref().map(|p| p.as_path()) == Some(path)) {
+            Some(ix) => Ok(ix),
+            None => {
+                let buf = Rc::new(RefCell::new(Buffer::load(path, self)?));
+                self.bufs.push(buf);
+                Ok(self.bufs.len()-1)
+            }
+        }
+    }
+
+    /// in a list buffer like :diagnostics, go to the place the cursor line refers to, opening its
+    /// file if it isn't already. Returns false if the line doesn't refer to anywhere
+    pub fn follow_location(&mut self) -> Result<bool, Box<Error>> {
+        let (path, loc) = {
+            let b = self.buf();
+            let b = b.borrow();
+            match b.locations.as_ref().and_then(|ls| ls.get(b.cursor_line)).and_then(|l| l.clone()) {
+                Some(l) => l,
+                None => return Ok(false)
+            }
+        };
+        let ix = self.open_file(&path)?;
+        self.move_to_buffer(ix);
+        self.mutate_buf(|b| b.place_cursor(loc.0, loc.1));
+        Ok(true)
+    }
+
+    /// show `text` in the list buffer, where Return on a line goes to its entry in `locations`.
+    /// The list buffer is reused each time
+    pub fn show_locations(&mut self, text: &str, locations: Vec<Option<(PathBuf, (usize, usize))>>) {
+        let ix = match self.bufs.iter().position(|b| b.borrow().locations.is_some()) {
+            Some(ix) => ix,
+            None => {
+                self.bufs.push(Rc::new(RefCell::new(Buffer::new(self.res.clone()))));
+                self.bufs.len()-1
+            }
+        };
+        {
+            let mut b = self.bufs[ix].borrow_mut();
+            b.clear();
+            b.insert_text((0, 0), text.trim_right_matches('\n'));
+            b.locations = Some(locations);
+            b.place_cursor(0, 0);
+        }
+        self.move_to_buffer(ix);
+    }
+
+    /// go to an LSP position in a file, opening it if it isn't already
+    pub fn goto_lsp_location(&mut self, path: &Path, pos: (usize, usize)) -> Result<(), Box<Error>> {
+        let ix = self.open_file(path)?;
+        // changing buffer remembers the jump by itself
+        if ix == self.current_buffer { self.push_jump(); } else { self.move_to_buffer(ix); }
+        self.mutate_buf(|b| { let (col, line) = b.from_lsp_position(pos); b.place_cursor(col, line) });
+        Ok(())
+    }
+
+    /// list LSP locations in the list buffer, each with the text of its line. Files that aren't
+    /// open are read from disk rather than loaded
+    pub fn list_lsp_locations(&mut self, ls: Vec<(PathBuf, (usize, usize))>) -> Result<(), Box<Error>> {
+        use std::fs::File;
+        use std::io::Read;
+        let cd = env::current_dir()?;
+        let mut files: HashMap<PathBuf, Vec<String>> = HashMap::new();
+        let mut text = String::new();
+        let mut locations = Vec::new();
+        for (path, (line, character)) in ls {
+            let (loc, content) = match self.bufs.iter().find(|b| b.borrow().fs_loc.as_ref() == Some(&path)) {
+                Some(b) => {
+                    let b = b.borrow();
+                    let loc = b.from_lsp_position((line, character));
+                    (loc, b.line(loc.1))
+                },
+                None => {
+                    if !files.contains_key(&path) {
+                        let mut s = String::new();
+                        File::open(&path)?.read_to_string(&mut s)?;
+                        files.insert(path.clone(), s.lines().map(String::from).collect());
+                    }
+                    let l = files[&path].get(line).cloned().unwrap_or_default();
+                    ((lsp::utf16_to_byte_col(&l, character), line), l)
+                }
+            };
+            text.push_str(&format!("{}:{}:{}: {}\n", path.strip_prefix(&cd).unwrap_or(&path).display(),
+                loc.1+1, loc.0+1, content.trim()));
+            locations.push(Some((path, loc)));
+        }
+        self.show_locations(&text, locations);
+        Ok(())
+    }
+
+    // the current buffer's first language server that can do something, going by `can`, with the
+    // params for a request about the cursor position. Edits the servers haven't seen yet are sent
+    // first. `what` is the error if none of them can
+    fn cursor_request<F>(&mut self, can: F, what: &'static str) -> Result<(Rc<RefCell<LanguageServer>>, ::json::JsonValue), Box<Error>>
+        where F: Fn(&ServerCapabilities) -> bool
+    {
+        let ls = self.server_supporting(can, what)?;
+        let buf = self.buf();
+        let mut buf = buf.borrow_mut();
+        let uri = buf.uri().ok_or(mode::CommandError::InvalidCommand(Some("buffer has no file")))?;
+        buf.sync_language_server(true);
+        let pos = buf.lsp_position(buf.curr_loc());
+        Ok((ls, object!{
+            "textDocument" => object!{ "uri" => uri },
+            "position" => lsp::position_to_json(pos)
+        }))
+    }
+
+    /// ask the language server where the symbol under the cursor is defined (or declared), and
+    /// go there. If there is more than one place they are listed instead
+    pub fn goto_definition(&mut self, declaration: bool) -> Result<(), Box<Error>> {
+        let method = if declaration { "textDocument/declaration" } else { "textDocument/definition" };
+        let (ls, params) = self.cursor_request(|c| if declaration { c.declaration } else { c.definition },
+            "language server can't find definitions")?;
+        ls.borrow_mut().request(method, params, move |r, app| {
+            let mut ls = match r {
+                Ok(v) => lsp::parse_locations(&v),
+                Err(e) => { app.status_text = Some(format!("{}: {}", method, e)); return }
+            };
+            let r = match ls.len() {
+                0 => { app.status_text = Some(String::from("no definition found")); Ok(()) },
+                1 => { let (path, pos) = ls.remove(0); app.goto_lsp_location(&path, pos) },
+                _ => app.list_lsp_locations(ls)
+            };
+            if let Err(e) = r { app.status_text = Some(format!("{}", e)); }
+        });
+        Ok(())
+    }
+
+    /// list everywhere the symbol under the cursor is used, including its declaration
+    pub fn find_references(&mut self) -> Result<(), Box<Error>> {
+        let (ls, mut params) = self.cursor_request(|c| c.references, "language server can't find references")?;
+        params["context"] = object!{ "includeDeclaration" => true };
+        ls.borrow_mut().request("textDocument/references", params, |r, app| {
+            let ls = match r {
+                Ok(v) => lsp::parse_locations(&v),
+                Err(e) => { app.status_text = Some(format!("references: {}", e)); return }
+            };
+            let r = if ls.len() == 0 {
+                app.status_text = Some(String::from("no references found"));
+                Ok(())
+            } else {
+                app.list_lsp_locations(ls)
+            };
+            if let Err(e) = r { app.status_text = Some(format!("{}", e)); }
+        });
+        Ok(())
+    }
+
+    /// ask the language server about the symbol under the cursor, to show next to it. An answer
+    /// that arrives after the cursor has moved is dropped
+    pub fn hover(&mut self) -> Result<(), Box<Error>> {
+        let (ls, params) = self.cursor_request(|c| c.hover, "language server has no hover information")?;
+        let at = (self.current_buffer, self.buf().borrow().curr_loc());
+        ls.borrow_mut().request("textDocument/hover", params, move |r, app| {
+            if (app.current_buffer, app.buf().borrow().curr_loc()) != at { return; }
+            match r.map(|v| lsp::hover_text(&v["contents"])) {
+                Ok(Some(t)) => app.hover = Some(t),
+                Ok(None) => app.status_text = Some(String::from("no hover information")),
+                Err(e) => app.status_text = Some(format!("hover: {}", e))
+            }
+        });
+        Ok(())
+    }
+
+    /// search the whole project for symbols matching `query`, with one of the current buffer's
+    /// language servers or else any running one that can, then pick one to go to
+    pub fn workspace_symbols(&mut self, query: &str) -> Result<(), Box<Error>> {
+        let ls = self.buf().borrow().language_server(|c| c.workspace_symbol).or_else(|| {
+            self.language_servers.iter().filter_map(|s| s.server.clone()).find(|ls| ls.borrow().capabilities.workspace_symbol)
+        });
+        let ls = ls.ok_or(mode::CommandError::InvalidCommand(Some("no language server can search for symbols")))?;
+        ls.borrow_mut().request("workspace/symbol", object!{ "query" => query }, |r, app| {
+            let results = match r {
+                Ok(r) => r,
+                Err(e) => { app.status_text = Some(format!("workspace symbols: {}", e)); return }
+            };
+            let cd = env::current_dir().unwrap_or_default();
+            let (items, locs): (Vec<String>, Vec<(PathBuf, (usize, usize))>) = results.members().filter_map(|s| {
+                let path = s["location"]["uri"].as_str().and_then(lsp::uri_to_path)?;
+                // WorkspaceSymbols can leave the range out, to be resolved later
+                let pos = lsp::position_from_json(&s["location"]["range"]["start"]);
+                let container = s["containerName"].as_str().map_or(String::new(), |c| format!(" ({})", c));
+                let item = format!("{} {}{}  {}:{}", lsp::symbol_kind_name(s["kind"].as_usize().unwrap_or(0)), s["name"],
+                    container, path.strip_prefix(&cd).unwrap_or(&path).display(), pos.0+1);
+                Some((item, (path, pos)))
+            }).unzip();
+            if items.len() == 0 {
+                app.status_text = Some(String::from("no symbols found"));
+                return;
+            }
+            let picker = mode::PickerMode::new(app, "symbol:", items, Box::new(move |i, app| {
+                app.goto_lsp_location(&locs[i].0, locs[i].1)?;
+                Ok(None)
+            }));
+            app.next_mode = Some(Box::new(picker));
+        });
+        Ok(())
+    }
+
+    /// apply a WorkspaceEdit, which can change several files. Files that aren't open are loaded
+    /// into hidden buffers, which :wa saves, and each buffer's changes are one undoable step
+    pub fn apply_workspace_edit(&mut self, edit: &::json::JsonValue) -> Result<(), Box<Error>> {
+        let mut changes = Vec::new();
+        if edit["documentChanges"].is_array() {
+            for c in edit["documentChanges"].members() {
+                if c.has_key("kind") {
+                    return Err(Box::new(mode::CommandError::InvalidCommand(Some("can't create, rename or delete files"))));
                 }
+                changes.push((c["textDocument"]["uri"].as_str().unwrap_or(""), &c["edits"]));
+            }
+        } else {
+            changes.extend(edit["changes"].entries());
+        }
+        // open everything first, so that nothing is changed if a file can't be
+        let mut targets = Vec::new();
+        for (uri, edits) in changes {
+            let path = lsp::uri_to_path(uri).ok_or(mode::CommandError::InvalidCommand(Some("edit to a file that isn't local")))?;
+            let open = self.bufs.len();
+            let ix = self.open_file(&path)?;
+            if ix >= open { self.bufs[ix].borrow_mut().hidden = true; }
+            targets.push((ix, edits));
+        }
+        let mut hidden = Vec::new();
+        for (ix, edits) in targets {
+            let mut b = self.bufs[ix].borrow_mut();
+            b.apply_text_edits(edits);
+            if b.hidden {
+                hidden.push(b.fs_loc.as_ref().and_then(|p| p.file_name()).map_or(String::new(), |n| n.to_string_lossy().into_owned()));
             }
         }
-        Ok(None)
+        // otherwise nothing shows that those files have changed
+        if hidden.len() > 0 {
+            self.status_text = Some(format!("changed files that aren't open: {} (:wa saves them)", hidden.join(", ")));
+        }
+        Ok(())
+    }
+
+    // the current buffer's first language server that can do something, going by `can`
+    fn server_supporting<F: Fn(&ServerCapabilities) -> bool>(&self, can: F, what: &'static str) -> Result<Rc<RefCell<LanguageServer>>, Box<Error>> {
+        let b = self.buf();
+        let b = b.borrow();
+        if !b.has_language_server() {
+            return Err(Box::new(mode::CommandError::InvalidCommand(Some("no language server for this buffer"))));
+        }
+        Ok(b.language_server(can).ok_or(mode::CommandError::InvalidCommand(Some(what)))?)
+    }
+
+    // an LSP range covering the whole lines first..=last of the current buffer
+    fn lines_range(&self, first: usize, last: usize) -> ::json::JsonValue {
+        let b = self.buf();
+        let b = b.borrow();
+        let end = b.lsp_position((b.line_len(last), last));
+        object!{ "start" => lsp::position_to_json((first, 0)), "end" => lsp::position_to_json(end) }
+    }
+
+    /// rename the symbol under the cursor everywhere the language server knows it is used
+    pub fn rename(&mut self, new_name: &str) -> Result<(), Box<Error>> {
+        let (ls, mut params) = self.cursor_request(|c| c.rename, "language server can't rename")?;
+        params["newName"] = new_name.into();
+        ls.borrow_mut().request("textDocument/rename", params, |r, app| {
+            let r = match r {
+                Ok(ref e) if e.is_null() => Err(Box::new(mode::CommandError::InvalidCommand(Some("nothing to rename"))) as Box<Error>),
+                Ok(e) => app.apply_workspace_edit(&e),
+                Err(e) => Err(Box::new(e) as Box<Error>)
+            };
+            if let Err(e) = r { app.status_text = Some(format!("rename: {}", e)); }
+        });
+        Ok(())
+    }
+
+    /// format the current buffer, or only the lines first..=last, as the language server would
+    pub fn format(&mut self, lines: Option<(usize, usize)>) -> Result<(), Box<Error>> {
+        let method = if lines.is_some() { "textDocument/rangeFormatting" } else { "textDocument/formatting" };
+        let (ls, mut params) = self.cursor_request(|c| if lines.is_some() { c.range_formatting } else { c.formatting },
+            "language server can't format")?;
+        params.remove("position");
+        if let Some((first, last)) = lines {
+            params["range"] = self.lines_range(first, last);
+        }
+        // the edits go to the buffer that was formatted, even if it isn't the current one by then
+        let buf = self.buf();
+        params["options"] = match buf.borrow().tab_style {
+            TabStyle::Tab => object!{ "tabSize" => buf.borrow().tab_width, "insertSpaces" => false },
+            TabStyle::Spaces(n) => object!{ "tabSize" => n, "insertSpaces" => true }
+        };
+        ls.borrow_mut().request(method, params, move |r, app| match r {
+            Ok(edits) => buf.borrow_mut().apply_text_edits(&edits),
+            Err(e) => app.status_text = Some(format!("format: {}", e))
+        });
+        Ok(())
+    }
+
+    /// ask the language server what it can do about lines first..=last and the problems on them,
+    /// then pick one of its code actions to carry out
+    pub fn code_actions(&mut self, first: usize, last: usize) -> Result<(), Box<Error>> {
+        let (ls, mut params) = self.cursor_request(|c| c.code_action, "language server has no code actions")?;
+        params.remove("position");
+        params["range"] = self.lines_range(first, last);
+        let diagnostics = {
+            let b = self.buf();
+            let b = b.borrow();
+            b.diagnostics.iter().filter(|d| d.start.1 <= last && d.end.1 >= first).map(|d| {
+                let (start, end) = (b.lsp_position(d.start), b.lsp_position(d.end));
+                let mut v = object!{
+                    "range" => object!{ "start" => lsp::position_to_json(start), "end" => lsp::position_to_json(end) },
+                    "severity" => d.severity as u8,
+                    "message" => d.message.clone()
+                };
+                if let Some(ref s) = d.source { v["source"] = s.clone().into(); }
+                v
+            }).collect::<Vec<_>>()
+        };
+        params["context"] = object!{ "diagnostics" => ::json::JsonValue::Array(diagnostics) };
+        let server = ls.clone();
+        ls.borrow_mut().request("textDocument/codeAction", params, move |r, app| {
+            let actions = match r {
+                Ok(a) => a.members().cloned().collect::<Vec<_>>(),
+                Err(e) => { app.status_text = Some(format!("code actions: {}", e)); return }
+            };
+            if actions.len() == 0 {
+                app.status_text = Some(String::from("no code actions"));
+                return;
+            }
+            let titles = actions.iter().map(|a| String::from(a["title"].as_str().unwrap_or("?"))).collect();
+            let server = server.clone();
+            let picker = mode::PickerMode::new(app, "code action:", titles, Box::new(move |i, app| {
+                app.run_code_action(&server, &actions[i], false)?;
+                Ok(None)
+            }));
+            app.next_mode = Some(Box::new(picker));
+        });
+        Ok(())
+    }
+
+    // carry out a Command or CodeAction from textDocument/codeAction. Actions that come without
+    // an edit or a command are resolved first, if the server can
+    fn run_code_action(&mut self, ls: &Rc<RefCell<LanguageServer>>, action: &::json::JsonValue, resolved: bool) -> Result<(), Box<Error>> {
+        let execute = |ls: &Rc<RefCell<LanguageServer>>, cmd: &::json::JsonValue| {
+            let mut params = object!{ "command" => cmd["command"].clone() };
+            if cmd.has_key("arguments") { params["arguments"] = cmd["arguments"].clone(); }
+            // anything the command changes comes back as a workspace/applyEdit request
+            ls.borrow_mut().request("workspace/executeCommand", params, |r, app| {
+                if let Err(e) = r { app.status_text = Some(format!("code action: {}", e)); }
+            });
+        };
+        // a bare Command
+        if action["command"].is_string() {
+            execute(ls, action);
+            return Ok(());
+        }
+        if !resolved && !action.has_key("edit") && !action.has_key("command") && ls.borrow().capabilities.code_action_resolve {
+            let server = ls.clone();
+            ls.borrow_mut().request("codeAction/resolve", action.clone(), move |r, app| {
+                let r = match r {
+                    Ok(a) => app.run_code_action(&server, &a, true),
+                    Err(e) => Err(Box::new(e) as Box<Error>)
+                };
+                if let Err(e) = r { app.status_text = Some(format!("code action: {}", e)); }
+            });
+            return Ok(());
+        }
+        if action.has_key("edit") {
+            self.apply_workspace_edit(&action["edit"])?;
+        }
+        if action["command"].is_object() {
+            execute(ls, &action["command"]);
+        }
+        Ok(())
+    }
+
+    /// the language servers for files with an extension, starting the ones that aren't running
+    /// yet. Servers that were stopped, or are waiting to be restarted, are left out, as are ones
+    /// that fail to start, which are restarted later like ones that crashed
+    pub fn language_servers_for_file_type(&mut self, file_ext: &str) -> Vec<Rc<RefCell<LanguageServer>>> {
+        let mut servers = Vec::new();
+        for slot in self.language_servers.iter_mut().filter(|s| s.handles(file_ext)) {
+            match slot.server {
+                Some(ref ls) => servers.push(ls.clone()),
+                None if slot.can_start() => match slot.start() {
+                    Ok(ls) => servers.push(ls),
+                    Err(e) => {
+                        self.status_text = Some(format!("language server {} failed to start: {}", slot.name, e));
+                        slot.crashed(format!("{}", e));
+                    }
+                },
+                None => {}
+            }
+        }
+        servers
+    }
+
+    // start the language server at `i` in `language_servers`, and open every buffer it handles in it
+    fn start_language_server(&mut self, i: usize) -> Result<(), Box<Error>> {
+        let ls = self.language_servers[i].start()?;
+        for b in self.bufs.iter() {
+            let mut b = b.borrow_mut();
+            let handled = b.fs_loc.as_ref().and_then(|p| p.extension()).and_then(|e| e.to_str())
+                .map_or(false, |e| self.language_servers[i].handles(e));
+            if handled { b.attach_language_server(ls.clone()); }
+        }
+        Ok(())
+    }
+
+    // take a language server that has gone away out of the buffers, along with its diagnostics
+    fn detach_language_server(&mut self, i: usize, ls: &Rc<RefCell<LanguageServer>>) {
+        for b in self.bufs.iter() {
+            let mut b = b.borrow_mut();
+            b.detach_language_server(ls);
+            b.set_diagnostics(i, &::json::JsonValue::new_array());
+        }
+    }
+
+    /// a language server has answered initialize, so the buffers open in it can ask it for things
+    pub fn language_server_initialized(&mut self, ls: &Rc<RefCell<LanguageServer>>) {
+        for b in self.bufs.iter() {
+            let mut b = b.borrow_mut();
+            if !b.uses_language_server(ls) { continue; }
+            // sending edits asks again by itself
+            if !b.sync_language_server(true) {
+                b.request_symbols();
+                b.request_semantic_tokens();
+            }
+        }
+    }
+
+    /// notice language servers that have exited by themselves, and start again the ones whose
+    /// restart is due
+    pub fn check_language_servers(&mut self) {
+        for i in 0..self.language_servers.len() {
+            if let Some(ls) = self.language_servers[i].check_exited() {
+                self.detach_language_server(i, &ls);
+                let s = &self.language_servers[i];
+                self.status_text = Some(format!("language server {} exited: {}", s.name, s.status()));
+            }
+            if self.language_servers[i].restart_due() {
+                if let Err(e) = self.start_language_server(i) {
+                    self.language_servers[i].crashed(format!("{}", e));
+                }
+            }
+        }
+    }
+
+    // the language servers called `name`, or else the ones for the current buffer's file type
+    fn language_server_slots(&self, name: Option<&str>) -> Result<Vec<usize>, Box<Error>> {
+        let ext = self.buf().borrow().fs_loc.as_ref().and_then(|p| p.extension()).and_then(|e| e.to_str()).map(String::from);
+        let slots = (0..self.language_servers.len()).filter(|&i| match name {
+            Some(n) => self.language_servers[i].name == n,
+            None => ext.as_ref().map_or(false, |e| self.language_servers[i].handles(e))
+        }).collect::<Vec<_>>();
+        if slots.len() == 0 {
+            return Err(Box::new(mode::CommandError::InvalidCommand(Some(match name {
+                Some(_) => "no language server with that name",
+                None => "no language server for this buffer"
+            }))));
+        }
+        Ok(slots)
+    }
+
+    /// start language servers over, even ones that were stopped or gave up after crashing
+    pub fn restart_language_servers(&mut self, name: Option<&str>) -> Result<(), Box<Error>> {
+        for i in self.language_server_slots(name)? {
+            if let Some(ls) = self.language_servers[i].stop() { self.detach_language_server(i, &ls); }
+            self.start_language_server(i)?;
+        }
+        Ok(())
+    }
+
+    /// shut language servers down until they are restarted
+    pub fn stop_language_servers(&mut self, name: Option<&str>) -> Result<(), Box<Error>> {
+        for i in self.language_server_slots(name)? {
+            if let Some(ls) = self.language_servers[i].stop() { self.detach_language_server(i, &ls); }
+        }
+        Ok(())
+    }
+
+    /// list the configured language servers in the list buffer, with what they are doing and
+    /// what they can do
+    pub fn show_language_servers(&mut self) {
+        let mut text = String::new();
+        for s in self.language_servers.iter() {
+            text.push_str(&format!("{}: {}\n", s.name, s.status()));
+            if let Some(ref ls) = s.server {
+                let open = self.bufs.iter().filter(|b| b.borrow().uses_language_server(ls)).count();
+                let ls = ls.borrow();
+                if let Some(ref info) = ls.server_info { text.push_str(&format!("    server: {}\n", info)); }
+                text.push_str(&format!("    open files: {}\n", open));
+                if ls.is_initialized() { text.push_str(&format!("    can do: {}\n", ls.capabilities.names().join(", "))); }
+            }
+        }
+        if text.len() == 0 { text.push_str("no language servers are configured"); }
+        let lines = text.trim_right_matches('\n').lines().count();
+        self.show_locations(&text, vec![None; lines]);
+    }
+
+    /// open the traffic log of the language server called `name`, or of the current buffer's
+    /// first one, in a read-only buffer, scrolled to the latest messages. Run from a log buffer,
+    /// it brings that log up to date
+    pub fn show_language_server_log(&mut self, name: Option<&str>) -> Result<(), Box<Error>> {
+        let current = self.buf().borrow().name.as_ref()
+            .and_then(|n| if n.starts_with(LOG_BUFFER_PREFIX) { Some(String::from(&n[LOG_BUFFER_PREFIX.len()..])) } else { None });
+        let i = self.language_server_slots(name.or(current.as_ref().map(|n| n.as_str())))?[0];
+        let (buf_name, text) = {
+            let s = &self.language_servers[i];
+            (format!("{}{}", LOG_BUFFER_PREFIX, s.name), s.log.lock().expect("lock traffic log").text())
+        };
+        let ix = match self.bufs.iter().position(|b| b.borrow().name.as_ref() == Some(&buf_name)) {
+            Some(ix) => ix,
+            None => {
+                let mut b = Buffer::new(self.res.clone());
+                b.name = Some(buf_name);
+                b.read_only = true;
+                self.bufs.push(Rc::new(RefCell::new(b)));
+                self.bufs.len()-1
+            }
+        };
+        {
+            let mut b = self.bufs[ix].borrow_mut();
+            b.set_text(&text);
+            let last = b.line_count()-1;
+            b.place_cursor(0, last);
+        }
+        self.move_to_buffer(ix);
+        Ok(())
     }
 }
 
@@ -83,6 +796,7 @@ pub struct TxdApp {
     state: State,
     last_err: Option<Box<Error>>,
     mode: Box<mode::Mode>,
+    keymap: Keymap
 }
 
 impl TxdApp {
@@ -109,37 +823,59 @@ impl TxdApp {
         }
 
         let res = Rc::new(RefCell::new(Resources::new(rx, config).expect("create resources")));
-        let buf = Rc::new(RefCell::new(Buffer::new(res.clone())));
-                //env::args().nth(1).map_or_else(|| Buffer::new(res.clone()),
-                //|p| Buffer::load(Path::new(&p), res.clone()).expect("open file"))  ));
-        let cmd = Rc::new(RefCell::new(Buffer::new(res.clone())));
-        { cmd.borrow_mut().show_cursor = false; }
+        let (keymap, le) = match Keymap::from_config(res.borrow().config.as_ref()) {
+            Ok(km) => (km, le),
+            Err(e) => (Keymap::new(), Some(e))
+        };
+        let (language_servers, le) = match lsp::ServerSlot::from_config(res.borrow().config.as_ref()) {
+            Ok(ls) => (ls, le),
+            Err(e) => { println!("language server config error {:?}", e); (Vec::new(), Some(e)) }
+        };
         //println!("cd = {}, canoncd = {}", ::std::env::current_dir().unwrap().display(),
         //    ::std::env::current_dir().unwrap().canonicalize().unwrap().display());
         TxdApp {
-            state: State {
-                bufs: vec![cmd, buf],
-                current_buffer: 1, last_buffer: 1,
-                clipstacks: HashMap::new(), res,
-                should_quit: false,
-                language_servers: Vec::new(),
-                status_text: None
-            },
+            state: State::new(res, language_servers),
             mode: Box::new(mode::NormalMode::new()), last_err: le,
+            keymap
         }
     }
+
+    fn handle(&mut self, r: Resolved) {
+        self.state.hover = None;
+        let nxm = match r {
+            Resolved::Key(k) => {
+                if let Some((_, ref mut keys)) = self.state.recording { keys.push(k); }
+                self.mode.event(k, &mut self.state)
+            },
+            Resolved::Command(c) => {
+                // a mapped command replaces whatever was half typed in the current mode
+                self.mode.leave(&mut self.state);
+                mode::CommandMode::run(&c, &mut self.state)
+            }
+        };
+        match nxm {
+            Ok(Some(new_mode)) => { if self.last_err.is_some() { self.last_err = None; } self.mode = new_mode }
+            Ok(None) => {}
+            Err(err) => { println!("error: {}", err); self.last_err = Some(err); self.mode = Box::new(mode::NormalMode::new()); }
+        }
+    }
+
+    // keys that were waiting to see if they would become a longer mapping
+    fn check_key_timeout(&mut self) {
+        let rs = self.keymap.check_timeout(self.mode.keymap());
+        for r in rs { self.handle(r); }
+    }
 }
 
 impl App for TxdApp {
     fn event(&mut self, e: Event) -> bool {
         match e {
             Event::WindowEvent { event: we, .. } => {
-                let nxm = self.mode.event(we, &mut self.state);
-                match nxm {
-                    Ok(Some(new_mode)) => { if self.last_err.is_some() { self.last_err = None; } self.mode = new_mode }
-                    Ok(None) => {}
-                    Err(err) => { println!("error: {}", err); self.last_err = Some(err); self.mode = Box::new(mode::NormalMode::new()); }
-                } 
+                self.check_key_timeout();
+                if let Some(k) = Key::from_event(&we) {
+                    let rs = self.keymap.feed(self.mode.keymap(), k);
+                    for r in rs { self.handle(r); }
+                }
             },
             _ => { }
         }
@@ -147,42 +883,73 @@ impl App for TxdApp {
     }
 
     fn paint(&mut self, rx: &mut RenderContext) {
-        for lsp in self.state.language_servers.iter() {
-            let st = &mut self.state.status_text;
-            lsp.1.borrow_mut().process_notifications(|n| {
-                match n["method"].as_str() {
-                    Some("window/progress") => {
-                        if n["params"].has_key("done") {
-                            *st = None;
-                        } else {
-                            *st = Some(format!("{}: {}", n["params"]["title"], n["params"]["message"]));
-                        }
-                    },
-                    Some(_) => println!("unknown notification {:?}", n),
-                    None => println!("invalid notification {:?}", n)
-                }
-            });
+        self.check_key_timeout();
+        for b in self.state.bufs.iter() {
+            b.borrow_mut().sync_language_server(false);
+        }
+        for i in 0..self.state.language_servers.len() {
+            let lsp = match self.state.language_servers[i].server.clone() { Some(ls) => ls, None => continue };
+            let server = lsp.clone();
+            let was_initialized = lsp.borrow().is_initialized();
+            // handlers run with the server unborrowed, so they can make more requests
+            let ds = lsp.borrow_mut().poll();
+            for d in ds {
+                d.run(&mut self.state, |n, st| {
+                    match n["method"].as_str() {
+                        Some("window/progress") => {
+                            if n["params"].has_key("done") {
+                                st.status_text = None;
+                            } else {
+                                st.status_text = Some(format!("{}: {}", n["params"]["title"], n["params"]["message"]));
+                            }
+                        },
+                        Some("textDocument/publishDiagnostics") => st.publish_diagnostics(i, &n["params"]),
+                        Some("window/showMessage") => st.status_text = n["params"]["message"].as_str().map(String::from),
+                        Some("window/logMessage") => st.language_servers[i].log.lock().expect("lock traffic log")
+                            .note(Direction::Received, &format!("{}", n["params"]["message"])),
+                        Some("workspace/applyEdit") => {
+                            let r = st.apply_workspace_edit(&n["params"]["edit"]);
+                            let mut result = object!{ "applied" => r.is_ok() };
+                            if let Err(e) = r { result["failureReason"] = format!("{}", e).into(); }
+                            server.borrow_mut().respond(n["id"].clone(), Ok(result));
+                        },
+                        Some(m) => st.language_servers[i].log.lock().expect("lock traffic log")
+                            .note(Direction::Received, &format!("unhandled notification {}", m)),
+                        None => st.language_servers[i].log.lock().expect("lock traffic log")
+                            .note(Direction::Received, &format!("invalid notification {}", n.dump()))
+                    }
+                });
+            }
+            if !was_initialized && lsp.borrow().is_initialized() {
+                self.state.language_server_initialized(&lsp);
+            }
+        }
+        self.state.check_language_servers();
+        if let Some(m) = self.state.next_mode.take() {
+            self.mode.leave(&mut self.state);
+            self.mode = m;
         }
 
         rx.clear(Color::rgb(0.1, 0.1, 0.1));
         let bnd = rx.bounds();
         let res = self.state.res.borrow();
 
-        let mode_tag_tl = rx.new_text_layout(self.mode.status_tag(), &res.font, bnd.w, bnd.h).expect("create mode text layout");
+        let mode_tag_tl = rx.new_text_layout(self.mode.status_tag(), res.font(), bnd.w, bnd.h).expect("create mode text layout");
         let mtb = mode_tag_tl.bounds();
 
         //draw buffer line
         rx.set_color(Color::rgb(0.25, 0.22, 0.2));
         rx.fill_rect(Rect::xywh(0.0, 0.0, bnd.w, mtb.h));
         rx.set_color(Color::rgb(0.1, 0.44, 0.5));
-        rx.draw_text(Rect::xywh(4.0, 0.0, bnd.w, mtb.h), "txd", &res.font);
+        rx.draw_text(Rect::xywh(4.0, 0.0, bnd.w, mtb.h), "txd", res.font());
         {
         let mut x = 48.0;
         for (i, b) in self.state.bufs.iter().enumerate() {
+            if b.borrow().hidden { continue; }
             let tl = rx.new_text_layout(&format!("[{} {}]", i, 
-                     b.borrow().fs_loc.as_ref().map_or_else(|| String::from("*"),
+                     b.borrow().fs_loc.as_ref().map_or_else(|| b.borrow().name.clone().unwrap_or(String::from("*")),
                         |p| format!("{}", p.strip_prefix(::std::env::current_dir().unwrap().as_path()).unwrap_or(p).display()) ),
-), &res.font, bnd.w, bnd.h).expect("create text layout");
+), res.font(), bnd.w, bnd.h).expect("create text layout");
             if i == self.state.current_buffer {
                 rx.set_color(Color::rgb(0.80, 0.44, 0.1));
             } else {
@@ -195,7 +962,7 @@ impl App for TxdApp {
 
         let buf_ = self.state.buf();
         let mut buf = buf_.borrow_mut();
-        buf.paint(rx, Rect::xywh(4.0, 4.0 + mtb.h*1.1, bnd.w-4.0, bnd.h-mtb.h*3.2));
+        buf.paint(rx, Rect::xywh(4.0, 4.0 + mtb.h*1.1, bnd.w-4.0, bnd.h-mtb.h*3.2), self.state.search_regex.as_ref());
 
         //draw status line
         let status_y = bnd.h-mtb.h*2.2;
@@ -205,30 +972,62 @@ impl App for TxdApp {
         /*rx.draw_text(Rect::xywh(4.0, bnd.h-35.0, bnd.w, 18.0), self.mode.status_tag(), &res.font);*/
         rx.draw_text_layout(Point::xy(4.0, status_y), &mode_tag_tl);
         rx.set_color(Color::rgb(0.9, 0.4, 0.0));
-        rx.draw_text(Rect::xywh(100.0, status_y, bnd.w, 18.0),
-                     &buf.fs_loc.as_ref().map_or_else(|| String::from("[new file]"),
+        let path_tl = rx.new_text_layout(&buf.fs_loc.as_ref().map_or_else(|| buf.name.clone().unwrap_or(String::from("[new file]")),
                         |p| format!("{}", p.strip_prefix(::std::env::current_dir().unwrap().as_path()).unwrap_or(p).display()) ),
-                     &res.font);
-        if let Some(ref s) = self.state.status_text {
-            rx.draw_text(Rect::xywh(600.0, status_y, bnd.w, 18.0), &s, &res.font);
+                     res.font(), bnd.w, bnd.h).expect("create path text layout");
+        rx.draw_text_layout(Point::xy(100.0, status_y), &path_tl);
+        // breadcrumbs: the symbols the cursor is inside
+        let mut x = 100.0 + path_tl.bounds().w + 16.0;
+        let crumbs = buf.symbol_path(buf.curr_loc()).iter().map(|s| s.name.as_str()).collect::<Vec<_>>().join(" > ");
+        if crumbs.len() > 0 {
+            let tl = rx.new_text_layout(&crumbs, res.font(), bnd.w, bnd.h).expect("create breadcrumbs text layout");
+            rx.set_color(Color::rgb(0.6, 0.5, 0.4));
+            rx.draw_text_layout(Point::xy(x, status_y), &tl);
+            x += tl.bounds().w + 16.0;
+        }
+        // the problem under the cursor takes the place of the language server's status
+        if let Some(d) = buf.diagnostic_at(buf.curr_loc()) {
+            let x = x.max(400.0);
+            rx.set_color(Color::rgb(0.9, 0.6, 0.3));
+            rx.draw_text(Rect::xywh(x, status_y, bnd.w-340.0-x, 18.0), &format!("{}: {}", d.severity, d.message.lines().next().unwrap_or("")), res.font());
+        } else if let Some(ref s) = self.state.status_text {
+            rx.set_color(Color::rgb(0.9, 0.4, 0.0));
+            rx.draw_text(Rect::xywh(x.max(600.0), status_y, bnd.w, 18.0), &s, res.font());
+        }
+        if let Some((ref r, _)) = self.state.recording {
+            rx.set_color(Color::rgb(0.9, 0.4, 0.0));
+            rx.draw_text(Rect::xywh(bnd.w-320.0, status_y, bnd.w, 18.0), &format!("recording @{}", r.0), res.font());
         }
         rx.set_color(Color::rgb(0.0, 0.6, 0.4));
         rx.draw_text(Rect::xywh(bnd.w-200.0, status_y, bnd.w, 18.0),
                      &format!("ln {} col {}", buf.cursor_line, buf.cursor_col),
-                     &res.font);
+                     res.font());
         if let Some(ref err) = self.last_err {
             rx.set_color(Color::rgb(0.9, 0.2, 0.0));
             rx.draw_text(Rect::xywh(4.0, status_y + mtb.h, bnd.w, 18.0),
                 &format!("error: {}", err),
-                &res.font);
+                res.font());
         }
         //draw command line
-        if let Some(cmd) = self.mode.pending_command() {
+        let pending_keys = self.keymap.pending().iter().map(|k| format!("{}", k)).collect::<String>();
+        if let Some(cmd) = self.mode.pending_command().map(|c| format!("{}{}", c, pending_keys))
+                .or(if pending_keys.len() > 0 { Some(pending_keys) } else { None }) {
             rx.set_color(Color::rgb(0.8, 0.8, 0.8));
-            rx.draw_text(Rect::xywh(bnd.w-200.0, status_y + mtb.h, bnd.w, 28.0), cmd,
-                        &res.font);
+            rx.draw_text(Rect::xywh(bnd.w-200.0, status_y + mtb.h, bnd.w, 28.0), &cmd,
+                        res.font());
+        }
+        self.state.bufs[0].borrow_mut().paint(rx, Rect::xywh(4.0, status_y + mtb.h, bnd.w-200.0, 50.0), None);
+
+        // popups go over everything else
+        let cursor = buf.cursor_bounds;
+        drop(buf);
+        self.mode.paint(rx, &self.state, cursor);
+        if let (Some(h), Some(c)) = (self.state.hover.as_ref(), cursor) {
+            let text = h.lines().take(HOVER_LINES).collect::<Vec<_>>().join("\n");
+            if let Ok(l) = rx.new_text_layout(&text, res.font(), 600.0, 600.0) {
+                popup::draw_text_panel(rx, &l, Point::xy(c.x, c.y + c.h), Color::rgb(0.85, 0.85, 0.85));
+            }
         }
-        self.state.bufs[0].borrow_mut().paint(rx, Rect::xywh(4.0, status_y + mtb.h, bnd.w-200.0, 50.0));
     }
 }
 
@@ -1,16 +1,24 @@
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::fs::*;
-use std::io::{Read, Write, Error as IoError, ErrorKind};
+use std::io::{Read, Write, BufWriter, Error as IoError, ErrorKind};
 use std::error::Error;
+use std::time::{Duration, Instant};
 
 use runic::*;
 use res::Resources;
 use movement::*;
 use app::State;
-use lsp::LanguageServer;
+use lsp::{self, LanguageServer, ServerCapabilities, ContentChange, Diagnostic, Severity, Symbol};
+use undo::{Edit, History};
+use rope::{Rope, RopeBuilder};
+use highlight::{Highlighter, Style};
+use traffic::Direction;
 use toml;
+use json::JsonValue;
+use regex::Regex;
 
 
 #[derive(Debug)]
@@ -19,23 +27,88 @@ pub enum TabStyle {
     Spaces(usize)
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SelectionKind {
+    Char, Line, Block
+}
+
+/// A selected region of a buffer, as made in Visual mode. `start` and `end` are (col, line)
+/// locations ordered so that start comes first, and both are included in the selection. For block
+/// selections the columns are counted in characters rather than bytes, since the same column
+/// is a different byte offset on each line
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Selection {
+    pub kind: SelectionKind,
+    pub start: (usize, usize),
+    pub end: (usize, usize)
+}
+
+// how long edits wait before they are sent to the language server, so bursts of typing go together
+const LSP_SYNC_DELAY_MS: u64 = 200;
+// width of the strip left of the text where diagnostics are marked
+const GUTTER_WIDTH: f32 = 10.0;
+// how many lines either side of the cursor text objects look at, so they stay quick in huge files
+const TEXT_OBJECT_LINES: usize = 500;
+
+fn severity_color(s: Severity) -> Color {
+    match s {
+        Severity::Error => Color::rgb(0.9, 0.2, 0.1),
+        Severity::Warning => Color::rgb(0.9, 0.6, 0.0),
+        Severity::Information => Color::rgb(0.2, 0.5, 0.9),
+        Severity::Hint => Color::rgb(0.5, 0.5, 0.5)
+    }
+}
+
 pub struct Buffer {
     pub fs_loc: Option<PathBuf>,
-    pub lines: Vec<String>,
+    /// what to call a buffer that isn't a file, like the one :lsp log opens
+    pub name: Option<String>,
+    /// edits are ignored, for buffers that only show something
+    pub read_only: bool,
+    pub text: Rope,
 
     // buffer view
     pub res: Rc<RefCell<Resources>>,
-    line_layouts: Vec<Option<TextLayout>>,
+    // only lines that are in or near the viewport have layouts, so this stays small even if the
+    // buffer is huge
+    line_layouts: HashMap<usize, TextLayout>,
     viewport_start: usize,
     viewport_end: usize,
     pub cursor_line: usize,
     pub cursor_col: usize,
     pub show_cursor: bool,
+    /// where the cursor was drawn on screen, the last time the buffer was painted with it visible
+    pub cursor_bounds: Option<Rect>,
+    /// where Visual mode started, if it is active
+    pub visual_anchor: Option<(SelectionKind, (usize, usize))>,
+    /// the last selection made in Visual mode, used by '<,'> in commands
+    pub last_selection: Option<Selection>,
 
     pub tab_style: TabStyle,
     pub tab_width: usize,
-    pub lang_server: Option<Rc<RefCell<LanguageServer>>>,
+    // the language servers the file is open in, each with the edits it hasn't been sent yet
+    lang_servers: Vec<(Rc<RefCell<LanguageServer>>, Vec<ContentChange>)>,
     pub version: usize,
+    /// the document's outline, as the language server last reported it
+    pub symbols: Vec<Symbol>,
+    highlight: Highlighter,
+    // when the last edit the language servers haven't seen was made
+    lsp_changed_at: Instant,
+    /// the problems the language servers last reported, ordered by where they start
+    pub diagnostics: Vec<Diagnostic>,
+    /// for list buffers like :diagnostics, the file and location each line refers to
+    pub locations: Option<Vec<Option<(PathBuf, (usize, usize))>>>,
+    /// opened only to take an edit to several files, so it isn't shown in the buffer line until
+    /// it has been switched to
+    pub hidden: bool,
+    history: History,
+    // lines that are followed through edits, for commands like :g that work through a list of
+    // lines while changing the buffer. None once the line has been deleted
+    line_anchors: HashMap<usize, Option<usize>>,
+    next_anchor: usize,
+    // marks set with m, as the anchor of their line and their column. Uppercase marks are global,
+    // so State makes sure only one buffer has each of them
+    marks: HashMap<char, (usize, usize)>
 }
 
 impl Buffer {
@@ -51,10 +124,12 @@ impl Buffer {
                     }).unwrap_or((TabStyle::Tab,4));
 
         Buffer {
-            fs_loc: None, lines: vec![String::from("")],
+            fs_loc: None, name: None, read_only: false, text: Rope::new(),
             res, cursor_line: 0, cursor_col: 0, viewport_start: 0, viewport_end: 0,
-            line_layouts: vec![None], show_cursor: true, tab_style: default_indent_style, tab_width: default_indent_width,
-            lang_server: None, version: 0
+            line_layouts: HashMap::new(), show_cursor: true, cursor_bounds: None, visual_anchor: None, last_selection: None, tab_style: default_indent_style, tab_width: default_indent_width,
+            lang_servers: Vec::new(), version: 0, symbols: Vec::new(), highlight: Highlighter::new(None), lsp_changed_at: Instant::now(),
+            diagnostics: Vec::new(), locations: None, hidden: false, history: History::new(), line_anchors: HashMap::new(), next_anchor: 0,
+            marks: HashMap::new()
         }
     }
 
@@ -76,64 +151,175 @@ impl Buffer {
                     }).unwrap_or(Ok((TabStyle::Tab,4)))?;
 
         
-        let (lns, lay, ts) = if fp_exists { 
-            let mut f = OpenOptions::new().read(true).write(true).open(&path)?;
-            let mut s : String = String::new();
-            f.read_to_string(&mut s)?;
-            let lns: Vec<String> = s.lines().map(String::from).collect();
-            let mut layouts = Vec::new();
+        let (text, ts) = if fp_exists {
+            let f = OpenOptions::new().read(true).write(true).open(&path)?;
+            let text = Buffer::read_text(f)?;
             let mut ts: Option<TabStyle> = None;
-            for i in 0..lns.len() { //replace with Vec::resize_default?
-                if ts.is_none() {
-                    let mut ch = lns[i].chars();
-                    ts = match ch.next() {
-                        Some('\t') => Some(TabStyle::Tab),
-                        Some(' ') => {
-                            let mut n = 1;
-                            while let Some(' ') = ch.next() { n += 1 }
-                            Some(TabStyle::Spaces(n))
-                        },
-                        _ => None
-                    };
-                }
-                layouts.push(None);
+            // only look at the start of the file, which could be enormous
+            for i in 0..text.len_lines().min(1000) {
+                let ln = text.line(i);
+                let mut ch = ln.chars();
+                ts = match ch.next() {
+                    Some('\t') => Some(TabStyle::Tab),
+                    Some(' ') => {
+                        let mut n = 1;
+                        while let Some(' ') = ch.next() { n += 1 }
+                        Some(TabStyle::Spaces(n))
+                    },
+                    _ => None
+                };
+                if ts.is_some() { break; }
             }
             //println!("detected tab style = {:?}", ts);
-            (lns, layouts, ts.unwrap_or(default_indent_style))
+            (text, ts.unwrap_or(default_indent_style))
         } else {
-            (vec![String::from("")], vec![None], default_indent_style)
+            (Rope::new(), default_indent_style)
         };
-        let buf = Buffer {
-            fs_loc: Some(path),
-            lines: lns, line_layouts: lay,
-            viewport_start: 0, viewport_end: 0, cursor_line: 0, cursor_col: 0, show_cursor: true,
+        let mut buf = Buffer {
+            highlight: Highlighter::new(Some(&path)),
+            fs_loc: Some(path), name: None, read_only: false,
+            text, line_layouts: HashMap::new(),
+            viewport_start: 0, viewport_end: 0, cursor_line: 0, cursor_col: 0, show_cursor: true, cursor_bounds: None,
+            visual_anchor: None, last_selection: None,
             res: app.res.clone(),
             tab_style: ts, tab_width: default_indent_width,
-            lang_server: match fp.extension().and_then(|ext| ext.to_str()) {
-                Some(ext) => app.language_server_for_file_type(ext)?,
-                None => None
-            },
-            version: 0
+            lang_servers: Vec::new(),
+            version: 0, symbols: Vec::new(), lsp_changed_at: Instant::now(),
+            diagnostics: Vec::new(), locations: None, hidden: false, history: History::new(), line_anchors: HashMap::new(), next_anchor: 0,
+            marks: HashMap::new()
         };
-        if let Some(ref ls) = buf.lang_server {
-            let mut ls = ls.borrow_mut();
-            ls.document_did_open(&buf);
-            ls.send("textDocument/documentSymbol", object!{
-                "textDocument" => object!{
-                    "uri" => String::from("file:///") + buf.fs_loc.as_ref().expect("buffer has location").to_str().unwrap(),
-                }
-            }).unwrap();
+        if let Some(ext) = fp.extension().and_then(|ext| ext.to_str()) {
+            for ls in app.language_servers_for_file_type(ext) {
+                buf.attach_language_server(ls);
+            }
         }
+        // servers that are still starting are asked once they have been initialized
+        buf.request_symbols();
+        buf.request_semantic_tokens();
         Ok(buf)
     }
 
+    /// read a file into a rope a block at a time, so that the whole file never has to be in one
+    /// String. Line breaks are normalized to \n and the final one is dropped, as sync_disk puts it
+    /// back
+    fn read_text<R: Read>(mut f: R) -> Result<Rope, Box<Error>> {
+        let mut builder = RopeBuilder::new();
+        let mut block = vec![0u8; 64*1024];
+        // bytes left over from the last block, either an incomplete character or a \r that might be
+        // the start of a \r\n
+        let mut carry: Vec<u8> = Vec::new();
+        loop {
+            let n = f.read(&mut block)?;
+            if n == 0 { break; }
+            carry.extend_from_slice(&block[0..n]);
+            let valid = match ::std::str::from_utf8(&carry) {
+                Ok(_) => carry.len(),
+                Err(e) => {
+                    if e.error_len().is_some() {
+                        return Err(Box::new(IoError::new(ErrorKind::InvalidData, "file is not valid UTF-8")));
+                    }
+                    e.valid_up_to()
+                }
+            };
+            let keep = if valid > 0 && carry[valid-1] == b'\r' { valid-1 } else { valid };
+            let rest = carry.split_off(keep);
+            builder.push_str(&::std::str::from_utf8(&carry).unwrap().replace("\r\n", "\n"));
+            carry = rest;
+        }
+        if carry.len() > 0 {
+            builder.push_str(&String::from_utf8(carry)?);
+        }
+        let mut text = builder.finish();
+        let len = text.len_bytes();
+        if len > 0 && text.slice((len-1)..len) == "\n" {
+            text.remove((len-1)..len);
+        }
+        Ok(text)
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.text.len_lines()
+    }
+
+    /// the text of a line, without its line break
+    pub fn line(&self, line: usize) -> String {
+        self.text.line(line)
+    }
+
+    /// length of a line in bytes, not counting the line break
+    pub fn line_len(&self, line: usize) -> usize {
+        self.text.line_len(line)
+    }
+
+    /// convert a (col, line) location into a byte offset into the text
+    pub fn loc_to_byte(&self, loc: (usize, usize)) -> usize {
+        self.text.line_to_byte(loc.1) + loc.0
+    }
+
+    pub fn byte_to_loc(&self, b: usize) -> (usize, usize) {
+        let line = self.text.byte_to_line(b);
+        (b - self.text.line_to_byte(line), line)
+    }
+
+    /// convert a (col, line) location into an LSP position, which is (line, character) where
+    /// character counts UTF-16 code units from the start of the line
+    pub fn lsp_position(&self, loc: (usize, usize)) -> (usize, usize) {
+        let line_start = self.text.line_to_byte(loc.1);
+        (loc.1, self.text.byte_to_utf16(line_start + loc.0) - self.text.byte_to_utf16(line_start))
+    }
+
+    /// the URI the language server knows the buffer's file by
+    pub fn uri(&self) -> Option<String> {
+        self.fs_loc.as_ref().map(|p| lsp::path_to_uri(p))
+    }
+
+    /// is the file open in any language servers?
+    pub fn has_language_server(&self) -> bool {
+        self.lang_servers.len() > 0
+    }
+
+    /// is the file open in this language server?
+    pub fn uses_language_server(&self, ls: &Rc<RefCell<LanguageServer>>) -> bool {
+        self.lang_servers.iter().any(|l| Rc::ptr_eq(&l.0, ls))
+    }
+
+    /// the first of the buffer's language servers that can do something, going by `can`
+    pub fn language_server<F: Fn(&ServerCapabilities) -> bool>(&self, can: F) -> Option<Rc<RefCell<LanguageServer>>> {
+        self.lang_servers.iter().find(|l| can(&l.0.borrow().capabilities)).map(|l| l.0.clone())
+    }
+
+    /// open the file in a language server, which is told about edits from now on
+    pub fn attach_language_server(&mut self, ls: Rc<RefCell<LanguageServer>>) {
+        if self.fs_loc.is_none() || self.uses_language_server(&ls) { return; }
+        ls.borrow_mut().document_did_open(self);
+        self.lang_servers.push((ls, Vec::new()));
+    }
+
+    /// forget a language server that has gone away, without telling it
+    pub fn detach_language_server(&mut self, ls: &Rc<RefCell<LanguageServer>>) {
+        self.lang_servers.retain(|l| !Rc::ptr_eq(&l.0, ls));
+    }
+
+    /// convert an LSP position back into a (col, line) location, keeping it inside the text
+    pub fn from_lsp_position(&self, (line, character): (usize, usize)) -> (usize, usize) {
+        let line = line.min(self.line_count()-1);
+        let line_start = self.text.line_to_byte(line);
+        let b = self.text.utf16_to_byte(self.text.byte_to_utf16(line_start) + character);
+        ((b - line_start).min(self.line_len(line)), line)
+    }
+
+    /// the location of an LSP Position in a response, keeping it inside the text
+    pub fn from_lsp_json(&self, p: &JsonValue) -> (usize, usize) {
+        self.from_lsp_position(lsp::position_from_json(p))
+    }
+
     pub fn place_cursor(&mut self, mut cursor_col: usize, mut cursor_line: usize) {
-        let bl = &self.lines;
-        if bl.len() == 0 { cursor_line = 0; }
+        let line_count = self.line_count();
+        if line_count == 0 { cursor_line = 0; }
         else {
-            if cursor_line >= bl.len() { cursor_line = bl.len()-1; } 
+            if cursor_line >= line_count { cursor_line = line_count-1; } 
 
-            let cln = &bl[cursor_line];
+            let cln = &self.line(cursor_line);
             if cursor_col > cln.len() { cursor_col = cln.len(); }
             /*let mut lowest_dist = 1000;
             for (i,g) in &cln[..].grapheme_indices() {
@@ -175,7 +361,8 @@ impl Buffer {
     // scan from cursor looking for character. possibly absurdly made and could be done better with
     // a better buffer representation
     pub fn scan_line<P: Fn(char)->bool>(&self, pred: P, forwards: bool) -> Option<usize> {
-        let line_chars = self.lines[self.cursor_line].char_indices();
+        let line = self.line(self.cursor_line);
+        let line_chars = line.char_indices();
         (if forwards {
             println!("fwd");
             for (i, c) in line_chars {
@@ -194,7 +381,7 @@ impl Buffer {
             None
             //line_chars.skip(self.cursor_col).inspect(|&v| print!("{:?}", v)).find(|&(_, c)| pred(c)).map(|(i, _)| i)
         })
-        /*let (left, right) = self.lines[self.cursor_line].split_at(self.cursor_col + if forwards {1} else {0});
+        /*let (left, right) = self.line(self.cursor_line).split_at(self.cursor_col + if forwards {1} else {0});
         //println!("({}, {})", left, right);
         if forwards {
             right.find(pred).map(|v| v as isize + 1)
@@ -235,11 +422,12 @@ impl Buffer {
                  * if we're on non-alphanum => move until we hit alphanum
                 */
                 let mut v = cur..cur;
-                'main: while v.end.1 < self.lines.len() {
+                'main: while v.end.1 < self.line_count() {
+                    let line = self.line(v.end.1);
                     let mut chars: Box<Iterator<Item = (usize, char)>> = if direction {
-                        Box::new(self.lines[v.end.1].char_indices().skip(v.end.0)) 
+                        Box::new(line.char_indices().skip(v.end.0)) 
                     } else {
-                        Box::new(self.lines[v.end.1].char_indices().rev().skip(self.lines[v.end.1].len()-v.end.0))
+                        Box::new(line.char_indices().rev().skip(line.len()-v.end.0))
                     };
                     match chars.next() {
                         Some((i ,c)) => {
@@ -264,12 +452,42 @@ impl Buffer {
                         }
                     }
                     let y = wrapadd1(v.end.1, direction);
-                    v.end = (if direction { 0 } else { self.lines[y].len() }, y);
+                    v.end = (if direction { 0 } else { self.line_len(y) }, y);
                 }
                 v
             },
-            Movement::EndOfLine => (cur..(self.lines[self.cursor_line].len()-1, cur.1)),
+            Movement::EndOfLine => (cur..(self.line_len(self.cursor_line).saturating_sub(1), cur.1)),
+            Movement::Match(ref re, forward) => match self.search(re, cur, forward) {
+                Some(loc) => cur..loc,
+                None => cur..cur
+            },
             Movement::StartOfLine => (cur..(0,cur.1)),
+            Movement::Diagnostic(forward) => match self.next_diagnostic(cur, forward) {
+                Some(loc) => cur..loc,
+                None => cur..cur
+            },
+            Movement::Mark(c, exact) => match self.mark(c) {
+                Some(loc) if exact => cur..loc,
+                Some((_, line)) => {
+                    let indent = self.line(line).find(|c: char| !c.is_whitespace()).unwrap_or(0);
+                    cur..(indent, line)
+                },
+                None => cur..cur
+            },
+            Movement::Object(obj, inner) => {
+                let first = cur.1.saturating_sub(TEXT_OBJECT_LINES);
+                let last = (cur.1 + TEXT_OBJECT_LINES + 1).min(self.line_count());
+                let start = self.text.line_to_byte(first);
+                let text = self.text.slice(start..self.text.line_range(last-1).end);
+                match obj.find(&text, self.loc_to_byte(cur) - start, inner) {
+                    Some(ref r) if obj.is_linewise() => {
+                        let end = if r.end >= text.len() { last } else { self.byte_to_loc(start + r.end).1 };
+                        (0, self.byte_to_loc(start + r.start).1)..(0, end)
+                    },
+                    Some(r) => self.byte_to_loc(start + r.start)..self.byte_to_loc(start + r.end),
+                    None => cur..cur
+                }
+            },
             Movement::Rep(count, ref movement) => {
                 let mut total_range = self.movement_range(movement);
                 let cp = self.curr_loc();
@@ -292,6 +510,36 @@ impl Buffer {
         }
     }
 
+    /// find the next match of a regex after (or before, if not `forward`) a location, wrapping
+    /// around the ends of the buffer. Returns the location of the start of the match
+    pub fn search(&self, re: &Regex, from: (usize, usize), forward: bool) -> Option<(usize, usize)> {
+        let n = self.line_count();
+        let from_line = self.line(from.1);
+        if forward {
+            if let Some(m) = re.find_iter(&from_line).find(|m| m.start() > from.0) {
+                return Some((m.start(), from.1));
+            }
+            for i in 1..n {
+                let line = (from.1 + i) % n;
+                if let Some(m) = re.find(&self.line(line)) {
+                    return Some((m.start(), line));
+                }
+            }
+            re.find(&from_line).map(|m| (m.start(), from.1))
+        } else {
+            if let Some(m) = re.find_iter(&from_line).filter(|m| m.start() < from.0).last() {
+                return Some((m.start(), from.1));
+            }
+            for i in 1..n {
+                let line = (from.1 + n - i) % n;
+                if let Some(m) = re.find_iter(&self.line(line)).last() {
+                    return Some((m.start(), line));
+                }
+            }
+            re.find_iter(&from_line).last().map(|m| (m.start(), from.1))
+        }
+    }
+
     pub fn make_movement(&mut self, mv: Movement) {
         let new_pos = self.movement_range(&mv).end;
         self.place_cursor(new_pos.0, new_pos.1);
@@ -308,29 +556,36 @@ impl Buffer {
         let incm = mv.inclusion_mode();
         let ::std::ops::Range { mut start, mut end } = self.movement_range(&mv);
         println!("\tfrom {:?} to {:?}", start, end);
-        self.line_layouts[start.1] = None;
-        for line in (start.1)..(end.1) {
-            println!("\tline {}: {}", line, self.lines[line]);
-            self.line_layouts[line] = None;
-        }
 
         if incm == Inclusion::Inclusive { end.0 += 1; }
 
-        if start.1 == end.1 { // all in the same line
-            if start.0 > self.lines[start.1].len() || end.0 > self.lines[start.1].len() { return removed; }
-            removed.push_str(&self.lines[start.1]
-                           .drain(if start.0 > end.0 { (end.0)..(start.0) } else { (start.0)..(end.0) })
-                           .collect::<String>());
+        if incm == Inclusion::Linewise {
+            // every line from the start to the end, including both
+            let (first, last) = (start.1.min(end.1), start.1.max(end.1).min(self.line_count()-1));
+            for i in first..(last+1) {
+                removed.push_str(&self.line(i));
+                removed.push_str("\n");
+            }
+            self.delete_lines(first, last+1);
+            self.place_cursor(0, first);
+        } else if mv.is_charwise() && start.1 != end.1 {
+            if (start.1, start.0) > (end.1, end.0) { ::std::mem::swap(&mut start, &mut end); }
+            end.0 = end.0.min(self.line_len(end.1));
+            removed = self.delete_text(start, end);
+            self.place_cursor(start.0, start.1);
+        } else if start.1 == end.1 { // all in the same line
+            let len = self.line_len(start.1);
+            if start.0 > len || end.0 > len { return removed; }
+            if start.0 > end.0 { ::std::mem::swap(&mut start, &mut end); }
+            removed = self.delete_text(start, end);
+            self.place_cursor(start.0, start.1);
         } else {
+            if start.1 > end.1 { ::std::mem::swap(&mut start, &mut end); }
             for i in (start.1)..(end.1) {
-                removed.push_str(&self.lines.remove(i));
+                removed.push_str(&self.line(i));
                 removed.push_str("\n");
-                self.line_layouts.remove(i);
             }
-        }
-        if self.lines.len() == 0 {
-            self.lines.push(String::new());
-            self.line_layouts.push(None);
+            self.delete_lines(start.1, end.1);
         }
         println!("\t removed: \"{}\"", removed);
         self.move_cursor((0,0));  //ensure that the cursor is in a valid position
@@ -344,16 +599,29 @@ impl Buffer {
         let ::std::ops::Range { mut start, mut end } = self.movement_range(&mv);
         println!("\tfrom {:?} to {:?}", start, end);
         for line in (start.1)..(end.1) {
-            println!("\tline {}: {}", line, self.lines[line]);
+            println!("\tline {}: {}", line, self.line(line));
         }
 
         if incm == Inclusion::Inclusive { end.0 += 1; }
 
-        if start.1 == end.1 { // all in the same line
-            selected.push_str(&self.lines[start.1][if start.0 > end.0 { (end.0)..(start.0) } else { (start.0)..(end.0) }]);
+        if incm == Inclusion::Linewise {
+            let last = start.1.max(end.1).min(self.line_count()-1);
+            for i in start.1.min(end.1)..(last+1) {
+                selected.push_str(&self.line(i));
+                selected.push_str("\n");
+            }
+        } else if mv.is_charwise() && start.1 != end.1 {
+            if (start.1, start.0) > (end.1, end.0) { ::std::mem::swap(&mut start, &mut end); }
+            end.0 = end.0.min(self.line_len(end.1));
+            let r = self.loc_to_byte(start)..self.loc_to_byte(end);
+            selected.push_str(&self.text.slice(r));
+        } else if start.1 == end.1 { // all in the same line
+            let line_start = self.text.line_to_byte(start.1);
+            let r = if start.0 > end.0 { (end.0)..(start.0) } else { (start.0)..(end.0) };
+            selected.push_str(&self.text.slice((line_start + r.start)..(line_start + r.end)));
         } else {
             for i in (start.1)..(end.1) {
-                selected.push_str(&self.lines[i]);
+                selected.push_str(&self.line(i));
                 selected.push_str("\n");
             }
         }
@@ -383,37 +651,668 @@ impl Buffer {
         }*/
     }
 
+    /// the current Visual mode selection, between the anchor and the cursor
+    pub fn selection(&self) -> Option<Selection> {
+        self.visual_anchor.map(|(kind, anchor)| {
+            let cur = self.curr_loc();
+            match kind {
+                SelectionKind::Block => {
+                    let (a, c) = (self.char_col(anchor), self.char_col(cur));
+                    Selection { kind,
+                        start: (a.min(c), anchor.1.min(cur.1)),
+                        end: (a.max(c), anchor.1.max(cur.1)) }
+                },
+                _ => {
+                    let (start, end) = if (anchor.1, anchor.0) <= (cur.1, cur.0) { (anchor, cur) } else { (cur, anchor) };
+                    Selection { kind, start, end }
+                }
+            }
+        })
+    }
+
+    /// the column of a location counted in characters instead of bytes
+    pub fn char_col(&self, loc: (usize, usize)) -> usize {
+        let line_start = self.text.line_to_byte(loc.1);
+        self.text.byte_to_char(line_start + loc.0) - self.text.byte_to_char(line_start)
+    }
+
+    /// the byte column of the character at char column `col` on a line, if the line is that long
+    pub fn byte_col(&self, line: usize, col: usize) -> Option<usize> {
+        let ln = self.line(line);
+        if col == ln.chars().count() { return Some(ln.len()); }
+        ln.char_indices().nth(col).map(|(i, _)| i)
+    }
+
+    /// the byte range of a line that is covered by a selection. A range that runs to the end of
+    /// the line doesn't include the line break
+    pub fn selection_on_line(&self, sel: &Selection, line: usize) -> Option<::std::ops::Range<usize>> {
+        if line < sel.start.1 || line > sel.end.1 { return None; }
+        let len = self.line_len(line);
+        match sel.kind {
+            SelectionKind::Line => Some(0..len),
+            SelectionKind::Char => {
+                let s = if line == sel.start.1 { sel.start.0.min(len) } else { 0 };
+                let e = if line == sel.end.1 {
+                    let ln = self.line(line);
+                    let e = sel.end.0.min(len);
+                    e + ln[e..].chars().next().map_or(0, char::len_utf8)
+                } else { len };
+                Some(s..e)
+            },
+            SelectionKind::Block => {
+                self.byte_col(line, sel.start.0).map(|s| {
+                    let e = self.byte_col(line, sel.end.0 + 1).unwrap_or(len);
+                    s..e
+                })
+            }
+        }
+    }
+
+    /// copy out the text covered by a selection. Linewise selections end with a line break so
+    /// that they get put back as whole lines
+    pub fn yank_selection(&self, sel: &Selection) -> String {
+        match sel.kind {
+            SelectionKind::Line => {
+                let mut s = String::new();
+                for line in (sel.start.1)..(sel.end.1+1) {
+                    s.push_str(&self.line(line));
+                    s.push('\n');
+                }
+                s
+            },
+            SelectionKind::Char => {
+                let start = self.loc_to_byte(sel.start);
+                let end_range = self.selection_on_line(sel, sel.end.1).unwrap();
+                let end = self.text.line_to_byte(sel.end.1) + end_range.end;
+                self.text.slice(start..end)
+            },
+            SelectionKind::Block => {
+                let mut parts = Vec::new();
+                for line in (sel.start.1)..(sel.end.1+1) {
+                    let line_start = self.text.line_to_byte(line);
+                    parts.push(self.selection_on_line(sel, line)
+                               .map_or(String::new(), |r| self.text.slice((line_start+r.start)..(line_start+r.end))));
+                }
+                parts.join("\n")
+            }
+        }
+    }
+
+    /// delete the text covered by a selection, returning it as yank_selection would. The cursor is
+    /// left at the start of the selection
+    pub fn delete_selection(&mut self, sel: &Selection) -> String {
+        let removed = self.yank_selection(sel);
+        match sel.kind {
+            SelectionKind::Line => {
+                self.delete_lines(sel.start.1, sel.end.1+1);
+                self.place_cursor(0, sel.start.1);
+            },
+            SelectionKind::Char => {
+                let end_range = self.selection_on_line(sel, sel.end.1).unwrap();
+                self.delete_text(sel.start, (end_range.end, sel.end.1));
+                self.place_cursor(sel.start.0, sel.start.1);
+            },
+            SelectionKind::Block => {
+                for line in ((sel.start.1)..(sel.end.1+1)).rev() {
+                    if let Some(r) = self.selection_on_line(sel, line) {
+                        self.delete_text((r.start, line), (r.end, line));
+                    }
+                }
+                let col = self.byte_col(sel.start.1, sel.start.0).unwrap_or(0);
+                self.place_cursor(col, sel.start.1);
+            }
+        }
+        removed
+    }
+
+    /// swap the case of every character covered by a selection
+    pub fn toggle_case_selection(&mut self, sel: &Selection) {
+        for line in (sel.start.1)..(sel.end.1+1) {
+            if let Some(r) = self.selection_on_line(sel, line) {
+                let line_start = self.text.line_to_byte(line);
+                let old = self.text.slice((line_start+r.start)..(line_start+r.end));
+                let new: String = old.chars().flat_map(|c| {
+                    let v: Vec<char> = if c.is_lowercase() { c.to_uppercase().collect() } else { c.to_lowercase().collect() };
+                    v.into_iter()
+                }).collect();
+                if new != old {
+                    self.delete_text((r.start, line), (r.end, line));
+                    self.insert_text((r.start, line), &new);
+                }
+            }
+        }
+        self.place_cursor(sel.start.0, sel.start.1);
+    }
+
+    /// indent (or outdent) every line in first..=last by one level
+    pub fn indent_lines(&mut self, first: usize, last: usize, outdent: bool) {
+        for line in first..(last+1) {
+            if outdent {
+                let ln = self.line(line);
+                let n = if ln.starts_with('\t') { 1 } else {
+                    let w = match self.tab_style { TabStyle::Spaces(w) => w, TabStyle::Tab => self.tab_width };
+                    ln.chars().take(w).take_while(|&c| c == ' ').count()
+                };
+                self.delete_text((0, line), (n, line));
+            } else if self.line_len(line) > 0 {
+                let mut indent = String::new();
+                self.indent_line(&mut indent, 1);
+                self.insert_text((0, line), &indent);
+            }
+        }
+        let col = self.cursor_col;
+        self.place_cursor(col, first);
+    }
+
     pub fn clear(&mut self) {
         self.cursor_col = 0; self.cursor_line = 0;
-        self.lines.clear(); self.line_layouts.clear();
-        self.lines = vec![String::from("")];
-        self.line_layouts = vec![None];
+        self.text = Rope::new();
+        self.line_layouts.clear();
+        self.highlight = Highlighter::new(self.fs_loc.as_ref().map(|p| p.as_path()));
+        self.history.clear();
+    }
+
+    /// replace all of the text, even in a read-only buffer. This can't be undone
+    pub fn set_text(&mut self, text: &str) {
+        let (col, line) = self.curr_loc();
+        self.clear();
+        if text.len() > 0 { self.raw_insert((0, 0), text); }
+        self.place_cursor(col, line);
     }
 
     pub fn invalidate_line(&mut self, line: usize) {
-        self.line_layouts[line] = None;
+        self.line_layouts.remove(&line);
+    }
+
+    /// lines after `line` have moved by `delta` lines, so move their layouts too
+    fn shift_layouts(&mut self, line: usize, delta: isize) {
+        if delta == 0 { return; }
+        let old = ::std::mem::replace(&mut self.line_layouts, HashMap::new());
+        for (ln, l) in old {
+            if ln <= line {
+                self.line_layouts.insert(ln, l);
+            } else if (ln as isize + delta) > line as isize {
+                self.line_layouts.insert((ln as isize + delta) as usize, l);
+            }
+        }
+    }
+
+    // lines first..=old_last are now first..=new_last. If that changes whether the lines after
+    // them start in a comment or string, their layouts have to be colored again
+    fn rehighlight(&mut self, first: usize, old_last: usize, new_last: usize) {
+        if self.highlight.edited(&self.text, first, old_last, new_last) {
+            self.line_layouts.retain(|&ln, _| ln <= new_last);
+        }
+    }
+
+    /// the location just past the end of `text` if it were inserted at `at`
+    fn end_of_text(at: (usize, usize), text: &str) -> (usize, usize) {
+        match text.rfind('\n') {
+            Some(i) => (text.len() - i - 1, at.1 + text.matches('\n').count()),
+            None => (at.0 + text.len(), at.1)
+        }
+    }
+
+    /// where a location ends up once the text from `at` to `end` has been inserted
+    fn loc_after_insert(loc: (usize, usize), at: (usize, usize), end: (usize, usize)) -> (usize, usize) {
+        if (loc.1, loc.0) < (at.1, at.0) { loc }
+        else if loc.1 == at.1 { (end.0 + loc.0 - at.0, end.1) }
+        else { (loc.0, loc.1 + end.1 - at.1) }
+    }
+
+    /// where a location ends up once the text from `start` to `end` has been deleted. Locations
+    /// inside it go to `start`
+    fn loc_after_delete(loc: (usize, usize), start: (usize, usize), end: (usize, usize)) -> (usize, usize) {
+        if (loc.1, loc.0) <= (start.1, start.0) { loc }
+        else if (loc.1, loc.0) <= (end.1, end.0) { start }
+        else if loc.1 == end.1 { (start.0 + loc.0 - end.0, start.1) }
+        else { (loc.0, loc.1 - (end.1 - start.1)) }
+    }
+
+    /// insert text at a location without recording it in the undo history. Returns the location
+    /// just past the end of the inserted text
+    fn raw_insert(&mut self, at: (usize, usize), text: &str) -> (usize, usize) {
+        if self.has_language_server() {
+            let p = self.lsp_position(at);
+            self.record_lsp_change(ContentChange { start: p, end: p, range_length: 0, text: String::from(text) });
+        }
+        let b = self.loc_to_byte(at);
+        self.text.insert(b, text);
+        let new_lines = text.matches('\n').count();
+        self.invalidate_line(at.1);
+        self.shift_layouts(at.1, new_lines as isize);
+        self.rehighlight(at.1, at.1, at.1 + new_lines);
+        if new_lines > 0 {
+            // inserting whole lines in front of a line moves it down too
+            let first_moved = if at.0 == 0 && text.ends_with('\n') { at.1 } else { at.1 + 1 };
+            for a in self.line_anchors.values_mut() {
+                if let Some(ref mut l) = *a {
+                    if *l >= first_moved { *l += new_lines; }
+                }
+            }
+        }
+        let end = Buffer::end_of_text(at, text);
+        for d in self.diagnostics.iter_mut() {
+            d.start = Buffer::loc_after_insert(d.start, at, end);
+            d.end = Buffer::loc_after_insert(d.end, at, end);
+        }
+        end
+    }
+
+    /// remove the text in the range start..end without recording it in the undo history
+    fn raw_delete(&mut self, start: (usize, usize), end: (usize, usize)) -> String {
+        let r = self.loc_to_byte(start)..self.loc_to_byte(end);
+        if self.has_language_server() {
+            let range_length = self.text.byte_to_utf16(r.end) - self.text.byte_to_utf16(r.start);
+            let (s, e) = (self.lsp_position(start), self.lsp_position(end));
+            self.record_lsp_change(ContentChange { start: s, end: e, range_length, text: String::new() });
+        }
+        if end.1 > start.1 {
+            // which lines disappear depends on whether whole lines were deleted or some were joined.
+            // Deleting the last lines takes the line break at the end of the line before them,
+            // which stays even when it is empty, so starts at column 0 too
+            let at_end = end == (self.line_len(end.1), self.line_count()-1);
+            let removed_lines = if at_end && start.0 == self.line_len(start.1) {
+                (start.1+1)..(end.1+1)
+            } else if start.0 == 0 && end.0 == 0 {
+                start.1..end.1
+            } else if start.0 == 0 && end.0 == self.line_len(end.1) {
+                start.1..(end.1+1)
+            } else {
+                (start.1+1)..(end.1+1)
+            };
+            let n = end.1 - start.1;
+            for a in self.line_anchors.values_mut() {
+                let l = match *a { Some(l) => l, None => continue };
+                if l >= removed_lines.start && l < removed_lines.end { *a = None; }
+                else if l >= removed_lines.end { *a = Some(l - n); }
+            }
+        }
+        for d in self.diagnostics.iter_mut() {
+            d.start = Buffer::loc_after_delete(d.start, start, end);
+            d.end = Buffer::loc_after_delete(d.end, start, end);
+        }
+        let removed = self.text.slice(r.clone());
+        self.text.remove(r);
+        self.invalidate_line(start.1);
+        self.shift_layouts(start.1, -((end.1 - start.1) as isize));
+        self.rehighlight(start.1, end.1, start.1);
+        removed
+    }
+
+    fn record_lsp_change(&mut self, c: ContentChange) {
+        for l in self.lang_servers.iter_mut() { l.1.push(c.clone()); }
+        self.lsp_changed_at = Instant::now();
+    }
+
+    /// replace the diagnostics from one language server, its index in `State::language_servers`,
+    /// with the ones in a publishDiagnostics notification
+    pub fn set_diagnostics(&mut self, server: usize, items: &JsonValue) {
+        let mut ds = items.members().map(|d| Diagnostic {
+            start: self.from_lsp_json(&d["range"]["start"]),
+            end: self.from_lsp_json(&d["range"]["end"]),
+            severity: match d["severity"].as_u8() {
+                Some(2) => Severity::Warning,
+                Some(3) => Severity::Information,
+                Some(4) => Severity::Hint,
+                _ => Severity::Error
+            },
+            message: d["message"].as_str().unwrap_or("").into(),
+            source: d["source"].as_str().map(String::from),
+            server
+        }).collect::<Vec<_>>();
+        ds.extend(self.diagnostics.drain(..).filter(|d| d.server != server));
+        ds.sort_by_key(|d| (d.start.1, d.start.0));
+        self.diagnostics = ds;
+    }
+
+    /// the most severe diagnostic covering a location, or failing that, one on its line
+    pub fn diagnostic_at(&self, loc: (usize, usize)) -> Option<&Diagnostic> {
+        let (col, line) = loc;
+        let covers = |d: &&Diagnostic| (d.start.1, d.start.0) <= (line, col) && (line, col) <= (d.end.1, d.end.0);
+        self.diagnostics.iter().filter(covers).min_by_key(|d| d.severity)
+            .or_else(|| self.diagnostics.iter().filter(|d| d.start.1 <= line && line <= d.end.1).min_by_key(|d| d.severity))
+    }
+
+    /// where the next (or previous) diagnostic after a location starts, wrapping around the buffer
+    pub fn next_diagnostic(&self, from: (usize, usize), forward: bool) -> Option<(usize, usize)> {
+        let from = (from.1, from.0);
+        let starts = self.diagnostics.iter().map(|d| (d.start.1, d.start.0));
+        let next = if forward {
+            starts.clone().find(|&s| s > from).or(starts.clone().next())
+        } else {
+            starts.clone().filter(|&s| s < from).last().or(starts.clone().last())
+        };
+        next.map(|(line, col)| (col, line))
+    }
+
+    /// ask a language server for the document's outline, which replaces `symbols` when it
+    /// arrives unless the buffer has changed again by then
+    pub fn request_symbols(&self) {
+        let ls = match self.language_server(|c| c.document_symbol) { Some(ls) => ls, None => return };
+        let mut ls = ls.borrow_mut();
+        let (path, uri) = match (self.fs_loc.clone(), self.uri()) {
+            (Some(p), Some(u)) => (p, u),
+            _ => return
+        };
+        let version = self.version;
+        let log = ls.log.clone();
+        ls.request("textDocument/documentSymbol", object!{
+            "textDocument" => object!{ "uri" => uri }
+        }, move |r, app| match r {
+            Ok(symbols) => {
+                for b in app.bufs.iter() {
+                    let mut b = b.borrow_mut();
+                    if b.fs_loc.as_ref() == Some(&path) && b.version == version { b.set_symbols(&symbols); }
+                }
+            },
+            Err(e) => log.lock().expect("lock traffic log").note(Direction::Received, &format!("document symbols for {}: {}", path.display(), e))
+        });
+    }
+
+    /// ask a language server for semantic tokens for the whole document, if one has them. They
+    /// are dropped if the buffer changes before they arrive
+    pub fn request_semantic_tokens(&self) {
+        let ls = match self.language_server(|c| c.semantic_tokens.is_some()) { Some(ls) => ls, None => return };
+        let legend = ls.borrow().capabilities.semantic_tokens.iter().flat_map(|l| l.iter())
+            .map(|t| Style::from_token_type(t)).collect::<Vec<_>>();
+        let mut ls = ls.borrow_mut();
+        let (path, uri) = match (self.fs_loc.clone(), self.uri()) {
+            (Some(p), Some(u)) => (p, u),
+            _ => return
+        };
+        let version = self.version;
+        let log = ls.log.clone();
+        ls.request("textDocument/semanticTokens/full", object!{
+            "textDocument" => object!{ "uri" => uri }
+        }, move |r, app| match r {
+            Ok(tokens) => {
+                for b in app.bufs.iter() {
+                    let mut b = b.borrow_mut();
+                    if b.fs_loc.as_ref() == Some(&path) && b.version == version {
+                        let b = &mut *b;
+                        b.highlight.set_semantic_tokens(&b.text, &tokens["data"], &legend);
+                        b.line_layouts.clear();
+                    }
+                }
+            },
+            Err(e) => log.lock().expect("lock traffic log").note(Direction::Received, &format!("semantic tokens for {}: {}", path.display(), e))
+        });
+    }
+
+    /// store the outline from a documentSymbol response. That is either a tree of DocumentSymbols,
+    /// or a flat list of SymbolInformation, which is nested by which symbols contain which
+    pub fn set_symbols(&mut self, items: &JsonValue) {
+        fn tree(b: &Buffer, s: &JsonValue) -> Symbol {
+            let mut children = s["children"].members().map(|c| tree(b, c)).collect::<Vec<_>>();
+            children.sort_by_key(|c| (c.start.1, c.start.0));
+            let range = &s["range"];
+            let name_range = if s.has_key("selectionRange") { &s["selectionRange"] } else { range };
+            Symbol {
+                name: s["name"].as_str().unwrap_or("").into(),
+                kind: s["kind"].as_usize().unwrap_or(0),
+                detail: s["detail"].as_str().map(String::from),
+                start: b.from_lsp_json(&range["start"]), end: b.from_lsp_json(&range["end"]),
+                name_at: b.from_lsp_json(&name_range["start"]),
+                children
+            }
+        }
+        let contains = |p: &Symbol, s: &Symbol| (p.start.1, p.start.0) <= (s.start.1, s.start.0) && (s.end.1, s.end.0) <= (p.end.1, p.end.0);
+
+        let flat = items.members().any(|s| s.has_key("location"));
+        let mut syms = items.members().map(|s| tree(self, if flat { &s["location"] } else { s })).collect::<Vec<_>>();
+        if flat {
+            for (sym, s) in syms.iter_mut().zip(items.members()) {
+                sym.name = s["name"].as_str().unwrap_or("").into();
+                sym.kind = s["kind"].as_usize().unwrap_or(0);
+            }
+        }
+        // outer symbols come before the ones inside them
+        syms.sort_by_key(|s| ((s.start.1, s.start.0), ::std::cmp::Reverse((s.end.1, s.end.0))));
+        if !flat {
+            self.symbols = syms;
+            return;
+        }
+        let mut out: Vec<Symbol> = Vec::new();
+        let mut open: Vec<Symbol> = Vec::new();
+        for s in syms {
+            while open.last().map_or(false, |p| !contains(p, &s)) {
+                let done = open.pop().unwrap();
+                match open.last_mut() { Some(p) => p.children.push(done), None => out.push(done) }
+            }
+            open.push(s);
+        }
+        while let Some(done) = open.pop() {
+            match open.last_mut() { Some(p) => p.children.push(done), None => out.push(done) }
+        }
+        self.symbols = out;
+    }
+
+    /// the symbols that contain a location, from the outermost in, for breadcrumbs
+    pub fn symbol_path(&self, loc: (usize, usize)) -> Vec<&Symbol> {
+        let at = (loc.1, loc.0);
+        let mut path = Vec::new();
+        let mut level = &self.symbols;
+        while let Some(s) = level.iter().find(|s| (s.start.1, s.start.0) <= at && at <= (s.end.1, s.end.0)) {
+            path.push(s);
+            level = &s.children;
+        }
+        path
+    }
+
+    /// every symbol in the outline in order, with how deeply it is nested
+    pub fn symbol_outline(&self) -> Vec<(usize, &Symbol)> {
+        fn walk<'a>(syms: &'a [Symbol], depth: usize, out: &mut Vec<(usize, &'a Symbol)>) {
+            for s in syms {
+                out.push((depth, s));
+                walk(&s.children, depth + 1, out);
+            }
+        }
+        let mut out = Vec::new();
+        walk(&self.symbols, 0, &mut out);
+        out
+    }
+
+    // the columns of a line that a diagnostic covers
+    fn diagnostic_on_line(&self, d: &Diagnostic, line: usize) -> Option<::std::ops::Range<usize>> {
+        if line < d.start.1 || line > d.end.1 { return None; }
+        let start = if line == d.start.1 { d.start.0 } else { 0 };
+        let end = if line == d.end.1 { d.end.0 } else { self.line_len(line) };
+        Some(start..end.max(start))
+    }
+
+    /// send the edits made since the last call to the language servers. Unless `now` is set, this
+    /// waits until there haven't been any edits for a moment, so that typing is sent in batches.
+    /// Returns whether anything was sent
+    pub fn sync_language_server(&mut self, now: bool) -> bool {
+        if !now && self.lsp_changed_at.elapsed() < Duration::from_millis(LSP_SYNC_DELAY_MS) {
+            return false;
+        }
+        // until a server has said how it wants changes, they have to wait
+        let ready = (0..self.lang_servers.len())
+            .filter(|&i| self.lang_servers[i].1.len() > 0 && self.lang_servers[i].0.borrow().is_initialized())
+            .collect::<Vec<_>>();
+        if ready.len() == 0 { return false; }
+        self.version += 1;
+        for i in ready {
+            let ls = self.lang_servers[i].0.clone();
+            let changes = ::std::mem::replace(&mut self.lang_servers[i].1, Vec::new());
+            ls.borrow_mut().document_did_change(self, changes);
+        }
+        self.request_symbols();
+        self.request_semantic_tokens();
+        true
+    }
+
+    fn apply_edit(&mut self, e: &Edit) {
+        match e {
+            &Edit::Insert { at, ref text } => { self.raw_insert(at, text); },
+            &Edit::Delete { at, ref text } => {
+                let end = Buffer::end_of_text(at, text);
+                self.raw_delete(at, end);
+            }
+        }
+    }
+
+    /// insert text (which may contain newlines) at a location, recording it so that it can be
+    /// undone. Returns the location just past the end of the inserted text. The cursor is not moved
+    pub fn insert_text(&mut self, at: (usize, usize), text: &str) -> (usize, usize) {
+        if text.len() == 0 || self.read_only { return at; }
+        let end = self.raw_insert(at, text);
+        let cur = self.curr_loc();
+        self.history.record(Edit::Insert { at, text: String::from(text) }, cur);
+        end
+    }
+
+    /// delete the text in the range start..end, recording it so that it can be undone. Returns the
+    /// removed text. The cursor is not moved
+    pub fn delete_text(&mut self, start: (usize, usize), end: (usize, usize)) -> String {
+        if start == end || self.read_only { return String::new(); }
+        let removed = self.raw_delete(start, end);
+        let cur = self.curr_loc();
+        self.history.record(Edit::Delete { at: start, text: removed.clone() }, cur);
+        removed
+    }
+
+    /// delete the whole lines in first..last, including their line breaks
+    pub fn delete_lines(&mut self, first: usize, last: usize) {
+        let last_line = self.line_count()-1;
+        if last <= last_line {
+            self.delete_text((0, first), (0, last));
+        } else if first > 0 {
+            // there is no line break after the last line, so take the one before the first line
+            let prev_len = self.line_len(first-1);
+            let last_len = self.line_len(last_line);
+            self.delete_text((prev_len, first-1), (last_len, last_line));
+        } else {
+            let last_len = self.line_len(last_line);
+            self.delete_text((0, 0), (last_len, last_line));
+        }
+    }
+
+    /// apply an array of LSP TextEdits, whose ranges all refer to the text as it was before any of
+    /// them, as one undoable step. The cursor stays on its line
+    pub fn apply_text_edits(&mut self, edits: &JsonValue) {
+        let mut edits = edits.members()
+            .map(|e| (self.from_lsp_json(&e["range"]["start"]), self.from_lsp_json(&e["range"]["end"]), e["newText"].as_str().unwrap_or("")))
+            .collect::<Vec<_>>();
+        if edits.len() == 0 { return; }
+        // from the bottom up, so that the edits don't move each other. Edits that start in the same
+        // place are inserted in reverse, so they end up in the order they were given
+        edits.sort_by_key(|&(s, _, _)| (s.1, s.0));
+        let (col, line) = self.curr_loc();
+        let anchor = self.add_line_anchor(line);
+        self.begin_edit_group();
+        for (start, end, text) in edits.into_iter().rev() {
+            self.delete_text(start, end);
+            self.insert_text(start, text);
+        }
+        self.end_edit_group();
+        let line = self.line_anchor(anchor).unwrap_or(line);
+        self.remove_line_anchor(anchor);
+        self.place_cursor(col, line);
+    }
+
+    /// start following a line through edits, returning an id for `line_anchor`
+    pub fn add_line_anchor(&mut self, line: usize) -> usize {
+        let id = self.next_anchor;
+        self.next_anchor += 1;
+        self.line_anchors.insert(id, Some(line));
+        id
+    }
+
+    /// where an anchored line is now, or None if it has been deleted
+    pub fn line_anchor(&self, id: usize) -> Option<usize> {
+        self.line_anchors.get(&id).and_then(|l| *l)
+    }
+
+    pub fn remove_line_anchor(&mut self, id: usize) {
+        self.line_anchors.remove(&id);
+    }
+
+    /// set mark `c` at a location, moving it if it was already set
+    pub fn set_mark(&mut self, c: char, loc: (usize, usize)) {
+        self.remove_mark(c);
+        let id = self.add_line_anchor(loc.1);
+        self.marks.insert(c, (id, loc.0));
+    }
+
+    /// where mark `c` is now. Marks follow their line through edits, and are gone once it's deleted
+    pub fn mark(&self, c: char) -> Option<(usize, usize)> {
+        self.marks.get(&c).and_then(|&(id, col)| self.line_anchor(id).map(|line| (col.min(self.line_len(line)), line)))
+    }
+
+    pub fn remove_mark(&mut self, c: char) {
+        if let Some((id, _)) = self.marks.remove(&c) {
+            self.remove_line_anchor(id);
+        }
+    }
+
+    /// start a group of edits that will be undone together
+    pub fn begin_edit_group(&mut self) {
+        let cur = self.curr_loc();
+        self.history.begin_group(cur);
+    }
+
+    pub fn end_edit_group(&mut self) {
+        let cur = self.curr_loc();
+        self.history.end_group(cur);
+    }
+
+    /// undo the last group of edits, returning false if there was nothing to undo
+    pub fn undo(&mut self) -> bool {
+        let cur = self.curr_loc();
+        match self.history.pop_undo(cur) {
+            Some(g) => {
+                for e in g.edits.iter().rev() {
+                    self.apply_edit(&e.inverse());
+                }
+                self.place_cursor(g.cursor_before.0, g.cursor_before.1);
+                self.history.push_redo(g);
+                true
+            },
+            None => false
+        }
+    }
+
+    /// redo the last undone group of edits, returning false if there was nothing to redo
+    pub fn redo(&mut self) -> bool {
+        let cur = self.curr_loc();
+        match self.history.pop_redo(cur) {
+            Some(g) => {
+                for e in g.edits.iter() {
+                    self.apply_edit(e);
+                }
+                self.place_cursor(g.cursor_after.0, g.cursor_after.1);
+                self.history.push_undo(g);
+                true
+            },
+            None => false
+        }
     }
 
     pub fn insert_char(&mut self, c: char) {
         let loc = self.curr_loc();
-        self.lines[loc.1].insert(loc.0, c);
-        self.invalidate_line(loc.1);
-        self.move_cursor((1, 0));
+        self.insert_text(loc, c.encode_utf8(&mut [0; 4]));
+        self.move_cursor((c.len_utf8() as isize, 0));
     }
     pub fn delete_char(&mut self) {
         let loc = self.curr_loc();
-        if loc.0 >= self.lines[loc.1].len() {
-            self.lines[loc.1].pop();
-        }
-        else {
-            self.lines[loc.1].remove(loc.0);
-        }
-        self.invalidate_line(loc.1);
+        let line = self.line(loc.1);
+        let start = if loc.0 >= line.len() {
+            match line.char_indices().last() {
+                Some((i, _)) => i,
+                None => return
+            }
+        } else { loc.0 };
+        let end = start + line[start..].chars().next().map_or(0, char::len_utf8);
+        self.delete_text((start, loc.1), (end, loc.1));
     }
     
     fn compute_line_indent(&self, line: usize) -> usize {
         let mut i = 0;
-        let mut ch = self.lines[line].chars();
+        let ln = self.line(line);
+        let mut ch = ln.chars();
         let spaces_in_indent = match self.tab_style {
             TabStyle::Spaces(n) => n,
             TabStyle::Tab => self.tab_width
@@ -470,16 +1369,13 @@ impl Buffer {
 
     pub fn break_line(&mut self) {
         let loc = self.curr_loc();
-        let mut new_line = if loc.0 >= self.lines[loc.1].len() {
-            String::from("")
-        } else {
-            self.lines[loc.1].split_off(loc.0)
-        };
-        let indent = self.compute_line_indent(loc.1) + if new_line.len() == 0 { 1 } else { 0 };
+        let line_len = self.line_len(loc.1);
+        let at_end = loc.0 >= line_len;
+        let indent = self.compute_line_indent(loc.1) + if at_end { 1 } else { 0 };
+        let mut new_line = String::new();
         let ln = self.indent_line(&mut new_line, indent);
-        self.lines.insert(loc.1+1, new_line);
-        self.invalidate_line(loc.1);
-        self.line_layouts.insert(loc.1, None);
+        let col = if at_end { line_len } else { loc.0 };
+        self.insert_text((col, loc.1), &(String::from("\n") + &new_line));
         self.viewport_end += 1;
         self.cursor_col = ln;
         self.move_cursor((0,1));
@@ -489,8 +1385,8 @@ impl Buffer {
         let mut line = val.map(|s| String::from(s)).unwrap_or_default();
         let indent = self.compute_line_indent(loc);
         let indent_ln = self.indent_line(&mut line, indent);
-        self.lines.insert(loc+1, line);
-        self.line_layouts.insert(loc+1, None);
+        let eol = self.line_len(loc);
+        self.insert_text((eol, loc), &(String::from("\n") + &line));
         self.viewport_end += 1;
         self.cursor_col = indent_ln; self.move_cursor((0,1));
     }
@@ -516,8 +1412,7 @@ impl Buffer {
             let mut lns = s.lines();
             let fln = lns.next().unwrap();
             let loc = self.curr_loc();
-            self.lines[loc.1].insert_str(loc.0, fln);
-            self.invalidate_line(loc.1);
+            self.insert_text(loc, fln);
             self.move_cursor((fln.len() as isize, 0));
             for ln in lns {
                 self.insert_line(Some(ln));
@@ -526,16 +1421,19 @@ impl Buffer {
     }
 
     pub fn sync_disk(&mut self) -> Result<(), IoError> {
-        let lines = self.lines.iter();
         match self.fs_loc {
             Some(ref path) => {
-                let mut f = OpenOptions::new().write(true).truncate(true).create(true).open(path.as_path())?;
-                for ln in lines {
-                    write!(f, "{}\n", ln)?;
+                let f = OpenOptions::new().write(true).truncate(true).create(true).open(path.as_path())?;
+                let mut w = BufWriter::new(f);
+                for chunk in self.text.chunks() {
+                    w.write_all(chunk.as_bytes())?;
                 }
+                w.write_all(b"\n")?;
+                let f = w.into_inner().map_err(|e| e.into_error())?;
                 f.sync_all()?;
-                if let Some(ref mut ls) = self.lang_server.clone() {
-                    ls.borrow_mut().document_did_save(self);
+                self.sync_language_server(true);
+                for ls in self.lang_servers.iter() {
+                    ls.0.borrow_mut().document_did_save(self);
                 }
                 Ok(())
             },
@@ -543,26 +1441,76 @@ impl Buffer {
         }
     }
 
-    pub fn paint(&mut self, rx: &mut RenderContext, bnd: Rect) {
+    pub fn paint(&mut self, rx: &mut RenderContext, bnd: Rect, highlight: Option<&Regex>) {
+        // buffers with a language server have a gutter for marking diagnostics
+        let gutter = if self.has_language_server() { GUTTER_WIDTH } else { 0.0 };
         //draw text
-        let mut p = Point::xy(bnd.x, bnd.y);
+        let mut p = Point::xy(bnd.x + gutter, bnd.y);
         let mut line = self.viewport_start;
         rx.set_color(Color::rgb(0.9, 0.9, 0.9));
-        'lineloop: while line < self.line_layouts.len() {
+        let line_count = self.line_count();
+        let sel = self.selection();
+        self.cursor_bounds = None;
+        'lineloop: while line < line_count {
             let mut replace = false;
-            match self.line_layouts[line] {
-                Some(ref l) => { 
+            match self.line_layouts.get(&line) {
+                Some(l) => { 
                     let b = l.bounds();
                     if p.y + b.h > bnd.y+bnd.h { break 'lineloop; }
+
+                    //draw selection
+                    if let Some(r) = sel.as_ref().and_then(|sel| self.selection_on_line(sel, line)) {
+                        let len = self.line_len(line);
+                        let x0 = if r.start < len { l.char_bounds(r.start).x } else { b.w };
+                        let x1 = if r.end < len { l.char_bounds(r.end).x } else { b.w + 8.0 };
+                        rx.set_color(Color::rgba(0.3, 0.4, 0.6, 0.6));
+                        rx.fill_rect(Rect::xywh(p.x + x0, p.y, x1 - x0, b.h));
+                        rx.set_color(Color::rgb(0.9, 0.9, 0.9));
+                    }
+
+                    //draw search matches
+                    if let Some(re) = highlight {
+                        let text = self.line(line);
+                        rx.set_color(Color::rgba(0.6, 0.5, 0.1, 0.5));
+                        for m in re.find_iter(&text) {
+                            if m.start() == m.end() { continue; }
+                            let x0 = l.char_bounds(m.start()).x;
+                            let x1 = if m.end() < text.len() { l.char_bounds(m.end()).x } else { b.w };
+                            rx.fill_rect(Rect::xywh(p.x + x0, p.y, x1 - x0, b.h));
+                        }
+                        rx.set_color(Color::rgb(0.9, 0.9, 0.9));
+                    }
+
                     rx.draw_text_layout(p, &l);
 
+                    //draw diagnostics
+                    let mut worst = None;
+                    for d in self.diagnostics.iter() {
+                        let r = match self.diagnostic_on_line(d, line) {
+                            Some(r) => r,
+                            None => continue
+                        };
+                        let len = self.line_len(line);
+                        let x0 = if r.start < len { l.char_bounds(r.start).x } else { b.w };
+                        let x1 = if r.end < len { l.char_bounds(r.end).x } else { b.w };
+                        rx.set_color(severity_color(d.severity));
+                        rx.fill_rect(Rect::xywh(p.x + x0, p.y + b.h - 2.0, (x1 - x0).max(8.0), 2.0));
+                        if worst.map_or(true, |w| d.severity < w) { worst = Some(d.severity); }
+                    }
+                    if let Some(sv) = worst {
+                        rx.set_color(severity_color(sv));
+                        rx.fill_rect(Rect::xywh(bnd.x, p.y + b.h*0.25, gutter*0.6, b.h*0.5));
+                    }
+                    rx.set_color(Color::rgb(0.9, 0.9, 0.9));
+
                     //draw cursor
                     if self.show_cursor && line == self.cursor_line {
                         let col = self.cursor_col;
-                        let mut cb = self.line_layouts[self.cursor_line].as_ref().map_or(Rect::xywh(0.0, 0.0, 8.0, 8.0), |v| v.char_bounds(col));
+                        let mut cb = l.char_bounds(col);
                         if cb.w == 0.0 { cb.w = 8.0; }
                         rx.set_color(Color::rgba(0.8, 0.6, 0.0, 0.9));
                         rx.fill_rect(cb.offset(p));
+                        self.cursor_bounds = Some(cb.offset(p));
                         rx.set_color(Color::rgb(0.9, 0.9, 0.9));
                     }
 
@@ -574,12 +1522,23 @@ impl Buffer {
                 }
             }
             if replace {
-                self.line_layouts[line] = rx.new_text_layout(&self.lines[line], &self.res.borrow().font,
-                bnd.w, bnd.h).ok();
+                let layout = rx.new_text_layout(&self.line(line), self.res.borrow().font(), bnd.w - gutter, bnd.h);
+                match layout {
+                    Ok(l) => {
+                        for s in self.highlight.spans(&self.text, line) {
+                            l.color_range(rx, (s.start as u32)..(s.end as u32), s.style.color());
+                        }
+                        self.line_layouts.insert(line, l);
+                    },
+                    Err(_) => { line += 1; }
+                }
             } else {
                 line += 1;
             }
         }
+        // forget layouts for lines that have scrolled out of view
+        let (vs, ve) = (self.viewport_start, line);
+        self.line_layouts.retain(|&ln, _| ln + 8 >= vs && ln <= ve + 8);
         self.viewport_end = line;
     }
 
@@ -587,14 +1546,83 @@ impl Buffer {
     }
 
     pub fn full_text(&self) -> String {
-        self.lines.iter().fold(String::new(), |a,i| a+i+"\n")
+        self.text.to_string() + "\n"
+    }
+
+    /// a buffer holding `text`, for tests of things that need one
+    #[cfg(test)]
+    pub fn with_text(text: &str) -> Buffer {
+        let mut b = Buffer::new(Rc::new(RefCell::new(Resources::without_font(None))));
+        b.set_text(text);
+        b
     }
 }
 
 impl Drop for Buffer {
     fn drop(&mut self) {
-        if let Some(ref mut ls) = self.lang_server.clone() {
-            ls.borrow_mut().document_did_close(self);
+        for ls in self.lang_servers.iter() {
+            ls.0.borrow_mut().document_did_close(self);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deleting_last_lines_keeps_empty_line_before() {
+        let mut b = Buffer::with_text("a\n\nb\nc");
+        let (empty, gone) = (b.add_line_anchor(1), b.add_line_anchor(3));
+        b.delete_lines(2, 4);
+        assert_eq!(b.full_text(), "a\n\n");
+        assert_eq!((b.line_anchor(empty), b.line_anchor(gone)), (Some(1), None));
+        // but a line whose text is all deleted goes with it
+        let mut b = Buffer::with_text("a\nb\nc");
+        let (first, last) = (b.add_line_anchor(1), b.add_line_anchor(2));
+        b.delete_text((0, 1), (1, 2));
+        assert_eq!((b.line_anchor(first), b.line_anchor(last)), (None, None));
+    }
+
+    #[test]
+    fn undo_and_redo() {
+        let mut b = Buffer::with_text("one\ntwo");
+        b.place_cursor(1, 1);
+        b.begin_edit_group();
+        b.insert_text((0, 1), "new\n");
+        assert_eq!(b.delete_text((1, 0), (3, 0)), "ne");
+        b.place_cursor(2, 2);
+        b.end_edit_group();
+        b.place_cursor(0, 0);
+        b.insert_text((3, 2), "!");
+        assert_eq!(b.full_text(), "o\nnew\ntwo!\n");
+        assert!(b.undo());
+        assert_eq!(b.full_text(), "o\nnew\ntwo\n");
+        assert_eq!(b.curr_loc(), (0, 0));
+        // the group goes in one step, and the cursor goes back to where it started
+        assert!(b.undo());
+        assert_eq!(b.full_text(), "one\ntwo\n");
+        assert_eq!(b.curr_loc(), (1, 1));
+        assert!(!b.undo());
+        assert!(b.redo());
+        assert_eq!(b.full_text(), "o\nnew\ntwo\n");
+        assert_eq!(b.curr_loc(), (2, 2));
+        assert!(b.redo());
+        assert_eq!(b.full_text(), "o\nnew\ntwo!\n");
+        assert!(!b.redo());
+    }
+
+    #[test]
+    fn locations_follow_edits() {
+        // "ab" inserted at (1, 2), then "xy\nz" at (3, 2)
+        assert_eq!(Buffer::loc_after_insert((0, 2), (1, 2), (3, 2)), (0, 2));
+        assert_eq!(Buffer::loc_after_insert((4, 2), (1, 2), (3, 2)), (6, 2));
+        assert_eq!(Buffer::loc_after_insert((4, 2), (3, 2), (1, 3)), (2, 3));
+        assert_eq!(Buffer::loc_after_insert((4, 5), (3, 2), (1, 3)), (4, 6));
+        // (1, 2)..(3, 4) deleted
+        assert_eq!(Buffer::loc_after_delete((5, 1), (1, 2), (3, 4)), (5, 1));
+        assert_eq!(Buffer::loc_after_delete((0, 3), (1, 2), (3, 4)), (1, 2));
+        assert_eq!(Buffer::loc_after_delete((7, 4), (1, 2), (3, 4)), (5, 2));
+        assert_eq!(Buffer::loc_after_delete((7, 6), (1, 2), (3, 4)), (7, 4));
+    }
+}
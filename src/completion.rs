@@ -0,0 +1,264 @@
+// the completion menu shown in Insert mode, filled by textDocument/completion. It opens when a
+// trigger character from the server's capabilities is typed, or with Ctrl-Space, and is filtered
+// by the word typed since it opened. Items' documentation is resolved when they are selected
+
+use runic::*;
+use json::JsonValue;
+
+use buffer::Buffer;
+use lsp::{self, PendingResponse};
+use popup;
+
+// how many items are shown at once
+const MENU_ROWS: usize = 10;
+
+pub struct Completion {
+    response: PendingResponse,
+    // the item at an index having its documentation filled in by completionItem/resolve
+    resolving: Option<(usize, PendingResponse)>,
+    items: Vec<JsonValue>,
+    // the list didn't have everything, so it is asked for again as the word changes
+    incomplete: bool,
+    // indices of the items that match the word typed so far
+    matches: Vec<usize>,
+    selected: usize,
+    // where the word being completed starts
+    start: (usize, usize)
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// does `word` appear in `text` in order, ignoring case?
+pub fn fuzzy_match(word: &str, text: &str) -> bool {
+    let mut cs = text.chars().flat_map(char::to_lowercase);
+    word.chars().flat_map(char::to_lowercase).all(|w| cs.any(|c| c == w))
+}
+
+// the text of a snippet with its tab stops and placeholders taken out, keeping placeholders'
+// default text
+fn strip_snippet(s: &str) -> String {
+    let mut out = String::new();
+    let mut cs = s.chars().peekable();
+    let mut depth = 0;
+    while let Some(c) = cs.next() {
+        match c {
+            '\\' => if let Some(e) = cs.next() { out.push(e) },
+            '$' => {
+                if cs.peek() == Some(&'{') {
+                    cs.next();
+                    while cs.peek().map_or(false, |c| c.is_digit(10)) { cs.next(); }
+                    if cs.peek() == Some(&':') { cs.next(); }
+                    depth += 1;
+                } else {
+                    while cs.peek().map_or(false, |c| c.is_digit(10)) { cs.next(); }
+                }
+            },
+            '}' if depth > 0 => depth -= 1,
+            c => out.push(c)
+        }
+    }
+    out
+}
+
+// the text of documentation, which is either a string or MarkupContent
+fn doc_text(d: &JsonValue) -> Option<String> {
+    d.as_str().or(d["value"].as_str()).map(String::from)
+}
+
+impl Completion {
+    /// ask the buffer's first language server that can complete for completions at the cursor.
+    /// `trigger` is the character that caused the request, if it wasn't asked for
+    pub fn request(buf: &mut Buffer, trigger: Option<char>) -> Option<Completion> {
+        let server = match buf.language_server(|c| c.completion.is_some()) {
+            Some(ls) => ls,
+            None => return None
+        };
+        let uri = match buf.uri() { Some(u) => u, None => return None };
+        // the server has to have seen everything up to the cursor
+        buf.sync_language_server(true);
+        let cur = buf.curr_loc();
+        let line = buf.line(cur.1);
+        let start = (line[..cur.0].rfind(|c: char| !is_word_char(c)).map(|i| i + line[i..].chars().next().unwrap().len_utf8()).unwrap_or(0), cur.1);
+        let response = PendingResponse::request(server, "textDocument/completion", object!{
+            "textDocument" => object!{ "uri" => uri },
+            "position" => lsp::position_to_json(buf.lsp_position(cur)),
+            "context" => match trigger {
+                Some(c) => object!{ "triggerKind" => 2, "triggerCharacter" => c.to_string() },
+                None => object!{ "triggerKind" => 1 }
+            }
+        }, "completion");
+        Some(Completion {
+            response, resolving: None,
+            items: Vec::new(), incomplete: false, matches: Vec::new(), selected: 0, start
+        })
+    }
+
+    /// is `c` one of the characters the completing server wants completion to start after?
+    pub fn is_trigger(buf: &Buffer, c: char) -> bool {
+        match buf.language_server(|caps| caps.completion.is_some()) {
+            Some(ls) => lsp::is_trigger(&ls.borrow().capabilities.completion, c),
+            None => false
+        }
+    }
+
+    /// the word typed since the menu opened, or None if the cursor has left it
+    fn word(&self, buf: &Buffer) -> Option<String> {
+        let cur = buf.curr_loc();
+        if cur.1 != self.start.1 || cur.0 < self.start.0 { return None; }
+        let w = String::from(&buf.line(cur.1)[self.start.0..cur.0]);
+        if w.chars().all(is_word_char) { Some(w) } else { None }
+    }
+
+    /// take in responses that have arrived and filter the items by what has been typed. Returns
+    /// false once the menu should close
+    pub fn update(&mut self, buf: &Buffer) -> bool {
+        let word = match self.word(buf) {
+            Some(w) => w,
+            None => return false
+        };
+        if let Some(r) = self.response.take() {
+            // either a CompletionList or just the items
+            self.incomplete = r["isIncomplete"].as_bool().unwrap_or(false);
+            let items = if r.is_array() { &r } else { &r["items"] };
+            self.items = items.members().cloned().collect();
+            self.items.sort_by(|a, b| {
+                let key = |i: &JsonValue| String::from(i["sortText"].as_str().or(i["label"].as_str()).unwrap_or(""));
+                key(a).cmp(&key(b))
+            });
+            if let Some((_, r)) = self.resolving.take() { r.cancel(); }
+        }
+        if let Some((i, item)) = self.resolving.as_mut().and_then(|&mut (i, ref mut r)| r.take().map(|item| (i, item))) {
+            if i < self.items.len() { self.items[i] = item; }
+        }
+        let selected_item = self.matches.get(self.selected).cloned();
+        let items = &self.items;
+        self.matches = (0..items.len()).filter(|&i| {
+            let text = items[i]["filterText"].as_str().or(items[i]["label"].as_str()).unwrap_or("");
+            fuzzy_match(&word, text)
+        }).collect();
+        // keep the same item selected as the list shrinks, if it's still there
+        self.selected = selected_item.and_then(|s| self.matches.iter().position(|&i| i == s)).unwrap_or(0);
+        self.resolve_selected();
+        true
+    }
+
+    /// the word has changed, so ask again if the last list was incomplete. Returns the new menu
+    pub fn refresh(self, buf: &mut Buffer) -> Option<Completion> {
+        if !self.incomplete { return Some(self); }
+        let start = self.start;
+        self.close();
+        Completion::request(buf, None).map(|mut c| { c.start = start; c })
+    }
+
+    // ask for the documentation of the selected item, if the server resolves items lazily
+    fn resolve_selected(&mut self) {
+        let i = match self.matches.get(self.selected) { Some(&i) => i, None => return };
+        if self.resolving.as_ref().map(|r| r.0) == Some(i) || !self.items[i]["documentation"].is_null() { return; }
+        if !self.response.server.borrow().capabilities.completion_resolve { return; }
+        if let Some((_, r)) = self.resolving.take() { r.cancel(); }
+        let server = self.response.server.clone();
+        self.resolving = Some((i, PendingResponse::request(server, "completionItem/resolve", self.items[i].clone(), "completion resolve")));
+    }
+
+    pub fn has_matches(&self) -> bool {
+        self.matches.len() > 0
+    }
+
+    /// move the selection down (or up) the menu, wrapping around
+    pub fn select(&mut self, down: bool) {
+        let n = self.matches.len();
+        if n == 0 { return; }
+        self.selected = if down { (self.selected + 1) % n } else { (self.selected + n - 1) % n };
+        self.resolve_selected();
+    }
+
+    /// put the selected item into the buffer, replacing the word typed so far
+    pub fn accept(self, buf: &mut Buffer) {
+        let item = match self.matches.get(self.selected) {
+            Some(&i) => self.items[i].clone(),
+            None => return
+        };
+        let cur = buf.curr_loc();
+        let (main, mut edits) = {
+            let snippet = item["insertTextFormat"].as_u8() == Some(2);
+            let text = |t: &str| if snippet { strip_snippet(t) } else { String::from(t) };
+            let pos = |p: &JsonValue| buf.from_lsp_json(p);
+            // the main edit goes from where it says it starts to the cursor, which has moved on if
+            // more has been typed since the request
+            let te = &item["textEdit"];
+            let main = if te.is_null() {
+                (self.start, cur, text(item["insertText"].as_str().or(item["label"].as_str()).unwrap_or("")))
+            } else {
+                let range = if te.has_key("range") { &te["range"] } else { &te["insert"] };
+                (pos(&range["start"]), cur, text(te["newText"].as_str().unwrap_or("")))
+            };
+            let edits = item["additionalTextEdits"].members()
+                .map(|e| (pos(&e["range"]["start"]), pos(&e["range"]["end"]), String::from(e["newText"].as_str().unwrap_or(""))))
+                .collect::<Vec<_>>();
+            (main, edits)
+        };
+        let main_start = main.0;
+        edits.push(main);
+        // from the bottom up, so that the edits don't move each other
+        edits.sort_by_key(|&(s, _, _)| (s.1, s.0));
+        let mut cursor = None;
+        for (start, end, new_text) in edits.into_iter().rev() {
+            buf.delete_text(start, end);
+            let end = buf.insert_text(start, &new_text);
+            if start == main_start && cursor.is_none() {
+                cursor = Some((end.0, buf.add_line_anchor(end.1)));
+            }
+        }
+        if let Some((col, anchor)) = cursor {
+            if let Some(line) = buf.line_anchor(anchor) { buf.place_cursor(col, line); }
+            buf.remove_line_anchor(anchor);
+        }
+        self.close();
+    }
+
+    /// stop waiting for the server
+    pub fn close(self) {
+        self.response.cancel();
+        if let Some((_, r)) = self.resolving { r.cancel(); }
+    }
+
+    /// draw the menu below the cursor, with the selected item's documentation beside it
+    pub fn paint(&self, rx: &mut RenderContext, font: &Font, cursor: Rect) {
+        if self.matches.len() == 0 { return; }
+        let first = if self.selected >= MENU_ROWS { self.selected + 1 - MENU_ROWS } else { 0 };
+        let rows = self.matches.iter().enumerate().skip(first).take(MENU_ROWS).filter_map(|(n, &i)| {
+            let item = &self.items[i];
+            let label = item["label"].as_str().unwrap_or("");
+            let text = match item["detail"].as_str() {
+                Some(d) => format!("{}  {}", label, d.lines().next().unwrap_or("")),
+                None => String::from(label)
+            };
+            rx.new_text_layout(&text, font, 600.0, 100.0).ok().map(|l| (n, l))
+        }).collect::<Vec<_>>();
+        let w = rows.iter().map(|&(_, ref l)| l.bounds().w).fold(100.0, f32::max) + 8.0;
+        let h = rows.iter().map(|&(_, ref l)| l.bounds().h).sum::<f32>();
+        let (x, mut y) = (cursor.x, cursor.y + cursor.h);
+        rx.set_color(Color::rgb(0.18, 0.18, 0.2));
+        rx.fill_rect(Rect::xywh(x, y, w, h));
+        for (n, l) in rows {
+            let lh = l.bounds().h;
+            if n == self.selected {
+                rx.set_color(Color::rgb(0.3, 0.4, 0.6));
+                rx.fill_rect(Rect::xywh(x, y, w, lh));
+            }
+            rx.set_color(Color::rgb(0.9, 0.9, 0.9));
+            rx.draw_text_layout(Point::xy(x + 4.0, y), &l);
+            y += lh;
+        }
+
+        let doc = self.matches.get(self.selected).and_then(|&i| doc_text(&self.items[i]["documentation"]));
+        if let Some(doc) = doc {
+            let doc = doc.lines().take(MENU_ROWS*2).collect::<Vec<_>>().join("\n");
+            if let Ok(l) = rx.new_text_layout(&doc, font, 400.0, 400.0) {
+                popup::draw_text_panel(rx, &l, Point::xy(x + w, cursor.y + cursor.h), Color::rgb(0.8, 0.8, 0.8));
+            }
+        }
+    }
+}
@@ -0,0 +1,395 @@
+// ex command line grammar: the ranges that can prefix a command, and the :s command
+//
+// range: % | address | address,address | address;address
+// address: (N | . | $ | '< | '> | 'm | /pattern/ | ?pattern?) followed by any number of +N / -N, where
+// a bare + or - means 1 and an offset on its own is relative to the current line. 'm is the line
+// of a mark set with m
+//
+// :s/pattern/replacement/flags takes any punctuation as the delimiter. In the replacement & and \0
+// are the whole match, \1 to \9 are capture groups, and \n or \r break the line. Flags:
+// g: every match on a line, not just the first
+// i, I: ignore case / match case
+// c: confirm each replacement
+
+use std::error::Error;
+use regex::{Regex, RegexBuilder};
+use buffer::Buffer;
+use mode::CommandError;
+
+#[derive(Debug, Clone)]
+pub enum Address {
+    Current,
+    Last,
+    Line(usize), // as typed, so starting from 1
+    SelectionStart,
+    SelectionEnd,
+    Mark(char),
+    Search(String, bool /*forward*/),
+    Offset(Box<Address>, isize)
+}
+
+#[derive(Debug, Clone)]
+pub enum Range {
+    Whole,
+    Single(Address),
+    // with ';' the cursor moves to the first address before the second one is resolved
+    Span(Address, Address, bool /*';'*/)
+}
+
+fn invalid(desc: &'static str) -> Box<Error> {
+    Box::new(CommandError::InvalidCommand(Some(desc)))
+}
+
+/// split off the text up to an unescaped `delim`. Escaped delimiters lose their backslash, any other
+/// escapes are kept for the regex or replacement parser. Returns the text and what follows the
+/// delimiter
+pub fn split_delimited(s: &str, delim: char) -> (String, &str) {
+    let mut out = String::new();
+    let mut cs = s.char_indices();
+    while let Some((i, c)) = cs.next() {
+        if c == delim {
+            return (out, &s[i+c.len_utf8()..]);
+        } else if c == '\\' {
+            match cs.next() {
+                Some((_, e)) if e == delim => out.push(e),
+                Some((_, e)) => { out.push('\\'); out.push(e); },
+                None => out.push('\\')
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    (out, &s[s.len()..])
+}
+
+fn parse_number(s: &str) -> (Option<usize>, &str) {
+    let end = s.find(|c: char| !c.is_digit(10)).unwrap_or(s.len());
+    (s[..end].parse::<usize>().ok(), &s[end..])
+}
+
+impl Address {
+    pub fn parse(s: &str) -> Result<(Option<Address>, &str), Box<Error>> {
+        let (mut addr, mut rest) = match s.chars().next() {
+            Some('.') => (Some(Address::Current), &s[1..]),
+            Some('$') => (Some(Address::Last), &s[1..]),
+            Some('\'') => match s[1..].chars().next() {
+                Some('<') => (Some(Address::SelectionStart), &s[2..]),
+                Some('>') => (Some(Address::SelectionEnd), &s[2..]),
+                Some(m) if m.is_ascii_alphabetic() => (Some(Address::Mark(m)), &s[2..]),
+                _ => return Err(invalid("unknown mark"))
+            },
+            Some(d) if d == '/' || d == '?' => {
+                let (pat, rest) = split_delimited(&s[1..], d);
+                (Some(Address::Search(pat, d == '/')), rest)
+            },
+            Some(c) if c.is_digit(10) => {
+                let (n, rest) = parse_number(s);
+                (Some(Address::Line(n.ok_or(invalid("line number too large"))?)), rest)
+            },
+            _ => (None, s)
+        };
+        loop {
+            let sign = match rest.chars().next() {
+                Some('+') => 1,
+                Some('-') => -1,
+                _ => break
+            };
+            let (n, r) = parse_number(&rest[1..]);
+            rest = r;
+            let base = addr.take().unwrap_or(Address::Current);
+            addr = Some(Address::Offset(Box::new(base), sign * n.unwrap_or(1) as isize));
+        }
+        Ok((addr, rest))
+    }
+
+    /// the (0-based) line this address refers to, with the cursor on `cur`
+    pub fn resolve(&self, buf: &Buffer, cur: usize, last_search: Option<&String>) -> Result<usize, Box<Error>> {
+        let count = buf.line_count();
+        match self {
+            &Address::Current => Ok(cur),
+            &Address::Last => Ok(count-1),
+            &Address::Line(n) => if n <= count { Ok(n.saturating_sub(1)) } else { Err(invalid("invalid range")) },
+            &Address::SelectionStart => buf.last_selection.map(|s| s.start.1).ok_or(invalid("no previous selection")),
+            &Address::SelectionEnd => buf.last_selection.map(|s| s.end.1).ok_or(invalid("no previous selection")),
+            &Address::Mark(m) => buf.mark(m).map(|loc| loc.1).ok_or(invalid("mark not set")),
+            &Address::Search(ref pat, forward) => {
+                let pat = if pat.len() > 0 { pat } else { last_search.ok_or(invalid("no previous search"))? };
+                let re = Regex::new(pat)?;
+                // the search starts on the line after (or before) the current one
+                let from = if forward { (buf.line_len(cur), cur) } else { (0, cur) };
+                buf.search(&re, from, forward).map(|loc| loc.1).ok_or(invalid("pattern not found"))
+            },
+            &Address::Offset(ref base, off) => {
+                let line = base.resolve(buf, cur, last_search)? as isize + off;
+                if line < 0 || line >= count as isize { Err(invalid("invalid range")) } else { Ok(line as usize) }
+            }
+        }
+    }
+}
+
+impl Range {
+    /// parse the range at the start of a command, returning it and the rest of the command
+    pub fn parse(s: &str) -> Result<(Option<Range>, &str), Box<Error>> {
+        let s = s.trim_left();
+        if s.starts_with('%') {
+            return Ok((Some(Range::Whole), &s[1..]));
+        }
+        let (first, rest) = Address::parse(s)?;
+        match rest.chars().next() {
+            Some(sep) if sep == ',' || sep == ';' => {
+                let (second, rest) = Address::parse(&rest[1..])?;
+                // a missing address on either side means the current line
+                let first = first.unwrap_or(Address::Current);
+                let second = second.unwrap_or(Address::Current);
+                Ok((Some(Range::Span(first, second, sep == ';')), rest))
+            },
+            _ => Ok((first.map(Range::Single), rest))
+        }
+    }
+
+    /// the first and last lines of the range, inclusive and 0-based
+    pub fn resolve(&self, buf: &Buffer, last_search: Option<&String>) -> Result<(usize, usize), Box<Error>> {
+        let cur = buf.curr_loc().1;
+        match self {
+            &Range::Whole => Ok((0, buf.line_count()-1)),
+            &Range::Single(ref a) => { let l = a.resolve(buf, cur, last_search)?; Ok((l, l)) },
+            &Range::Span(ref a, ref b, move_cursor) => {
+                let first = a.resolve(buf, cur, last_search)?;
+                let last = b.resolve(buf, if move_cursor { first } else { cur }, last_search)?;
+                Ok(if first <= last { (first, last) } else { (last, first) })
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum ReplacePart {
+    Text(String),
+    Group(usize)
+}
+
+#[derive(Debug, Clone)]
+pub struct Substitute {
+    pub re: Regex,
+    pub pattern: String,
+    pub replacement: String, // as typed, for showing to the user
+    parts: Vec<ReplacePart>,
+    pub global: bool,
+    pub confirm: bool
+}
+
+impl Substitute {
+    /// parse the part of a :s command after the s. An empty pattern means the last search
+    pub fn parse(s: &str, last_search: Option<&String>) -> Result<Substitute, Box<Error>> {
+        let delim = match s.chars().next() {
+            Some(d) if !d.is_alphanumeric() && !d.is_whitespace() && d != '\\' && d != '"' => d,
+            _ => return Err(invalid("invalid delimiter"))
+        };
+        let (pattern, rest) = split_delimited(&s[delim.len_utf8()..], delim);
+        let (replacement, flags) = split_delimited(rest, delim);
+        let pattern = if pattern.len() > 0 { pattern } else {
+            last_search.cloned().ok_or(invalid("no previous search"))?
+        };
+
+        let (mut global, mut confirm, mut ignore_case) = (false, false, false);
+        for f in flags.trim().chars() {
+            match f {
+                'g' => global = true,
+                'c' => confirm = true,
+                'i' => ignore_case = true,
+                'I' => ignore_case = false,
+                _ => return Err(invalid("invalid flag"))
+            }
+        }
+        let re = RegexBuilder::new(&pattern).case_insensitive(ignore_case).build()?;
+
+        fn push_group(parts: &mut Vec<ReplacePart>, text: &mut String, n: usize) {
+            if text.len() > 0 { parts.push(ReplacePart::Text(text.clone())); text.clear(); }
+            parts.push(ReplacePart::Group(n));
+        }
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut cs = replacement.chars();
+        while let Some(c) = cs.next() {
+            match c {
+                '&' => push_group(&mut parts, &mut text, 0),
+                '\\' => match cs.next() {
+                    Some(d) if d.is_digit(10) => push_group(&mut parts, &mut text, d as usize - '0' as usize),
+                    Some('n') | Some('r') => text.push('\n'),
+                    Some('t') => text.push('\t'),
+                    Some(e) => text.push(e),
+                    None => text.push('\\')
+                },
+                c => text.push(c)
+            }
+        }
+        if text.len() > 0 { parts.push(ReplacePart::Text(text)); }
+
+        Ok(Substitute { re, pattern, replacement, parts, global, confirm })
+    }
+
+    /// the start of the next match at or after `from`, up to the end of line `last`
+    pub fn next_match(&self, buf: &Buffer, from: (usize, usize), last: usize) -> Option<(usize, usize)> {
+        for line in from.1..(last+1).min(buf.line_count()) {
+            let text = buf.line(line);
+            let col = if line == from.1 { from.0 } else { 0 };
+            if col > text.len() { continue; }
+            if let Some(m) = self.re.find_at(&text, col) {
+                return Some((m.start(), line));
+            }
+        }
+        None
+    }
+
+    // where to look for the next match after one at `at` ending at `end`
+    fn resume(&self, buf: &Buffer, at: (usize, usize), end: (usize, usize)) -> (usize, usize) {
+        if !self.global {
+            (0, end.1 + 1)
+        } else if at == end {
+            // step over a character so an empty match doesn't repeat forever
+            let step = buf.line(end.1)[end.0..].chars().next().map(|c| c.len_utf8()).unwrap_or(1);
+            (end.0 + step, end.1)
+        } else { end }
+    }
+
+    /// leave the match at `at` alone, returning where to continue
+    pub fn skip(&self, buf: &Buffer, at: (usize, usize)) -> (usize, usize) {
+        let text = buf.line(at.1);
+        let end = self.re.find_at(&text, at.0).map(|m| m.end()).unwrap_or(at.0);
+        self.resume(buf, at, (end, at.1))
+    }
+
+    /// replace the match at `at`, returning where to continue. Line breaks in the replacement move
+    /// `last` down so it still refers to the same line
+    pub fn replace(&self, buf: &mut Buffer, at: (usize, usize), last: &mut usize) -> (usize, usize) {
+        let text = buf.line(at.1);
+        let mut locs = self.re.locations();
+        let (start, end) = match self.re.read_captures_at(&mut locs, &text, at.0) {
+            Some(m) => (m.start(), m.end()),
+            None => return (0, at.1 + 1)
+        };
+        let mut new = String::new();
+        for p in self.parts.iter() {
+            match p {
+                &ReplacePart::Text(ref t) => new.push_str(t),
+                &ReplacePart::Group(n) => if let Some((s, e)) = locs.pos(n) { new.push_str(&text[s..e]); }
+            }
+        }
+        buf.delete_text((start, at.1), (end, at.1));
+        let after = buf.insert_text((start, at.1), &new);
+        *last += after.1 - at.1;
+        if start == end {
+            // an empty match right after the replacement would be the same match again
+            self.resume(buf, after, after)
+        } else {
+            self.resume(buf, (start, at.1), after)
+        }
+    }
+
+    /// replace every match from `from` to the end of line `last`, returning how many there were and
+    /// the line of the last one
+    pub fn replace_all(&self, buf: &mut Buffer, from: (usize, usize), mut last: usize) -> (usize, Option<usize>) {
+        let mut count = 0;
+        let mut last_line = None;
+        let mut pos = from;
+        while let Some(at) = self.next_match(buf, pos, last) {
+            pos = self.replace(buf, at, &mut last);
+            count += 1;
+            last_line = Some(at.1);
+        }
+        (count, last_line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // "a", "foo", "b", "c", "foo", "d" with the cursor on "b"
+    fn buffer() -> Buffer {
+        let mut b = Buffer::with_text("a\nfoo\nb\nc\nfoo\nd");
+        b.place_cursor(0, 2);
+        b
+    }
+
+    fn lines(range: &str, buf: &Buffer) -> Result<(usize, usize), String> {
+        let (r, rest) = Range::parse(range).map_err(|e| format!("{}", e))?;
+        assert_eq!(rest, "d");
+        r.expect("a range").resolve(buf, None).map_err(|e| format!("{}", e))
+    }
+
+    #[test]
+    fn ranges() {
+        let b = buffer();
+        assert_eq!(lines("%d", &b), Ok((0, 5)));
+        assert_eq!(lines(".d", &b), Ok((2, 2)));
+        assert_eq!(lines("$d", &b), Ok((5, 5)));
+        assert_eq!(lines("2,4d", &b), Ok((1, 3)));
+        // backwards ranges are turned around
+        assert_eq!(lines("4,2d", &b), Ok((1, 3)));
+        assert_eq!(lines(",$d", &b), Ok((2, 5)));
+        assert_eq!(lines("7d", &b), Err(String::from("invalid range")));
+        assert!(Range::parse("99999999999999999999999d").is_err());
+        assert!(Range::parse("d").unwrap().0.is_none());
+    }
+
+    #[test]
+    fn offsets() {
+        let b = buffer();
+        assert_eq!(lines("+2d", &b), Ok((4, 4)));
+        assert_eq!(lines("-d", &b), Ok((1, 1)));
+        assert_eq!(lines(".+1,$-1d", &b), Ok((3, 4)));
+        assert_eq!(lines("1++d", &b), Ok((2, 2)));
+        assert_eq!(lines("$+1d", &b), Err(String::from("invalid range")));
+        // with ; the second address is from the first
+        assert_eq!(lines("1,+1d", &b), Ok((0, 3)));
+        assert_eq!(lines("1;+1d", &b), Ok((0, 1)));
+    }
+
+    #[test]
+    fn searches_and_marks() {
+        let mut b = buffer();
+        assert_eq!(lines("/foo/d", &b), Ok((4, 4)));
+        assert_eq!(lines("?foo?d", &b), Ok((1, 1)));
+        assert_eq!(lines("/foo/+1d", &b), Ok((5, 5)));
+        assert_eq!(lines("/nope/d", &b), Err(String::from("pattern not found")));
+        assert_eq!(lines("'<,'>d", &b), Err(String::from("no previous selection")));
+        b.last_selection = Some(::buffer::Selection { kind: ::buffer::SelectionKind::Line, start: (0, 1), end: (2, 3) });
+        assert_eq!(lines("'<,'>d", &b), Ok((1, 3)));
+        assert!(Range::parse("'1d").is_err());
+    }
+
+    #[test]
+    fn delimiters() {
+        assert_eq!(split_delimited("a\\/b/rest", '/'), (String::from("a/b"), "rest"));
+        // other escapes are left for the regex
+        assert_eq!(split_delimited("a\\.b#", '#'), (String::from("a\\.b"), ""));
+        assert_eq!(split_delimited("no end", '/'), (String::from("no end"), ""));
+    }
+
+    // run :s over the whole of `text`
+    fn substitute(text: &str, args: &str) -> String {
+        let mut b = Buffer::with_text(text);
+        let sub = Substitute::parse(args, None).unwrap();
+        let last = b.line_count() - 1;
+        sub.replace_all(&mut b, (0, 0), last);
+        b.full_text()
+    }
+
+    #[test]
+    fn substitutions() {
+        assert_eq!(substitute("ab ab\nab", "/(a)(b)/\\2\\1[&]/"), "ba[ab] ab\nba[ab]\n");
+        assert_eq!(substitute("ab ab", "/(a)(b)/\\2\\1[&]/g"), "ba[ab] ba[ab]\n");
+        assert_eq!(substitute("aA", "/a/x/gi"), "xx\n");
+        assert_eq!(substitute("aA", "/a/x/giI"), "xA\n");
+        assert_eq!(substitute("a/b", "#a/b#c\\n\\td#"), "c\n\td\n");
+        assert_eq!(substitute("a/b", "/a\\/b/\\//"), "/\n");
+        let s = Substitute::parse("/x/y/c", None).unwrap();
+        assert!(s.confirm && !s.global);
+        // an empty pattern is the last search
+        assert_eq!(Substitute::parse("//y/", Some(&String::from("x"))).unwrap().pattern, "x");
+        assert!(Substitute::parse("//y/", None).is_err());
+        assert!(Substitute::parse("/x/y/z", None).is_err());
+        assert!(Substitute::parse("axaya", None).is_err());
+    }
+}
@@ -0,0 +1,409 @@
+// syntax highlighting: the spans of each line that get their own color. Files that a language
+// server has sent semantic tokens for use those, over the top of a simple built-in grammar picked
+// by file extension, so files without a language server still get colors. The grammar's state
+// at the start of each line (inside a block comment or string or not) is worked out lazily, from
+// the top of the file down to the lines that are painted
+
+use std::collections::HashMap;
+use std::path::Path;
+use runic::Color;
+use json::JsonValue;
+
+use rope::Rope;
+use lsp;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Style {
+    Keyword, Type, Function, Macro, Variable, Constant, String, Number, Comment, Operator
+}
+
+impl Style {
+    pub fn color(&self) -> Color {
+        match self {
+            &Style::Keyword => Color::rgb(0.8, 0.5, 0.2),
+            &Style::Type => Color::rgb(0.4, 0.7, 0.7),
+            &Style::Function => Color::rgb(0.6, 0.7, 0.9),
+            &Style::Macro => Color::rgb(0.7, 0.5, 0.8),
+            &Style::Variable => Color::rgb(0.85, 0.85, 0.8),
+            &Style::Constant => Color::rgb(0.8, 0.6, 0.7),
+            &Style::String => Color::rgb(0.6, 0.75, 0.4),
+            &Style::Number => Color::rgb(0.8, 0.6, 0.7),
+            &Style::Comment => Color::rgb(0.5, 0.5, 0.45),
+            &Style::Operator => Color::rgb(0.75, 0.75, 0.7)
+        }
+    }
+
+    /// the style for an LSP semantic token type
+    pub fn from_token_type(t: &str) -> Option<Style> {
+        Some(match t {
+            "keyword" | "modifier" => Style::Keyword,
+            "namespace" | "type" | "class" | "enum" | "interface" | "struct" | "typeParameter" => Style::Type,
+            "function" | "method" => Style::Function,
+            "macro" | "decorator" => Style::Macro,
+            "variable" | "parameter" | "property" => Style::Variable,
+            "enumMember" => Style::Constant,
+            "string" | "regexp" => Style::String,
+            "number" => Style::Number,
+            "comment" => Style::Comment,
+            "operator" => Style::Operator,
+            _ => return None
+        })
+    }
+}
+
+/// the token types the editor has a style for, to tell the server in the client capabilities
+pub const TOKEN_TYPES: &[&str] = &["keyword", "modifier", "namespace", "type", "class", "enum", "interface",
+    "struct", "typeParameter", "function", "method", "macro", "decorator", "variable", "parameter",
+    "property", "enumMember", "string", "regexp", "number", "comment", "operator"];
+
+/// some bytes of a line, start..end, and how they look
+#[derive(Debug, Copy, Clone)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub style: Style
+}
+
+// a language, as far as the built-in highlighter understands one
+struct Grammar {
+    extensions: &'static [&'static str],
+    line_comment: Option<&'static str>,
+    block_comment: Option<(&'static str, &'static str)>,
+    // quotes that start strings, which can go over several lines
+    quotes: &'static [char],
+    // the quote for character literals, which is only taken as one if it closes just after
+    char_quote: Option<char>,
+    keywords: &'static [&'static str],
+    types: &'static [&'static str],
+    constants: &'static [&'static str],
+    // identifiers followed by ! are macros
+    bang_macros: bool,
+    // identifiers that start with a capital letter are types
+    capitalized_types: bool
+}
+
+const GRAMMARS: &[Grammar] = &[
+    Grammar {
+        extensions: &["rs"],
+        line_comment: Some("//"), block_comment: Some(("/*", "*/")),
+        quotes: &['"'], char_quote: Some('\''),
+        keywords: &["as", "break", "const", "continue", "crate", "else", "enum", "extern", "fn", "for", "if",
+            "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref", "return", "self",
+            "Self", "static", "struct", "super", "trait", "type", "unsafe", "use", "where", "while",
+            "dyn", "async", "await", "box"],
+        types: &["bool", "char", "str", "u8", "u16", "u32", "u64", "u128", "usize", "i8", "i16", "i32",
+            "i64", "i128", "isize", "f32", "f64"],
+        constants: &["true", "false", "None", "Some", "Ok", "Err"],
+        bang_macros: true, capitalized_types: true
+    },
+    Grammar {
+        extensions: &["c", "h", "cpp", "cc", "cxx", "hpp", "hh"],
+        line_comment: Some("//"), block_comment: Some(("/*", "*/")),
+        quotes: &['"'], char_quote: Some('\''),
+        keywords: &["auto", "break", "case", "class", "const", "constexpr", "continue", "default", "delete",
+            "do", "else", "enum", "extern", "for", "goto", "if", "inline", "namespace", "new", "operator",
+            "private", "protected", "public", "return", "sizeof", "static", "struct", "switch", "template",
+            "this", "typedef", "typename", "union", "using", "virtual", "volatile", "while", "#include",
+            "#define", "#if", "#ifdef", "#ifndef", "#else", "#endif", "#pragma"],
+        types: &["void", "bool", "char", "short", "int", "long", "float", "double", "signed", "unsigned",
+            "size_t", "uint8_t", "uint16_t", "uint32_t", "uint64_t", "int8_t", "int16_t", "int32_t", "int64_t"],
+        constants: &["true", "false", "NULL", "nullptr"],
+        bang_macros: false, capitalized_types: false
+    },
+    Grammar {
+        extensions: &["py"],
+        line_comment: Some("#"), block_comment: None,
+        quotes: &['"', '\''], char_quote: None,
+        keywords: &["and", "as", "assert", "async", "await", "break", "class", "continue", "def", "del", "elif",
+            "else", "except", "finally", "for", "from", "global", "if", "import", "in", "is", "lambda",
+            "nonlocal", "not", "or", "pass", "raise", "return", "try", "while", "with", "yield", "self"],
+        types: &["int", "float", "str", "bool", "list", "dict", "set", "tuple", "bytes", "object"],
+        constants: &["True", "False", "None"],
+        bang_macros: false, capitalized_types: true
+    },
+    Grammar {
+        extensions: &["js", "ts", "jsx", "tsx"],
+        line_comment: Some("//"), block_comment: Some(("/*", "*/")),
+        quotes: &['"', '\'', '`'], char_quote: None,
+        keywords: &["async", "await", "break", "case", "catch", "class", "const", "continue", "default",
+            "delete", "do", "else", "export", "extends", "finally", "for", "function", "if", "import", "in",
+            "instanceof", "interface", "let", "new", "of", "return", "static", "switch", "this", "throw",
+            "try", "type", "typeof", "var", "void", "while", "yield"],
+        types: &["number", "string", "boolean", "any", "unknown", "never", "object"],
+        constants: &["true", "false", "null", "undefined"],
+        bang_macros: false, capitalized_types: true
+    },
+    Grammar {
+        extensions: &["toml"],
+        line_comment: Some("#"), block_comment: None,
+        quotes: &['"', '\''], char_quote: None,
+        keywords: &[], types: &[],
+        constants: &["true", "false"],
+        bang_macros: false, capitalized_types: false
+    }
+];
+
+// where a line starts, as far as the grammar is concerned
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum LineState {
+    Code,
+    BlockComment,
+    String(char)
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+impl Grammar {
+    fn for_path(path: &Path) -> Option<&'static Grammar> {
+        let ext = path.extension().and_then(|e| e.to_str())?;
+        GRAMMARS.iter().find(|g| g.extensions.contains(&ext))
+    }
+
+    // the byte just past the closing quote of a string that starts at `from`, or None if it
+    // carries on to the next line
+    fn string_end(line: &str, from: usize, q: char) -> Option<usize> {
+        let mut escaped = false;
+        for (i, c) in line[from..].char_indices() {
+            if escaped { escaped = false; }
+            else if c == '\\' { escaped = true; }
+            else if c == q { return Some(from + i + c.len_utf8()); }
+        }
+        None
+    }
+
+    // the spans of one line, starting in `state`, and the state the next line starts in
+    fn highlight(&self, line: &str, mut state: LineState) -> (Vec<Span>, LineState) {
+        let mut spans = Vec::new();
+        let mut i = 0;
+        while i < line.len() {
+            match state {
+                LineState::BlockComment => {
+                    let close = self.block_comment.unwrap().1;
+                    match line[i..].find(close) {
+                        Some(e) => {
+                            spans.push(Span { start: i, end: i + e + close.len(), style: Style::Comment });
+                            i += e + close.len();
+                            state = LineState::Code;
+                        },
+                        None => {
+                            spans.push(Span { start: i, end: line.len(), style: Style::Comment });
+                            i = line.len();
+                        }
+                    }
+                    continue;
+                },
+                LineState::String(q) => {
+                    match Grammar::string_end(line, i, q) {
+                        Some(e) => { spans.push(Span { start: i, end: e, style: Style::String }); i = e; state = LineState::Code; },
+                        None => { spans.push(Span { start: i, end: line.len(), style: Style::String }); i = line.len(); }
+                    }
+                    continue;
+                },
+                LineState::Code => {}
+            }
+            let rest = &line[i..];
+            let c = rest.chars().next().unwrap();
+            if self.line_comment.map_or(false, |lc| rest.starts_with(lc)) {
+                spans.push(Span { start: i, end: line.len(), style: Style::Comment });
+                break;
+            }
+            if let Some((open, _)) = self.block_comment {
+                if rest.starts_with(open) {
+                    state = LineState::BlockComment;
+                    spans.push(Span { start: i, end: i + open.len(), style: Style::Comment });
+                    i += open.len();
+                    continue;
+                }
+            }
+            if self.quotes.contains(&c) {
+                state = LineState::String(c);
+                spans.push(Span { start: i, end: i + 1, style: Style::String });
+                i += 1;
+                continue;
+            }
+            if Some(c) == self.char_quote {
+                // 'x' and '\n' are characters, anything else (like a Rust lifetime) isn't
+                let len = match rest[1..].chars().next() {
+                    Some('\\') => rest[2..].find(c).map(|e| e + 3),
+                    Some(x) if rest[1 + x.len_utf8()..].starts_with(c) => Some(2 + x.len_utf8()),
+                    _ => None
+                };
+                if let Some(len) = len {
+                    spans.push(Span { start: i, end: i + len, style: Style::String });
+                    i += len;
+                    continue;
+                }
+            }
+            if is_ident_char(c) || (c == '#' && self.keywords.iter().any(|k| k.starts_with('#'))) {
+                let len = rest[c.len_utf8()..].find(|c| !is_ident_char(c)).map_or(rest.len(), |e| e + c.len_utf8());
+                let word = &rest[..len];
+                let next = rest[len..].chars().next();
+                let style = if c.is_numeric() { Some(Style::Number) }
+                    else if self.keywords.contains(&word) { Some(Style::Keyword) }
+                    else if self.types.contains(&word) { Some(Style::Type) }
+                    else if self.constants.contains(&word) { Some(Style::Constant) }
+                    else if self.bang_macros && next == Some('!') { Some(Style::Macro) }
+                    else if next == Some('(') { Some(Style::Function) }
+                    else if self.capitalized_types && c.is_uppercase() { Some(Style::Type) }
+                    else { None };
+                if let Some(style) = style {
+                    spans.push(Span { start: i, end: i + len, style });
+                }
+                i += len;
+                continue;
+            }
+            i += c.len_utf8();
+        }
+        (spans, state)
+    }
+}
+
+pub struct Highlighter {
+    grammar: Option<&'static Grammar>,
+    // the grammar's state at the start of each line, for as many lines as have been highlighted
+    states: Vec<LineState>,
+    // spans from the language server's semantic tokens, by line
+    semantic: HashMap<usize, Vec<Span>>
+}
+
+impl Highlighter {
+    pub fn new(path: Option<&Path>) -> Highlighter {
+        Highlighter {
+            grammar: path.and_then(Grammar::for_path),
+            states: vec![LineState::Code],
+            semantic: HashMap::new()
+        }
+    }
+
+    // the state line `line` starts in, working it out from the last line that is known
+    fn state_at(&mut self, text: &Rope, line: usize) -> LineState {
+        let g = match self.grammar { Some(g) => g, None => return LineState::Code };
+        while self.states.len() <= line {
+            let ln = self.states.len() - 1;
+            let next = if ln < text.len_lines() { g.highlight(&text.line(ln), self.states[ln]).1 } else { LineState::Code };
+            self.states.push(next);
+        }
+        self.states[line]
+    }
+
+    /// the spans of a line, with the server's tokens on top of the grammar's
+    pub fn spans(&mut self, text: &Rope, line: usize) -> Vec<Span> {
+        let mut spans = match self.grammar {
+            Some(g) => {
+                let state = self.state_at(text, line);
+                g.highlight(&text.line(line), state).0
+            },
+            None => Vec::new()
+        };
+        if let Some(s) = self.semantic.get(&line) { spans.extend(s.iter().cloned()); }
+        spans
+    }
+
+    /// the lines from `first` to `old_last` have been replaced by `first` to `new_last`. Returns
+    /// true if that changes how the lines after them start, so they have to be highlighted again
+    pub fn edited(&mut self, text: &Rope, first: usize, old_last: usize, new_last: usize) -> bool {
+        let old_next = self.states.get(old_last + 1).cloned();
+        self.states.truncate(first + 1);
+        // the server's tokens for the changed lines are out of date, and the rest have moved
+        let delta = new_last as isize - old_last as isize;
+        let old = ::std::mem::replace(&mut self.semantic, HashMap::new());
+        for (ln, s) in old {
+            if ln < first { self.semantic.insert(ln, s); }
+            else if ln > old_last { self.semantic.insert((ln as isize + delta) as usize, s); }
+        }
+        match old_next {
+            Some(o) => self.state_at(text, new_last + 1) != o,
+            None => false
+        }
+    }
+
+    /// replace the semantic tokens with the `data` of a semanticTokens/full response, decoded with
+    /// the token types from the server's legend
+    pub fn set_semantic_tokens(&mut self, text: &Rope, data: &JsonValue, legend: &[Option<Style>]) {
+        self.semantic.clear();
+        let nums = data.members().map(|n| n.as_usize().unwrap_or(0)).collect::<Vec<_>>();
+        let (mut line, mut start) = (0, 0);
+        let mut line_text = (None, String::new());
+        for t in nums.chunks(5) {
+            if t.len() < 5 { break; }
+            // each token is relative to the one before
+            if t[0] > 0 { line += t[0]; start = 0; }
+            start += t[1];
+            if line >= text.len_lines() { break; }
+            if line_text.0 != Some(line) { line_text = (Some(line), text.line(line)); }
+            let line_text = &line_text.1;
+            let style = match legend.get(t[3]).and_then(|s| *s) { Some(s) => s, None => continue };
+            // positions are in UTF-16 code units
+            let s = lsp::utf16_to_byte_col(line_text, start);
+            let e = lsp::utf16_to_byte_col(line_text, start + t[2]);
+            self.semantic.entry(line).or_insert(Vec::new()).push(Span { start: s, end: e, style });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spans(h: &mut Highlighter, text: &Rope, line: usize) -> Vec<(usize, usize, Style)> {
+        h.spans(text, line).iter().map(|s| (s.start, s.end, s.style)).collect()
+    }
+
+    fn rust(text: &str) -> (Highlighter, Rope) {
+        (Highlighter::new(Some(Path::new("a.rs"))), Rope::from_str(text))
+    }
+
+    #[test]
+    fn block_comment_over_lines() {
+        let (mut h, text) = rust("let a = 1; /* one\ntwo\nthree */ let b");
+        assert_eq!(spans(&mut h, &text, 0), vec![(0, 3, Style::Keyword), (8, 9, Style::Number), (11, 13, Style::Comment), (13, 17, Style::Comment)]);
+        assert_eq!(spans(&mut h, &text, 1), vec![(0, 3, Style::Comment)]);
+        assert_eq!(spans(&mut h, &text, 2), vec![(0, 8, Style::Comment), (9, 12, Style::Keyword)]);
+    }
+
+    #[test]
+    fn string_over_lines() {
+        let (mut h, text) = rust("let s = \"one\ntwo \\\" still\nend\"; fn");
+        assert_eq!(spans(&mut h, &text, 0), vec![(0, 3, Style::Keyword), (8, 9, Style::String), (9, 12, Style::String)]);
+        // the escaped quote doesn't end it
+        assert_eq!(spans(&mut h, &text, 1), vec![(0, 12, Style::String)]);
+        assert_eq!(spans(&mut h, &text, 2), vec![(0, 4, Style::String), (6, 8, Style::Keyword)]);
+    }
+
+    #[test]
+    fn lifetimes_are_not_chars() {
+        let line = "fn f<'a>(x: &'a str) -> bool { x == '\\n' || x == 'é' || x == 'y' }";
+        let (mut h, text) = rust(line);
+        let strings = spans(&mut h, &text, 0).into_iter().filter(|s| s.2 == Style::String).collect::<Vec<_>>();
+        let at = |s: &str| line.find(s).unwrap();
+        assert_eq!(strings, vec![(at("'\\n'"), at("'\\n'") + 4, Style::String), (at("'é'"), at("'é'") + 4, Style::String),
+            (at("'y'"), at("'y'") + 3, Style::String)]);
+    }
+
+    #[test]
+    fn edited_sees_new_string() {
+        let (mut h, mut text) = rust("let a = 1;\nlet b = 2;\nlet c = 3;");
+        spans(&mut h, &text, 2);
+        text.insert(0, "x");
+        assert!(!h.edited(&text, 0, 0, 0));
+        spans(&mut h, &text, 2);
+        // an unclosed quote makes the next line start inside a string
+        text.insert(0, "\"");
+        assert!(h.edited(&text, 0, 0, 0));
+        assert_eq!(spans(&mut h, &text, 1), vec![(0, 10, Style::String)]);
+    }
+
+    #[test]
+    fn semantic_tokens_in_utf16() {
+        let mut h = Highlighter::new(None);
+        let text = Rope::from_str("let s = \"😀\"; x\nfoo bar");
+        // s, then x 10 code units on (the emoji is two), then foo with a type that isn't in the
+        // legend, then bar
+        let data = array![0, 4, 1, 0, 0, 0, 10, 1, 0, 0, 1, 0, 3, 5, 0, 0, 4, 3, 1, 0];
+        h.set_semantic_tokens(&text, &data, &[Some(Style::Variable), Some(Style::Function)]);
+        assert_eq!(spans(&mut h, &text, 0), vec![(4, 5, Style::Variable), (16, 17, Style::Variable)]);
+        assert_eq!(spans(&mut h, &text, 1), vec![(4, 7, Style::Function)]);
+    }
+}
@@ -0,0 +1,266 @@
+// JSON-RPC messages framed the way the language server protocol frames them: `Name: value`
+// header lines, each ended by \r\n, then a blank line, then Content-Length bytes of JSON. The
+// decoder takes bytes as they are read, split anywhere, and only turns them into text once a whole
+// message has arrived, so characters split between reads come out whole
+
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::str;
+use json::{self, JsonValue};
+
+// a header block that goes on longer than this without ending is not going to
+const MAX_HEADER_LEN: usize = 8 * 1024;
+// no server sends a message this big, so a header that says it will is taken to be garbage rather
+// than waited for
+const MAX_CONTENT_LEN: usize = 64 * 1024 * 1024;
+
+/// why a message couldn't be decoded. Decoding carries on after the message
+#[derive(Debug)]
+pub enum FrameError {
+    /// a header block that can't be read, or that doesn't say how long the content is
+    Header(String),
+    /// content that isn't UTF-8, or says it is in some other charset
+    Charset(String),
+    /// content that isn't JSON
+    Json(String)
+}
+
+impl Error for FrameError {
+    fn description(&self) -> &str {
+        match self {
+            &FrameError::Header(_) => "invalid message header",
+            &FrameError::Charset(_) => "message isn't UTF-8",
+            &FrameError::Json(_) => "message isn't JSON"
+        }
+    }
+}
+
+impl Display for FrameError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            &FrameError::Header(ref s) | &FrameError::Charset(ref s) | &FrameError::Json(ref s) =>
+                write!(f, "{}: {}", self.description(), s)
+        }
+    }
+}
+
+/// frame a message to be written
+pub fn encode(msg: &JsonValue) -> String {
+    let content = json::stringify(msg.clone());
+    format!("Content-Length: {}\r\n\r\n{}", content.len(), content)
+}
+
+/// takes in bytes as they are read and gives back the messages in them as they are completed
+pub struct Decoder {
+    // bytes that haven't been decoded yet
+    buf: Vec<u8>,
+    // once a header block has been read, how long the content after it is, and what is wrong
+    // with it if the header said so
+    content: Option<(usize, Option<FrameError>)>,
+    // after a header that can't be read, the bytes up to the next Content-Length are skipped
+    resync: bool
+}
+
+// where \r\n\r\n starts
+fn find_header_end(b: &[u8]) -> Option<usize> {
+    b.windows(4).position(|w| w == b"\r\n\r\n")
+}
+
+// the content length and charset problem from the lines of a header block
+fn parse_header(header: &[u8]) -> Result<(usize, Option<FrameError>), FrameError> {
+    let header = str::from_utf8(header).map_err(|_| FrameError::Header(String::from("not ASCII")))?;
+    let mut length = None;
+    let mut charset = None;
+    for line in header.split("\r\n") {
+        let colon = line.find(':').ok_or_else(|| FrameError::Header(format!("{:?}", line)))?;
+        let (name, value) = (line[..colon].trim(), line[colon+1..].trim());
+        if name.eq_ignore_ascii_case("Content-Length") {
+            let n = value.parse::<usize>().map_err(|_| FrameError::Header(format!("Content-Length {:?}", value)))?;
+            if n > MAX_CONTENT_LEN { return Err(FrameError::Header(format!("Content-Length {} is too long", n))); }
+            length = Some(n);
+        } else if name.eq_ignore_ascii_case("Content-Type") {
+            // utf8 is allowed as well, for old servers
+            for param in value.split(';').skip(1) {
+                let mut kv = param.splitn(2, '=');
+                let (k, v) = (kv.next().unwrap_or("").trim(), kv.next().unwrap_or("").trim().trim_matches('"'));
+                if k.eq_ignore_ascii_case("charset") && !(v.eq_ignore_ascii_case("utf-8") || v.eq_ignore_ascii_case("utf8")) {
+                    charset = Some(FrameError::Charset(format!("charset {}", v)));
+                }
+            }
+        }
+    }
+    match length {
+        Some(n) => Ok((n, charset)),
+        None => Err(FrameError::Header(String::from("no Content-Length")))
+    }
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder { buf: Vec::new(), content: None, resync: false }
+    }
+
+    /// add bytes that have been read
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+}
+
+impl Iterator for Decoder {
+    type Item = Result<JsonValue, FrameError>;
+
+    /// the next message, if all of it has arrived
+    fn next(&mut self) -> Option<Result<JsonValue, FrameError>> {
+        if self.resync {
+            let key = b"Content-Length";
+            match self.buf.windows(key.len()).position(|w| w == key) {
+                Some(i) => { self.buf.drain(..i); self.resync = false; },
+                None => {
+                    // the start of it may have arrived already
+                    let keep = self.buf.len().saturating_sub(key.len() - 1);
+                    self.buf.drain(..keep);
+                    return None;
+                }
+            }
+        }
+        if self.content.is_none() {
+            let end = match find_header_end(&self.buf) {
+                Some(e) => e,
+                None if self.buf.len() > MAX_HEADER_LEN => {
+                    self.buf.clear();
+                    self.resync = true;
+                    return Some(Err(FrameError::Header(String::from("too long"))));
+                },
+                None => return None
+            };
+            match parse_header(&self.buf[..end]) {
+                Ok(c) => {
+                    self.buf.drain(..end+4);
+                    self.content = Some(c);
+                },
+                // without a length there is no telling where the content ends. The header may
+                // only have had junk in front of it, which a resync goes past
+                Err(e) => {
+                    self.buf.drain(..1);
+                    self.resync = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+        let length = self.content.as_ref().map_or(0, |c| c.0);
+        if self.buf.len() < length { return None; }
+        let content = self.buf.drain(..length).collect::<Vec<_>>();
+        if let Some(e) = self.content.take().and_then(|c| c.1) {
+            return Some(Err(e));
+        }
+        let text = match str::from_utf8(&content) {
+            Ok(t) => t,
+            Err(e) => return Some(Err(FrameError::Charset(format!("{}", e))))
+        };
+        Some(json::parse(text).map_err(|e| FrameError::Json(format!("{}", e))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages() -> Vec<JsonValue> {
+        vec![
+            object!{ "jsonrpc" => "2.0", "id" => 1, "method" => "initialize", "params" => object!{} },
+            object!{ "jsonrpc" => "2.0", "method" => "window/logMessage", "params" => object!{ "message" => "héllo wörld ✓ 𝄞" } },
+            object!{ "jsonrpc" => "2.0", "id" => 2, "result" => array!["日本語", "", "\r\n\r\n"] }
+        ]
+    }
+
+    fn stream(msgs: &[JsonValue]) -> Vec<u8> {
+        msgs.iter().map(encode).collect::<String>().into_bytes()
+    }
+
+    fn decode_chunks(chunks: &[&[u8]]) -> Vec<Result<JsonValue, FrameError>> {
+        let mut d = Decoder::new();
+        let mut out = Vec::new();
+        for c in chunks {
+            d.push(c);
+            out.extend(&mut d);
+        }
+        out
+    }
+
+    fn decoded(results: Vec<Result<JsonValue, FrameError>>) -> Vec<JsonValue> {
+        results.into_iter().map(|r| r.expect("decode message")).collect()
+    }
+
+    #[test]
+    fn split_at_every_offset() {
+        let msgs = messages();
+        let bytes = stream(&msgs);
+        for i in 0..(bytes.len()+1) {
+            let (a, b) = bytes.split_at(i);
+            assert_eq!(decoded(decode_chunks(&[a, b])), msgs, "split at {}", i);
+        }
+    }
+
+    #[test]
+    fn byte_at_a_time() {
+        let msgs = messages();
+        let bytes = stream(&msgs);
+        let chunks = bytes.chunks(1).collect::<Vec<_>>();
+        assert_eq!(decoded(decode_chunks(&chunks)), msgs);
+    }
+
+    #[test]
+    fn split_inside_header_end() {
+        let msg = b"Content-Length: 2\r\n\r\n{}";
+        for i in 17..21 {
+            let (a, b) = msg.split_at(i);
+            assert_eq!(decoded(decode_chunks(&[a, b])), vec![object!{}]);
+        }
+    }
+
+    #[test]
+    fn lowercase_header() {
+        let r = decode_chunks(&[b"content-length: 2\r\ncontent-type: application/vscode-jsonrpc; charset=utf8\r\n\r\n{}"]);
+        assert_eq!(decoded(r), vec![object!{}]);
+    }
+
+    #[test]
+    fn other_charset() {
+        let mut bytes = b"Content-Length: 2\r\nContent-Type: application/vscode-jsonrpc; charset=latin1\r\n\r\n{}".to_vec();
+        bytes.extend(stream(&messages()[..1]));
+        let r = decode_chunks(&[&bytes]);
+        assert_eq!(r.len(), 2);
+        match r[0] { Err(FrameError::Charset(_)) => {}, ref r => panic!("expected a charset error, got {:?}", r) }
+        assert_eq!(r[1].as_ref().unwrap(), &messages()[0]);
+    }
+
+    #[test]
+    fn resync_past_junk() {
+        let mut bytes = b"junk\r\n\r\n".to_vec();
+        bytes.extend(stream(&messages()));
+        let r = decode_chunks(&[&bytes]);
+        assert!(r.len() > 0);
+        match r[0] { Err(FrameError::Header(_)) => {}, ref r => panic!("expected a header error, got {:?}", r) }
+        assert_eq!(decoded(r.into_iter().skip(1).collect()), messages());
+    }
+
+    #[test]
+    fn bad_content_length() {
+        for header in &["Content-Length: two\r\n\r\n", "Content-Length: 99999999999999\r\n\r\n"] {
+            let mut bytes = header.as_bytes().to_vec();
+            bytes.extend(stream(&messages()));
+            let r = decode_chunks(&[&bytes]);
+            match r[0] { Err(FrameError::Header(_)) => {}, ref r => panic!("expected a header error, got {:?}", r) }
+            assert_eq!(decoded(r.into_iter().skip(1).collect()), messages());
+        }
+    }
+
+    #[test]
+    fn invalid_json() {
+        let mut bytes = b"Content-Length: 5\r\n\r\n{nope".to_vec();
+        bytes.extend(stream(&messages()));
+        let r = decode_chunks(&[&bytes]);
+        match r[0] { Err(FrameError::Json(_)) => {}, ref r => panic!("expected a JSON error, got {:?}", r) }
+        assert_eq!(decoded(r.into_iter().skip(1).collect()), messages());
+    }
+}
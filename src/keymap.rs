@@ -0,0 +1,394 @@
+// keymaps: config.toml can bind key sequences in each mode to other keys, built-in actions and
+// motions, or ex commands. For example:
+//
+// [keymap]
+// leader = ","         # what <leader> stands for, \ by default
+// timeout = 1000       # ms to wait when typed keys could still become a longer mapping
+//
+// [keymap.normal]
+// "H" = "^"                            # plain strings are keys, which aren't remapped again
+// "<leader>w" = { command = "w" }
+// "<C-u>" = { action = "undo" }
+// "gh" = { motion = "line-start" }     # also works after an operator: dgh
+// "<leader>d" = { keys = "dd" }
+//
+// Maps exist for normal, visual, insert and command modes. Keys are written like vim's, with
+// <Esc>, <CR>, <Tab>, <BS>, <Del>, <Up>, <Down>, <Left>, <Right>, <Space>, <lt>, <C-x>, <C-Space> and <leader>
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::time::{Duration, Instant};
+use toml::Value;
+use winit::{WindowEvent, KeyboardInput, ElementState, VirtualKeyCode};
+
+use super::ConfigError;
+
+/// a key press, after winit's events have been boiled down to what the modes care about
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Key {
+    Char(char),
+    Ctrl(char), // always lowercase, or ' ' for Ctrl-Space
+    Escape, Return, Tab, Backspace, Delete,
+    Up, Down, Left, Right
+}
+
+fn letter(k: VirtualKeyCode) -> Option<char> {
+    use winit::VirtualKeyCode::*;
+    Some(match k {
+        A => 'a', B => 'b', C => 'c', D => 'd', E => 'e', F => 'f', G => 'g', H => 'h', I => 'i',
+        J => 'j', K => 'k', L => 'l', M => 'm', N => 'n', O => 'o', P => 'p', Q => 'q', R => 'r',
+        S => 's', T => 't', U => 'u', V => 'v', W => 'w', X => 'x', Y => 'y', Z => 'z',
+        _ => return None
+    })
+}
+
+impl Key {
+    /// the key for a window event, if it is one. Printable characters come from ReceivedCharacter,
+    /// everything else from KeyboardInput
+    pub fn from_event(e: &WindowEvent) -> Option<Key> {
+        match e {
+            &WindowEvent::ReceivedCharacter(c) => {
+                if c.is_control() || ((c as u32) >= 0xf700 && (c as u32) < 0xf7ff) { None } else { Some(Key::Char(c)) }
+            },
+            &WindowEvent::KeyboardInput { input: KeyboardInput { virtual_keycode: Some(k), state: ElementState::Pressed, modifiers, .. }, .. } => {
+                if modifiers.ctrl {
+                    if k == VirtualKeyCode::Space { return Some(Key::Ctrl(' ')); }
+                    return letter(k).map(Key::Ctrl);
+                }
+                match k {
+                    VirtualKeyCode::Escape => Some(Key::Escape),
+                    VirtualKeyCode::Return => Some(Key::Return),
+                    VirtualKeyCode::Tab => Some(Key::Tab),
+                    VirtualKeyCode::Back => Some(Key::Backspace),
+                    VirtualKeyCode::Delete => Some(Key::Delete),
+                    VirtualKeyCode::Up => Some(Key::Up),
+                    VirtualKeyCode::Down => Some(Key::Down),
+                    VirtualKeyCode::Left => Some(Key::Left),
+                    VirtualKeyCode::Right => Some(Key::Right),
+                    _ => None
+                }
+            },
+            _ => None
+        }
+    }
+
+    /// parse keys written like vim's, with `leader` standing in for <leader>
+    pub fn parse_seq(s: &str, leader: &[Key]) -> Result<Vec<Key>, Box<Error>> {
+        let mut keys = Vec::new();
+        let mut rest = s;
+        while let Some(c) = rest.chars().next() {
+            let name_end = if c == '<' { rest.find('>') } else { None };
+            match name_end {
+                Some(end) if end > 1 => {
+                    let name = rest[1..end].to_lowercase();
+                    match &name[..] {
+                        "esc" => keys.push(Key::Escape),
+                        "cr" | "enter" | "return" => keys.push(Key::Return),
+                        "tab" => keys.push(Key::Tab),
+                        "bs" => keys.push(Key::Backspace),
+                        "del" => keys.push(Key::Delete),
+                        "up" => keys.push(Key::Up),
+                        "down" => keys.push(Key::Down),
+                        "left" => keys.push(Key::Left),
+                        "right" => keys.push(Key::Right),
+                        "space" => keys.push(Key::Char(' ')),
+                        "lt" => keys.push(Key::Char('<')),
+                        "leader" => keys.extend_from_slice(leader),
+                        "c-space" => keys.push(Key::Ctrl(' ')),
+                        n if n.starts_with("c-") && n.chars().count() == 3 => keys.push(Key::Ctrl(n.chars().nth(2).unwrap())),
+                        _ => return Err(Box::new(ConfigError::Invalid("key name in keymap")))
+                    }
+                    rest = &rest[end+1..];
+                },
+                _ => {
+                    keys.push(Key::Char(c));
+                    rest = &rest[c.len_utf8()..];
+                }
+            }
+        }
+        Ok(keys)
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Key::Char('<') => write!(f, "<lt>"),
+            &Key::Char(c) => write!(f, "{}", c),
+            &Key::Ctrl(' ') => write!(f, "<C-Space>"),
+            &Key::Ctrl(c) => write!(f, "<C-{}>", c),
+            &Key::Escape => write!(f, "<Esc>"),
+            &Key::Return => write!(f, "<CR>"),
+            &Key::Tab => write!(f, "<Tab>"),
+            &Key::Backspace => write!(f, "<BS>"),
+            &Key::Delete => write!(f, "<Del>"),
+            &Key::Up => write!(f, "<Up>"),
+            &Key::Down => write!(f, "<Down>"),
+            &Key::Left => write!(f, "<Left>"),
+            &Key::Right => write!(f, "<Right>")
+        }
+    }
+}
+
+// the default keys for each built-in action and motion that can be named in a keymap
+fn builtin(name: &str) -> Option<&'static str> {
+    Some(match name {
+        // actions
+        "undo" => "u",
+        "redo" => "<C-r>",
+        "insert" => "i",
+        "append" => "a",
+        "open-line" => "o",
+        "command" => ":",
+        "put" => "p",
+        "put-before" => "P",
+        "delete" => "d",
+        "change" => "c",
+        "yank" => "y",
+        "delete-char" => "x",
+        "repeat" => ".",
+        "visual" => "v",
+        "visual-line" => "V",
+        "visual-block" => "<C-v>",
+        "jump-back" => "<C-o>",
+        "jump-forward" => "<C-i>",
+        "search-forward" => "/",
+        "search-backward" => "?",
+        "escape" => "<Esc>",
+        "newline" => "<CR>",
+        "backspace" => "<BS>",
+        "complete" => "<C-Space>",
+        "goto-definition" => "gd",
+        "goto-declaration" => "gD",
+        "references" => "gr",
+        "hover" => "K",
+        // motions
+        "left" => "h",
+        "right" => "l",
+        "up" => "k",
+        "down" => "j",
+        "word-forward" => "w",
+        "word-backward" => "b",
+        "word-end" => "e",
+        "line-start" => "^",
+        "line-end" => "$",
+        "next-diagnostic" => "]d",
+        "prev-diagnostic" => "[d",
+        "search-next" => "n",
+        "search-prev" => "N",
+        _ => return None
+    })
+}
+
+#[derive(Debug, Clone)]
+pub enum Binding {
+    /// typed into the mode as-is, without being remapped
+    Keys(Vec<Key>),
+    /// run as an ex command
+    Command(String)
+}
+
+/// what typed keys turn into once they have been looked up in the keymap
+#[derive(Debug, Clone)]
+pub enum Resolved {
+    Key(Key),
+    Command(String)
+}
+
+pub struct Keymap {
+    maps: HashMap<String, HashMap<Vec<Key>, Binding>>,
+    timeout: Duration,
+    pending: Vec<Key>,
+    pending_since: Instant
+}
+
+impl Keymap {
+    pub fn new() -> Keymap {
+        Keymap { maps: HashMap::new(), timeout: Duration::from_millis(1000), pending: Vec::new(), pending_since: Instant::now() }
+    }
+
+    pub fn from_config(config: Option<&Value>) -> Result<Keymap, Box<Error>> {
+        let mut km = Keymap::new();
+        let cfg = match config.and_then(|c| c.get("keymap")) {
+            Some(c) => c,
+            None => return Ok(km)
+        };
+        let leader = match cfg.get("leader") {
+            Some(l) => Key::parse_seq(l.as_str().ok_or(ConfigError::Invalid("keymap leader"))?, &[])?,
+            None => vec![Key::Char('\\')]
+        };
+        if let Some(t) = cfg.get("timeout") {
+            km.timeout = Duration::from_millis(t.as_integer().ok_or(ConfigError::Invalid("keymap timeout"))? as u64);
+        }
+        for mode in ["normal", "visual", "insert", "command"].iter() {
+            let table = match cfg.get(mode).and_then(Value::as_table) {
+                Some(t) => t,
+                None => continue
+            };
+            let mut map = HashMap::new();
+            for (keys, binding) in table.iter() {
+                let keys = Key::parse_seq(keys, &leader)?;
+                if keys.len() == 0 { return Err(Box::new(ConfigError::Invalid("empty key sequence in keymap"))); }
+                let binding = match binding {
+                    &Value::String(ref s) => Binding::Keys(Key::parse_seq(s, &leader)?),
+                    &Value::Table(ref t) => {
+                        if let Some(c) = t.get("command").and_then(Value::as_str) {
+                            Binding::Command(String::from(c))
+                        } else if let Some(k) = t.get("keys").and_then(Value::as_str) {
+                            Binding::Keys(Key::parse_seq(k, &leader)?)
+                        } else if let Some(name) = t.get("action").or(t.get("motion")).and_then(Value::as_str) {
+                            Binding::Keys(Key::parse_seq(builtin(name).ok_or(ConfigError::Invalid("unknown action or motion in keymap"))?, &[])?)
+                        } else {
+                            return Err(Box::new(ConfigError::Invalid("keymap binding")));
+                        }
+                    },
+                    _ => return Err(Box::new(ConfigError::Invalid("keymap binding")))
+                };
+                map.insert(keys, binding);
+            }
+            km.maps.insert(String::from(*mode), map);
+        }
+        Ok(km)
+    }
+
+    /// keys typed so far that could still become a mapping
+    pub fn pending(&self) -> &[Key] {
+        &self.pending
+    }
+
+    /// take a key typed in a mode that uses the map `mode`, returning whatever can be decided now
+    pub fn feed(&mut self, mode: &str, k: Key) -> Vec<Resolved> {
+        self.pending.push(k);
+        self.pending_since = Instant::now();
+        self.resolve(mode, false)
+    }
+
+    /// once the timeout has passed, keys that could have become a longer mapping are taken as they
+    /// are (or as the shorter mapping they match)
+    pub fn check_timeout(&mut self, mode: &str) -> Vec<Resolved> {
+        if self.pending.len() > 0 && self.pending_since.elapsed() >= self.timeout {
+            self.resolve(mode, true)
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn resolve(&mut self, mode: &str, timed_out: bool) -> Vec<Resolved> {
+        let mut out = Vec::new();
+        let map = match self.maps.get(mode) {
+            Some(m) => m,
+            None => {
+                out.extend(self.pending.drain(..).map(Resolved::Key));
+                return out;
+            }
+        };
+        while self.pending.len() > 0 {
+            let longer = map.keys().any(|ks| ks.len() > self.pending.len() && ks.starts_with(&self.pending));
+            if longer && !timed_out {
+                // ambiguous or unfinished, so wait for more keys
+                break;
+            }
+            // the longest mapping the pending keys start with
+            let found = (1..(self.pending.len()+1)).rev()
+                .filter_map(|n| map.get(&self.pending[..n]).map(|b| (n, b)))
+                .next();
+            match found {
+                Some((n, b)) => {
+                    self.pending.drain(..n);
+                    match b {
+                        &Binding::Keys(ref ks) => out.extend(ks.iter().cloned().map(Resolved::Key)),
+                        &Binding::Command(ref c) => out.push(Resolved::Command(c.clone()))
+                    }
+                },
+                None => out.push(Resolved::Key(self.pending.remove(0)))
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+[keymap]
+leader = ","
+timeout = 0
+
+[keymap.normal]
+"H" = "^"
+"<leader>w" = { command = "w" }
+"g" = "x"
+"gh" = { motion = "line-start" }
+"<C-u>" = { action = "undo" }
+"<lt>" = { keys = "<Esc>dd" }
+"#;
+
+    fn keymap(config: &str) -> Result<Keymap, Box<Error>> {
+        Keymap::from_config(Some(&config.parse::<Value>().unwrap()))
+    }
+
+    fn show(rs: Vec<Resolved>) -> String {
+        rs.iter().map(|r| match r {
+            &Resolved::Key(k) => format!("{}", k),
+            &Resolved::Command(ref c) => format!("[:{}]", c)
+        }).collect()
+    }
+
+    #[test]
+    fn key_names() {
+        let leader = [Key::Char(',')];
+        assert_eq!(Key::parse_seq("<C-x><c-X>", &[]).unwrap(), vec![Key::Ctrl('x'), Key::Ctrl('x')]);
+        assert_eq!(Key::parse_seq("<C-Space><Esc><cr>", &[]).unwrap(), vec![Key::Ctrl(' '), Key::Escape, Key::Return]);
+        assert_eq!(Key::parse_seq("<lt>a", &[]).unwrap(), vec![Key::Char('<'), Key::Char('a')]);
+        assert_eq!(Key::parse_seq("<leader>w", &leader).unwrap(), vec![Key::Char(','), Key::Char('w')]);
+        // a < that doesn't start a name is just a <
+        assert_eq!(Key::parse_seq("<>a<b", &[]).unwrap(), "<>a<b".chars().map(Key::Char).collect::<Vec<_>>());
+        assert!(Key::parse_seq("<nope>", &[]).is_err());
+        assert!(Key::parse_seq("<C-xy>", &[]).is_err());
+        assert!(keymap("[keymap.normal]\n\"<bad>\" = \"x\"").is_err());
+        assert!(keymap("[keymap.normal]\n\"x\" = { action = \"nope\" }").is_err());
+    }
+
+    #[test]
+    fn feeding() {
+        let mut km = keymap(CONFIG).unwrap();
+        assert_eq!(show(km.feed("normal", Key::Char('H'))), "^");
+        assert_eq!(show(km.feed("normal", Key::Ctrl('u'))), "u");
+        assert_eq!(show(km.feed("normal", Key::Char('<'))), "<Esc>dd");
+        assert_eq!(show(km.feed("normal", Key::Char(','))), "");
+        assert_eq!(km.pending(), &[Key::Char(',')]);
+        assert_eq!(show(km.feed("normal", Key::Char('w'))), "[:w]");
+        // a leader that doesn't go on to a mapping is typed as it is
+        km.feed("normal", Key::Char(','));
+        assert_eq!(show(km.feed("normal", Key::Char('q'))), ",q");
+        // other modes aren't mapped
+        assert_eq!(show(km.feed("insert", Key::Char('H'))), "H");
+    }
+
+    #[test]
+    fn prefixes() {
+        let mut km = keymap(&CONFIG.replace("timeout = 0", "")).unwrap();
+        // g is mapped, but so is gh, so g waits
+        assert_eq!(show(km.feed("normal", Key::Char('g'))), "");
+        assert_eq!(show(km.feed("normal", Key::Char('h'))), "^");
+        km.feed("normal", Key::Char('g'));
+        assert_eq!(show(km.feed("normal", Key::Char('z'))), "xz");
+        // still within the timeout
+        km.feed("normal", Key::Char('g'));
+        assert_eq!(show(km.check_timeout("normal")), "");
+        assert_eq!(km.pending(), &[Key::Char('g')]);
+    }
+
+    #[test]
+    fn timeout() {
+        let mut km = keymap(CONFIG).unwrap();
+        assert_eq!(show(km.feed("normal", Key::Char('g'))), "");
+        assert_eq!(show(km.check_timeout("normal")), "x");
+        assert_eq!(km.pending().len(), 0);
+        km.feed("normal", Key::Char(','));
+        assert_eq!(show(km.check_timeout("normal")), ",");
+    }
+}
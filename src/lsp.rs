@@ -1,30 +1,33 @@
 
 use std::error::Error;
 use std::process::*;
-use std::sync::{Arc, Mutex, TryLockError, atomic::AtomicUsize, atomic::Ordering};
-use std::cell::RefCell;
+use std::sync::{Arc, Mutex, atomic::AtomicUsize, atomic::AtomicBool, atomic::Ordering};
 use std::thread;
 use std::io::{self, Read, Write, Error as IOError, ErrorKind as IOErrorKind};
 use std::collections::{HashMap,VecDeque};
+use std::time::{Duration, Instant};
+use std::path::{Path, PathBuf};
+use std::net::{TcpStream, ToSocketAddrs};
+#[cfg(any(target_os="macos", target_os="linux"))]
+use std::os::unix::net::UnixStream;
 use std::result::Result as SResult;
+use std::rc::Rc;
+use std::cell::RefCell;
 use toml::Value as TomlValue;
 use json;
 use json::{JsonValue};
+use regex::Regex;
 use super::ConfigError;
 
-use futures;
-use futures::prelude::*;
-
 use mio;
 #[cfg(target_os="windows")]
 use mio_named_pipes::NamedPipe;
 
 use buffer;
-
-#[cfg(target_os="windows")]
-struct ProcessInPipe(NamedPipe);
-#[cfg(target_os="windows")]
-struct ProcessOutPipe(NamedPipe);
+use app;
+use highlight;
+use jsonrpc::{self, Decoder, FrameError};
+use traffic::{TrafficLog, Direction};
 
 #[cfg(any(target_os="macos", target_os="linux"))]
 pub struct Fd<T>(T);
@@ -78,233 +81,560 @@ impl<T> mio::Evented for Fd<T> where T: ::std::os::unix::prelude::AsRawFd {
     }
 }
 
-#[cfg(any(target_os="macos", target_os="linux"))]
-struct ProcessInPipe(Fd<ChildStdin>);
-#[cfg(any(target_os="macos", target_os="linux"))]
-struct ProcessOutPipe(Fd<ChildStdout>);
+/// the end of a transport that messages from the server are read from
+pub trait Incoming: Read + mio::Evented + Send {}
+impl<T: Read + mio::Evented + Send> Incoming for T {}
+
+/// the end of a transport that messages to the server are written to
+pub trait Outgoing: Write + mio::Evented + Send {}
+impl<T: Write + mio::Evented + Send> Outgoing for T {}
+
+/// how messages get to and from a language server: the stdio of a process started for it, or a
+/// socket to one that is already running. Framing and dispatch are the same whichever it is
+pub trait Transport {
+    /// the two ends, which the reader thread waits on separately
+    fn split(self: Box<Self>) -> io::Result<(Box<Incoming>, Box<Outgoing>)>;
+}
+
+// the stdin and stdout of a language server process
+struct ChildStdio(ChildStdin, ChildStdout);
 
-impl ProcessInPipe {
-    fn wrap(ins: ChildStdin) -> ProcessInPipe {
+impl Transport for ChildStdio {
+    fn split(self: Box<Self>) -> io::Result<(Box<Incoming>, Box<Outgoing>)> {
+        let ChildStdio(ins, out) = *self;
         #[cfg(target_os="windows")]
         {
             use std::os::windows::io::*;
             unsafe {
-                ProcessInPipe(NamedPipe::from_raw_handle(ins.into_raw_handle()))
+                Ok((Box::new(NamedPipe::from_raw_handle(out.into_raw_handle())), Box::new(NamedPipe::from_raw_handle(ins.into_raw_handle()))))
             }
         }
         #[cfg(any(target_os="macos", target_os="linux"))]
         {
-            use std::os::unix::io::*;
-            unsafe { ProcessInPipe(Fd(ins)) }
+            Ok((Box::new(Fd(out)), Box::new(Fd(ins))))
         }
     }
 }
-impl ProcessOutPipe {
-    fn wrap(out: ChildStdout) -> ProcessOutPipe {
-        #[cfg(target_os="windows")]
-        {
-            use std::os::windows::io::*;
-            unsafe {
-                ProcessOutPipe(NamedPipe::from_raw_handle(out.into_raw_handle()))
-            }
-        }
-        #[cfg(any(target_os="macos", target_os="linux"))]
-        {
-            use std::os::unix::io::*;
-            unsafe { ProcessOutPipe(Fd(out)) }
-        }
+
+// sockets are split into two handles to the same connection
+#[cfg(any(target_os="macos", target_os="linux"))]
+impl Transport for TcpStream {
+    fn split(self: Box<Self>) -> io::Result<(Box<Incoming>, Box<Outgoing>)> {
+        let ins = self.try_clone()?;
+        Ok((Box::new(Fd(*self)), Box::new(Fd(ins))))
     }
 }
 
-impl Read for ProcessOutPipe {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.0.read(buf)
+#[cfg(target_os="windows")]
+impl Transport for TcpStream {
+    fn split(self: Box<Self>) -> io::Result<(Box<Incoming>, Box<Outgoing>)> {
+        let out = mio::net::TcpStream::from_stream(*self)?;
+        let ins = out.try_clone()?;
+        Ok((Box::new(out), Box::new(ins)))
     }
 }
 
-impl Write for ProcessInPipe {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.0.write(buf)
+#[cfg(any(target_os="macos", target_os="linux"))]
+impl Transport for UnixStream {
+    fn split(self: Box<Self>) -> io::Result<(Box<Incoming>, Box<Outgoing>)> {
+        let ins = self.try_clone()?;
+        Ok((Box::new(Fd(*self)), Box::new(Fd(ins))))
     }
+}
 
-    fn flush(&mut self) -> io::Result<()> {
-        self.0.flush()
+// connect to each address `addr` resolves to in turn, giving up on each one after `timeout`
+fn connect_tcp(addr: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let mut err = IOError::new(IOErrorKind::NotFound, format!("{} has no addresses", addr));
+    for a in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&a, timeout) {
+            Ok(s) => return Ok(s),
+            Err(e) => err = e
+        }
     }
+    Err(err)
 }
 
+// connect to a language server at the `tcp` address or `socket` path in its config, or else start
+// its `cmd`. A process that was started is returned too. Connecting is done on the UI thread, so
+// it only waits for `timeout`
+fn connect(config: &TomlValue, timeout: Duration) -> SResult<(Option<Child>, Box<Transport>), Box<Error>> {
+    if let Some(addr) = config.get("tcp") {
+        let addr = addr.as_str().ok_or(ConfigError::Invalid("language server tcp address"))?;
+        return Ok((None, Box::new(connect_tcp(addr, timeout)?)));
+    }
+    if let Some(path) = config.get("socket") {
+        let path = path.as_str().ok_or(ConfigError::Invalid("language server socket path"))?;
+        #[cfg(any(target_os="macos", target_os="linux"))]
+        {
+            return Ok((None, Box::new(UnixStream::connect(path)?)));
+        }
+        #[cfg(target_os="windows")]
+        {
+            let _ = path;
+            return Err(Box::new(ConfigError::Invalid("language server socket: Unix sockets aren't supported on Windows")));
+        }
+    }
+    let mut cmd = Command::new(config.get("cmd")
+                         .ok_or(ConfigError::Missing("language server command"))?.as_str()
+                         .ok_or(ConfigError::Invalid("language server command"))?);
+    if let Some(args) = config.get("args") {
+        for a in args.as_array().ok_or(ConfigError::Invalid("language server arguments"))? {
+            cmd.arg(a.as_str().ok_or(ConfigError::Invalid("language server arguments"))?);
+        }
+    }
+    let mut ps = cmd.stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+    let stdio = ChildStdio(ps.stdin.take().unwrap(), ps.stdout.take().unwrap());
+    Ok((Some(ps), Box::new(stdio)))
+}
+
+/// why a request didn't get a result
 #[derive(Debug)]
-pub enum FutureResponseError {
-    LockPoisoned
+pub enum ResponseError {
+    /// the server answered with an error
+    Server { code: i64, message: String },
+    TimedOut,
+    Cancelled,
+    /// the server exited before it answered
+    Exited
 }
 
-impl Error for FutureResponseError {
+impl Error for ResponseError {
     fn description(&self) -> &str {
         match self {
-            FutureResponseError::LockPoisoned => "poisoned lock"
+            &ResponseError::Server { .. } => "language server error",
+            &ResponseError::TimedOut => "language server request timed out",
+            &ResponseError::Cancelled => "language server request cancelled",
+            &ResponseError::Exited => "language server exited"
         }
     }
 }
 
 use std::fmt::*;
-impl Display for FutureResponseError {
+impl Display for ResponseError {
     fn fmt(&self, f: &mut Formatter) -> Result {
-        write!(f, "{}", self.description())
+        match self {
+            &ResponseError::Server { code, ref message } => write!(f, "language server error {}: {}", code, message),
+            _ => write!(f, "{}", self.description())
+        }
+    }
+}
+
+// JSON-RPC error codes
+const METHOD_NOT_FOUND: i64 = -32601;
+
+/// called on the UI thread with the result of a request, once the server has answered (or the
+/// request has timed out or been cancelled)
+pub type ResponseHandler = Box<FnMut(SResult<JsonValue, ResponseError>, &mut app::State)>;
+
+/// something from the server that the editor needs to deal with, from `LanguageServer::poll`
+pub enum Dispatch {
+    Response(ResponseHandler, SResult<JsonValue, ResponseError>),
+    Notification(JsonValue),
+    /// a request that only the editor can answer, like workspace/applyEdit. It has to be answered
+    /// with `LanguageServer::respond`
+    Request(JsonValue),
+    /// something from the server that couldn't be made sense of
+    ProtocolError(String)
+}
+
+impl Dispatch {
+    /// run a response's handler, or pass a notification or request to `notify`. Protocol errors
+    /// go in the status line
+    pub fn run<F: FnMut(&JsonValue, &mut app::State)>(self, app: &mut app::State, mut notify: F) {
+        match self {
+            Dispatch::Response(mut h, r) => h(r, app),
+            Dispatch::Notification(n) | Dispatch::Request(n) => notify(&n, app),
+            Dispatch::ProtocolError(e) => app.status_text = Some(format!("language server protocol error: {}", e))
+        }
     }
 }
 
-pub struct FutureResponse {
+/// a request whose response is checked for by something on screen, like the completion menu,
+/// rather than handled as soon as it arrives
+pub struct PendingResponse {
+    pub server: Rc<RefCell<LanguageServer>>,
     id: usize,
-    response_pool: Arc<Mutex<HashMap<usize, JsonValue>>>
+    slot: Rc<RefCell<Option<JsonValue>>>,
+    answered: bool
 }
 
-impl FutureResponse {
-    fn wait(self) -> SResult<JsonValue, FutureResponseError> {
-        loop {
-            match self.response_pool.try_lock() {
-                Ok(ref mut rp) => {
-                    match rp.remove(&self.id) {
-                        Some(r) => return Ok(r),
-                        None => {}
-                    }
-                },
-                Err(TryLockError::Poisoned(_)) => return Err(FutureResponseError::LockPoisoned),
-                Err(TryLockError::WouldBlock) => {}
-            }
+impl PendingResponse {
+    /// send a request. Errors go in the status line after `what`, except cancellations
+    pub fn request(server: Rc<RefCell<LanguageServer>>, method: &str, params: JsonValue, what: &'static str) -> PendingResponse {
+        let slot = Rc::new(RefCell::new(None));
+        let s = slot.clone();
+        let id = server.borrow_mut().request(method, params, move |r, app| match r {
+            Ok(r) => *s.borrow_mut() = Some(r),
+            Err(ResponseError::Cancelled) => {},
+            Err(e) => app.status_text = Some(format!("{}: {}", what, e))
+        });
+        PendingResponse { server, id, slot, answered: false }
+    }
+
+    /// the response, if it has arrived since the last call
+    pub fn take(&mut self) -> Option<JsonValue> {
+        let r = self.slot.borrow_mut().take();
+        if r.is_some() { self.answered = true; }
+        r
+    }
+
+    /// stop waiting, telling the server if it hasn't answered yet
+    pub fn cancel(self) {
+        if !self.answered && self.slot.borrow().is_none() {
+            self.server.borrow_mut().cancel(self.id);
         }
     }
 }
 
-impl Future for FutureResponse {
-    type Item = JsonValue;
-    type Error = FutureResponseError;
+/// is `c` one of the characters a server gave for starting something, like completion?
+pub fn is_trigger(triggers: &Option<Vec<String>>, c: char) -> bool {
+    triggers.iter().flat_map(|t| t.iter()).any(|t| t.starts_with(c) && t.len() == c.len_utf8())
+}
 
-    fn poll(&mut self, _: &mut futures::task::Context) -> SResult<Async<JsonValue>, FutureResponseError> {
-        match self.response_pool.try_lock() {
-            Ok(ref mut rp) => {
-                rp.remove(&self.id).map_or_else(|| Ok(Async::Pending), |r| Ok(Async::Ready(r))) 
-            },
-            Err(TryLockError::Poisoned(_)) => Err(FutureResponseError::LockPoisoned),
-            Err(TryLockError::WouldBlock) => Ok(Async::Pending)
+/// an edit to a document. `start` and `end` are LSP positions, (line, UTF-16 character), in the
+/// document as it was just before the edit
+#[derive(Debug, Clone)]
+pub struct ContentChange {
+    pub start: (usize, usize),
+    pub end: (usize, usize),
+    pub range_length: usize,
+    pub text: String
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Error = 1,
+    Warning = 2,
+    Information = 3,
+    Hint = 4
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "{}", match self {
+            &Severity::Error => "error",
+            &Severity::Warning => "warning",
+            &Severity::Information => "info",
+            &Severity::Hint => "hint"
+        })
+    }
+}
+
+/// a problem the server has found in a document. `start` and `end` are (col, line) locations in
+/// the buffer, converted from the LSP positions when the diagnostics were published
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub start: (usize, usize),
+    pub end: (usize, usize),
+    pub severity: Severity,
+    pub message: String,
+    pub source: Option<String>,
+    /// the index in `State::language_servers` of the server that reported it
+    pub server: usize
+}
+
+/// a symbol in a document's outline. `start`..`end` covers all of it and `name_at` is where its
+/// name is, as (col, line) locations in the buffer
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: usize,
+    pub detail: Option<String>,
+    pub start: (usize, usize),
+    pub end: (usize, usize),
+    pub name_at: (usize, usize),
+    pub children: Vec<Symbol>
+}
+
+/// what a SymbolKind number is called
+pub fn symbol_kind_name(kind: usize) -> &'static str {
+    match kind {
+        1 => "file", 2 => "module", 3 => "namespace", 4 => "package", 5 => "class", 6 => "method",
+        7 => "property", 8 => "field", 9 => "constructor", 10 => "enum", 11 => "interface",
+        12 => "function", 13 => "variable", 14 => "constant", 15 => "string", 16 => "number",
+        17 => "boolean", 18 => "array", 19 => "object", 20 => "key", 21 => "null",
+        22 => "enum member", 23 => "struct", 24 => "event", 25 => "operator", 26 => "type parameter",
+        _ => "symbol"
+    }
+}
+
+/// the path a file:// URI refers to
+pub fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    if !uri.starts_with("file://") { return None; }
+    // undo percent encoding
+    let bytes = uri[7..].as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match (bytes[i], bytes.get(i+1..i+3).and_then(|h| ::std::str::from_utf8(h).ok()).and_then(|h| u8::from_str_radix(h, 16).ok())) {
+            (b'%', Some(b)) => { decoded.push(b); i += 3; },
+            (b, _) => { decoded.push(b); i += 1; }
         }
     }
+    let path = String::from_utf8_lossy(&decoded).into_owned();
+    // file:///C:/x on Windows, and file:///x elsewhere
+    #[cfg(target_os="windows")]
+    let path = String::from(path.trim_left_matches('/'));
+    #[cfg(not(target_os="windows"))]
+    let path = format!("/{}", path.trim_left_matches('/'));
+    Some(PathBuf::from(path))
 }
 
-use std::rc::Rc;
+/// the file:// URI for a path. Everything but unreserved characters, separators and colons is
+/// percent-encoded, and Windows paths get forward slashes and a / before the drive
+pub fn path_to_uri(path: &Path) -> String {
+    let path = path.to_string_lossy();
+    #[cfg(target_os="windows")]
+    let path = format!("/{}", path.replace('\\', "/"));
+    let mut uri = String::from("file://");
+    for &b in path.as_bytes() {
+        if (b as char).is_ascii_alphanumeric() || b"-._~/:".contains(&b) {
+            uri.push(b as char);
+        } else {
+            uri.push_str(&format!("%{:02X}", b));
+        }
+    }
+    uri
+}
 
-pub struct LanguageServer {
-    ps: Child,
-    response_pool: Arc<Mutex<HashMap<usize, JsonValue>>>,
-    request_queue: Arc<Mutex<VecDeque<JsonValue>>>,
-    notification_queue: Arc<Mutex<VecDeque<JsonValue>>>,
-    next_id: Arc<AtomicUsize>,
-    response_thread: Option<thread::JoinHandle<()>>,
-    lang_id: String
+/// an LSP Position as (line, character), where character counts UTF-16 code units
+pub fn position_from_json(p: &JsonValue) -> (usize, usize) {
+    (p["line"].as_usize().unwrap_or(0), p["character"].as_usize().unwrap_or(0))
 }
 
-#[derive(Debug)]
-enum ReadState {
-    Waiting,
-    Data { content_length: usize, data: String }
+/// the JSON for an LSP Position, from (line, character)
+pub fn position_to_json(p: (usize, usize)) -> JsonValue {
+    object!{ "line" => p.0, "character" => p.1 }
 }
 
-struct JsonRpcHeaderIter<'s> {
-    s: &'s str,
-    i: usize
+/// the places in a definition, declaration or references response, which can be a Location, an
+/// array of them or an array of LocationLinks. Each is a path and an LSP (line, character) position
+pub fn parse_locations(v: &JsonValue) -> Vec<(PathBuf, (usize, usize))> {
+    let one = |l: &JsonValue| {
+        let (uri, range) = if l.has_key("targetUri") { (&l["targetUri"], &l["targetSelectionRange"]) } else { (&l["uri"], &l["range"]) };
+        let path = uri.as_str().and_then(uri_to_path);
+        path.map(|p| (p, position_from_json(&range["start"])))
+    };
+    if v.is_array() {
+        v.members().filter_map(one).collect()
+    } else {
+        one(v).into_iter().collect()
+    }
 }
 
-fn read_headers<'s, S: AsRef<str> + 's>(s: &'s S) -> JsonRpcHeaderIter<'s> {
-    JsonRpcHeaderIter { s: s.as_ref(), i: 0 }
+/// the text of a hover response's contents, which can be a MarkupContent, a MarkedString or an
+/// array of MarkedStrings
+pub fn hover_text(v: &JsonValue) -> Option<String> {
+    let marked = |s: &JsonValue| s.as_str().or(s["value"].as_str()).map(String::from);
+    let text = if v.is_array() {
+        v.members().filter_map(marked).collect::<Vec<_>>().join("\n\n")
+    } else {
+        marked(v)?
+    };
+    if text.trim().len() == 0 { None } else { Some(text) }
 }
 
-impl<'s> Iterator for JsonRpcHeaderIter<'s> {
-    type Item = (usize, usize);
+/// the byte offset in `line` of an LSP character offset, which counts UTF-16 code units
+pub fn utf16_to_byte_col(line: &str, character: usize) -> usize {
+    let mut units = 0;
+    for (i, c) in line.char_indices() {
+        if units >= character { return i; }
+        units += c.len_utf16();
+    }
+    line.len()
+}
 
-    fn next(&mut self) -> Option<(usize, usize)> {
-        if self.i >= self.s.len() { return None; }
-        let s = self.s.split_at(self.i).1;
-        match s.find("Content-Length: ") {
-            Some(header_start) => {
-                let header_end = s.split_at(header_start).1.find("\r\n").unwrap() + header_start + 4;
-                //println!("t = {}", &s[header_start+16..header_end-4]);
-                let content_length = s[header_start+16..header_end-4].parse::<usize>().expect("parse content length");
-                self.i += content_length + header_end;
-                Some((content_length,self.i - content_length))
+/// TextDocumentSyncKind
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SyncKind {
+    None,
+    Full,
+    Incremental
+}
+
+impl Default for SyncKind {
+    fn default() -> SyncKind { SyncKind::None }
+}
+
+/// what a server can do, picked out of the ServerCapabilities in its response to initialize.
+/// Until that arrives, it can't do anything
+#[derive(Debug, Clone, Default)]
+pub struct ServerCapabilities {
+    pub sync: SyncKind,
+    /// whether it wants to hear about saves
+    pub save: bool,
+    /// the characters that start completion, if it can complete at all
+    pub completion: Option<Vec<String>>,
+    pub completion_resolve: bool,
+    /// the characters that ask for signature help, including the retrigger characters, if it
+    /// has signature help at all
+    pub signature_help: Option<Vec<String>>,
+    pub hover: bool,
+    pub definition: bool,
+    pub declaration: bool,
+    pub references: bool,
+    pub document_symbol: bool,
+    pub workspace_symbol: bool,
+    pub rename: bool,
+    pub code_action: bool,
+    pub code_action_resolve: bool,
+    pub formatting: bool,
+    pub range_formatting: bool,
+    /// the token types in the legend, if it has semantic tokens for whole documents
+    pub semantic_tokens: Option<Vec<String>>
+}
+
+impl ServerCapabilities {
+    pub fn parse(c: &JsonValue) -> ServerCapabilities {
+        // providers are either true or an object of options
+        let provides = |p: &JsonValue| p.as_bool().unwrap_or(p.is_object());
+        let strings = |v: &JsonValue| v.members().filter_map(|s| s.as_str().map(String::from)).collect::<Vec<_>>();
+        let sync = &c["textDocumentSync"];
+        // either the kind itself, or an object with the kind in `change`
+        let kind = if sync.is_object() { sync["change"].as_u8() } else { sync.as_u8() };
+        let completion = &c["completionProvider"];
+        let signature = &c["signatureHelpProvider"];
+        let tokens = &c["semanticTokensProvider"];
+        ServerCapabilities {
+            sync: match kind {
+                Some(1) => SyncKind::Full,
+                Some(2) => SyncKind::Incremental,
+                _ => SyncKind::None
             },
-            None => None
+            // servers that only give the kind have always been told about saves
+            save: if sync.is_object() { provides(&sync["save"]) } else { !sync.is_null() },
+            completion: if completion.is_object() { Some(strings(&completion["triggerCharacters"])) } else { None },
+            completion_resolve: completion["resolveProvider"].as_bool() == Some(true),
+            signature_help: if signature.is_object() {
+                let mut t = strings(&signature["triggerCharacters"]);
+                t.extend(strings(&signature["retriggerCharacters"]));
+                Some(t)
+            } else { None },
+            hover: provides(&c["hoverProvider"]),
+            definition: provides(&c["definitionProvider"]),
+            declaration: provides(&c["declarationProvider"]),
+            references: provides(&c["referencesProvider"]),
+            document_symbol: provides(&c["documentSymbolProvider"]),
+            workspace_symbol: provides(&c["workspaceSymbolProvider"]),
+            rename: provides(&c["renameProvider"]),
+            code_action: provides(&c["codeActionProvider"]),
+            code_action_resolve: c["codeActionProvider"]["resolveProvider"].as_bool() == Some(true),
+            formatting: provides(&c["documentFormattingProvider"]),
+            range_formatting: provides(&c["documentRangeFormattingProvider"]),
+            semantic_tokens: if provides(&tokens["full"]) { Some(strings(&tokens["legend"]["tokenTypes"])) } else { None }
         }
     }
+
+    /// the names of the features it has, for :lsp info
+    pub fn names(&self) -> Vec<&'static str> {
+        let features = [
+            (self.sync != SyncKind::None, "sync"), (self.completion.is_some(), "completion"),
+            (self.signature_help.is_some(), "signature help"), (self.hover, "hover"),
+            (self.definition, "definition"), (self.declaration, "declaration"), (self.references, "references"),
+            (self.document_symbol, "symbols"), (self.workspace_symbol, "workspace symbols"), (self.rename, "rename"),
+            (self.code_action, "code actions"), (self.formatting, "formatting"), (self.range_formatting, "range formatting"),
+            (self.semantic_tokens.is_some(), "semantic tokens")
+        ];
+        features.iter().filter(|f| f.0).map(|f| f.1).collect()
+    }
 }
 
-impl ReadState {
-    fn advance<R: Read, F: FnMut(&str)>(self, out: &mut R, buf: &mut [u8], mut done: F) -> ReadState {
-        match self {
-            ReadState::Waiting => {
-                let n = out.read(buf).unwrap();
-                let s = String::from_utf8_lossy(&buf[0..n]);
-
-                for (content_length, header_end) in read_headers(&s) {
-                    if header_end + content_length > n {
-                        // wait for rest of data
-                        return ReadState::Data { content_length, data: String::from(s.split_at(header_end).1) };
-                    } else {
-                        done(&s[header_end..header_end+content_length]);
-                    }
-                }
-                ReadState::Waiting
-            },
-            ReadState::Data { content_length, mut data } => {
-                let n = out.read(buf).unwrap();
-                data += &String::from_utf8_lossy(&buf[0..n]);
-                
-                if data.len() >= content_length {
-                    done(&data[0..content_length]);
-                    data = data.split_off(content_length);
-                }
+// a request that hasn't been answered yet
+struct Pending {
+    method: String,
+    deadline: Option<Instant>,
+    cancelled: bool,
+    // None for requests the LanguageServer answers itself, like initialize
+    handler: Option<ResponseHandler>
+}
 
-                for (content_length, header_end) in read_headers(&data) {
-                    if header_end + content_length > data.len() {
-                        // wait for rest of data
-                        return ReadState::Data { content_length, data: String::from(data.split_at(header_end).1) };
-                    } else {
-                        done(&data[header_end..header_end+content_length]);
-                    }
-                }
-                ReadState::Waiting
-            },
+// convert a config value, for the settings sent in response to workspace/configuration
+fn toml_to_json(v: &TomlValue) -> JsonValue {
+    match v {
+        &TomlValue::String(ref s) => s.clone().into(),
+        &TomlValue::Integer(i) => i.into(),
+        &TomlValue::Float(f) => f.into(),
+        &TomlValue::Boolean(b) => b.into(),
+        &TomlValue::Datetime(ref d) => d.to_string().into(),
+        &TomlValue::Array(ref a) => JsonValue::Array(a.iter().map(toml_to_json).collect()),
+        &TomlValue::Table(ref t) => {
+            let mut o = JsonValue::new_object();
+            for (k, v) in t.iter() { o[k.as_str()] = toml_to_json(v); }
+            o
         }
     }
 }
 
+pub struct LanguageServer {
+    // the server's process, unless it was already running and is connected to over a socket
+    ps: Option<Child>,
+    // set by the reader thread when the connection is closed
+    disconnected: Arc<AtomicBool>,
+    // how the server went away, once it has
+    exit: Option<String>,
+    // messages waiting to be written to the server. A null closes the connection instead
+    request_queue: Arc<Mutex<VecDeque<JsonValue>>>,
+    // messages read from the server, or why they couldn't be, waiting for `poll`
+    incoming: Arc<Mutex<VecDeque<SResult<JsonValue, FrameError>>>>,
+    next_id: Arc<AtomicUsize>,
+    response_thread: Option<thread::JoinHandle<()>>,
+    lang_id: String,
+    pending: HashMap<usize, Pending>,
+    timeout: Duration,
+    /// what the server said it can do, in its response to initialize
+    pub capabilities: ServerCapabilities,
+    /// the name and version the server gave in its response to initialize
+    pub server_info: Option<String>,
+    // messages sent before the server has been initialized, which have to wait until it is
+    held: Vec<JsonValue>,
+    initialized: bool,
+    // the [language-server.settings] table, for workspace/configuration
+    settings: JsonValue,
+    /// what has been sent and received, shared with the reader thread and the server's slot
+    pub log: Arc<Mutex<TrafficLog>>
+}
+
 impl LanguageServer {
-    pub fn new(config: &TomlValue) -> SResult<LanguageServer, Box<Error>> {
-        let mut ps = Command::new(config.get("cmd")
-                             .ok_or(ConfigError::Missing("language server command"))?.as_str()
-                             .ok_or(ConfigError::Invalid("language server command"))?)
-                .stdin(Stdio::piped())
-                .stdout(Stdio::piped())
-                .spawn()?;
-        let mut ins = ProcessInPipe::wrap(ps.stdin.take().unwrap());
-        let mut out = ProcessOutPipe::wrap(ps.stdout.take().unwrap());
+    /// start or connect to the server described by a [[language-server]] table, recording what is
+    /// sent and received in `log`
+    pub fn new(config: &TomlValue, log: Arc<Mutex<TrafficLog>>) -> SResult<LanguageServer, Box<Error>> {
+        let timeout = match config.get("timeout") {
+            Some(t) => Duration::from_millis(t.as_integer().ok_or(ConfigError::Invalid("language server timeout"))? as u64),
+            None => Duration::from_secs(10)
+        };
+        let (ps, transport) = connect(config, timeout)?;
+        let (mut out, mut ins) = transport.split()?;
         let mut ls = LanguageServer {
             ps,
-            response_pool: Arc::new(Mutex::new(HashMap::new())),
+            disconnected: Arc::new(AtomicBool::new(false)),
+            exit: None,
             request_queue: Arc::new(Mutex::new(VecDeque::new())),
-            notification_queue: Arc::new(Mutex::new(VecDeque::new())),
+            incoming: Arc::new(Mutex::new(VecDeque::new())),
             next_id: Arc::new(AtomicUsize::new(1)),
             response_thread: None,
             lang_id: config.get("language-id").ok_or(ConfigError::Missing("language server id"))?.as_str()
-                .ok_or(ConfigError::Invalid("language server id"))?.into()
+                .ok_or(ConfigError::Invalid("language server id"))?.into(),
+            pending: HashMap::new(),
+            timeout,
+            capabilities: ServerCapabilities::default(),
+            server_info: None,
+            held: Vec::new(),
+            initialized: false,
+            settings: config.get("settings").map_or(json::Null, toml_to_json),
+            log: log.clone()
         };
         let poll = mio::Poll::new().unwrap();
-        poll.register(&out.0, mio::Token(0), mio::Ready::readable(), mio::PollOpt::edge()/* | mio::PollOpt::oneshot()*/).unwrap();
-        poll.register(&ins.0, mio::Token(1), mio::Ready::writable(), mio::PollOpt::level()).unwrap();
+        poll.register(&*out, mio::Token(0), mio::Ready::readable(), mio::PollOpt::level()).unwrap();
+        poll.register(&*ins, mio::Token(1), mio::Ready::writable(), mio::PollOpt::level()).unwrap();
         let rq = ls.request_queue.clone();
-        let rp = ls.response_pool.clone();
-        let nq = ls.notification_queue.clone();
+        let inc = ls.incoming.clone();
+        let disconnected = ls.disconnected.clone();
         ls.response_thread = Some(thread::spawn(move || {
             let mut buf: [u8; 1024] = [0; 1024];
             let mut events = mio::Events::with_capacity(1024);
-            let mut read_state_machine = ReadState::Waiting;
+            let mut decoder = Decoder::new();
             'main: loop {
                 match poll.poll(&mut events, None) {
                     Ok(_) => {},
@@ -316,31 +646,42 @@ impl LanguageServer {
                 for event in events.iter() {
                     match event.token() {
                         mio::Token(0) => {
-                            println!("rsm = {:?}", read_state_machine);
-                            read_state_machine = read_state_machine.advance(&mut out, &mut buf, |s| {
-                                println!("got: {}", s);
-                                let j = json::parse(&s).expect("parse response");
-                                if let Some(id) = j["id"].as_usize() {
-                                    rp.lock().expect("lock response pool").insert(id, j); 
-                                } else {
-                                    nq.lock().expect("lock notification queue").push_back(j);
+                            let n = match out.read(&mut buf) {
+                                Ok(n) => n,
+                                Err(ref e) if e.kind() == IOErrorKind::WouldBlock || e.kind() == IOErrorKind::Interrupted => continue,
+                                Err(_) => 0
+                            };
+                            // the server has closed its end, so nothing more can be sent either
+                            if n == 0 {
+                                log.lock().expect("lock traffic log").note(Direction::Received, "disconnected");
+                                disconnected.store(true, Ordering::SeqCst);
+                                break 'main;
+                            }
+                            decoder.push(&buf[..n]);
+                            let mut inc = inc.lock().expect("lock incoming queue");
+                            let mut log = log.lock().expect("lock traffic log");
+                            for msg in &mut decoder {
+                                match msg {
+                                    Ok(ref m) => log.message(Direction::Received, m),
+                                    Err(ref e) => log.note(Direction::Received, &format!("{}", e))
                                 }
-                            });
+                                inc.push_back(msg);
+                            }
                         }
                         mio::Token(1) => {
-                            //println!("cin writable!");
                             let mut resp = rq.lock().expect("lock request queue");
                             if let Some(msg) = resp.pop_front() {
+                                // dropping the connection closes it
+                                if msg.is_null() { break 'main; }
                                 let exiting = msg.has_key("method") && msg["method"] == "exit";
-                                let msg_s = json::stringify(msg);
-                                let msg_s = format!("Content-Length: {}\r\n\r\n{}", msg_s.len(), msg_s);
-                                println!("sending {}", msg_s);
-                                write!(&mut ins, "{}", msg_s);
-                                ins.flush().expect("flush ins");
-                                if exiting {
-                                    println!("exiting!");
+                                let mut log = log.lock().expect("lock traffic log");
+                                log.message(Direction::Sent, &msg);
+                                if let Err(e) = ins.write_all(jsonrpc::encode(&msg).as_bytes()).and_then(|_| ins.flush()) {
+                                    log.note(Direction::Sent, &format!("disconnected: {}", e));
+                                    disconnected.store(true, Ordering::SeqCst);
                                     break 'main;
                                 }
+                                if exiting { break 'main; }
                             }
                         }
                         _ => unreachable!()
@@ -348,107 +689,644 @@ impl LanguageServer {
                 }
             }
         }));
-        let init_response = ls.send("initialize", object!{
-            "processId" => json::Null,
-            "rootUri" => (String::from("file:///") + ::std::env::current_dir().unwrap().to_str().unwrap()),
+        // everything else waits in `held` until the server has answered this
+        let msg = ls.message("initialize", object!{
+            "processId" => ::std::process::id(),
+            "clientInfo" => object!{ "name" => "txd" },
+            "rootUri" => ::std::env::current_dir().map_or(json::Null, |d| path_to_uri(&d).into()),
             "capabilities" => object!{
                 "workspace" => object!{
                     "workspaceFolders" => false,
+                    "configuration" => true,
+                    "applyEdit" => true,
+                    "symbol" => object!{},
+                    "workspaceEdit" => object!{
+                        "documentChanges" => true
+                    }
                 },
                 "textDocument" => object!{
                     "synchronization" => object!{
                         "didSave" => true
                     },
                     "documentSymbol" => object!{
-                        "dynamicRegistration" => true
+                        "dynamicRegistration" => true,
+                        "hierarchicalDocumentSymbolSupport" => true
+                    },
+                    "signatureHelp" => object!{
+                        "signatureInformation" => object!{
+                            "parameterInformation" => object!{ "labelOffsetSupport" => true }
+                        }
+                    },
+                    "semanticTokens" => object!{
+                        "requests" => object!{ "full" => true },
+                        "tokenTypes" => highlight::TOKEN_TYPES.iter().map(|&t| JsonValue::from(t)).collect::<Vec<_>>(),
+                        "tokenModifiers" => JsonValue::new_array(),
+                        "formats" => array!["relative"]
+                    },
+                    "codeAction" => object!{
+                        "codeActionLiteralSupport" => object!{
+                            "codeActionKind" => object!{
+                                "valueSet" => array!["", "quickfix", "refactor", "refactor.extract", "refactor.inline",
+                                    "refactor.rewrite", "source", "source.organizeImports"]
+                            }
+                        }
                     }
                 }
             }
-        }).expect("send init").wait().expect("response from init");
-        
+        }, None, None);
+        ls.request_queue.lock().expect("lock queue").push_back(msg);
         Ok(ls)
     }
 
-    pub fn send<S: AsRef<str>>(&mut self, method: S, params: JsonValue) -> SResult<FutureResponse, Box<Error>> {
-        let mut msg = JsonValue::new_object();
-        msg["jsonrpc"] = ("2.0").into();
+    // build a request, remembering what to do with its response
+    fn message(&mut self, method: &str, params: JsonValue, handler: Option<ResponseHandler>, deadline: Option<Instant>) -> JsonValue {
         let id = self.next_id.fetch_add(1, Ordering::SeqCst);
-        msg["id"] = id.into();
-        msg["method"] = method.as_ref().into();
-        msg["params"] = params;
+        self.pending.insert(id, Pending { method: String::from(method), deadline, cancelled: false, handler });
+        object!{
+            "jsonrpc" => "2.0",
+            "id" => id,
+            "method" => method,
+            "params" => params
+        }
+    }
+
+    // queue a message to be written to the server, once it has been initialized
+    fn queue(&mut self, msg: JsonValue) {
+        if self.initialized {
+            self.request_queue.lock().expect("lock queue").push_back(msg);
+        } else {
+            self.held.push(msg);
+        }
+    }
+
+    /// send a request. `handler` is run on the UI thread by `poll`'s caller when the response
+    /// arrives, or with an error if it takes longer than the server's timeout. Returns the id of the
+    /// request, for `cancel`
+    pub fn request<S, F>(&mut self, method: S, params: JsonValue, handler: F) -> usize
+        where S: AsRef<str>, F: FnMut(SResult<JsonValue, ResponseError>, &mut app::State) + 'static
+    {
+        let deadline = Instant::now() + self.timeout;
+        let msg = self.message(method.as_ref(), params, Some(Box::new(handler)), Some(deadline));
+        let id = msg["id"].as_usize().unwrap();
+        self.queue(msg);
+        id
+    }
+
+    /// send a notification, which the server doesn't answer
+    pub fn notify<S: AsRef<str>>(&mut self, method: S, params: JsonValue) {
+        let msg = object!{
+            "jsonrpc" => "2.0",
+            "method" => method.as_ref(),
+            "params" => params
+        };
+        self.queue(msg);
+    }
+
+    /// stop waiting for a request. Its handler gets a Cancelled error the next time the server is
+    /// polled, and whatever the server answers is ignored
+    pub fn cancel(&mut self, id: usize) {
+        match self.pending.get_mut(&id) {
+            Some(p) => { p.cancelled = true; p.deadline = Some(Instant::now()); },
+            None => return
+        }
+        self.notify("$/cancelRequest", object!{ "id" => id });
+    }
 
+    /// answer a request from the server
+    pub fn respond(&mut self, id: JsonValue, result: SResult<JsonValue, (i64, &str)>) {
+        let mut msg = object!{ "jsonrpc" => "2.0", "id" => id };
+        match result {
+            Ok(r) => msg["result"] = r,
+            Err((code, message)) => msg["error"] = object!{ "code" => code, "message" => message }
+        }
+        // requests can arrive before initialize has been answered, and their responses can't wait
         self.request_queue.lock().expect("lock queue").push_back(msg);
+    }
 
-        Ok(FutureResponse {
-            id, response_pool: self.response_pool.clone()
-        })
+    // the settings under `section`, a dotted path into the settings table
+    fn setting(&self, section: Option<&str>) -> JsonValue {
+        let mut v = &self.settings;
+        if let Some(s) = section {
+            for k in s.split('.') { v = &v[k]; }
+        }
+        v.clone()
+    }
+
+    // requests from the server that it can answer by itself
+    fn handle_request(&mut self, msg: JsonValue, out: &mut Vec<Dispatch>) {
+        let id = msg["id"].clone();
+        match msg["method"].as_str() {
+            Some("workspace/configuration") => {
+                let items = msg["params"]["items"].members().map(|i| self.setting(i["section"].as_str())).collect::<Vec<_>>();
+                self.respond(id, Ok(JsonValue::Array(items)));
+            },
+            Some("window/showMessageRequest") => {
+                // there is nowhere to pick one of the actions yet, so show the message and choose none
+                out.push(Dispatch::Notification(object!{
+                    "method" => "window/showMessage",
+                    "params" => msg["params"].clone()
+                }));
+                self.respond(id, Ok(json::Null));
+            },
+            Some("workspace/applyEdit") => out.push(Dispatch::Request(msg)),
+            _ => self.respond(id, Err((METHOD_NOT_FOUND, "method not found")))
+        }
+    }
+
+    fn initialize(&mut self, result: SResult<JsonValue, ResponseError>) {
+        match result {
+            Ok(r) => {
+                self.capabilities = ServerCapabilities::parse(&r["capabilities"]);
+                let info = &r["serverInfo"];
+                self.server_info = info["name"].as_str().map(|n| match info["version"].as_str() {
+                    Some(v) => format!("{} {}", n, v),
+                    None => String::from(n)
+                });
+            },
+            Err(e) => self.log.lock().expect("lock traffic log").note(Direction::Received, &format!("failed to initialize: {}", e))
+        }
+        self.initialized = true;
+        self.notify("initialized", JsonValue::new_object());
+        let held = ::std::mem::replace(&mut self.held, Vec::new());
+        for msg in held { self.queue(msg); }
+    }
+
+    /// deal with everything the server has sent since the last call, and with requests that have
+    /// timed out. Responses come back with their handlers, which the caller should run without
+    /// this LanguageServer borrowed, as they may want to send more requests
+    pub fn poll(&mut self) -> Vec<Dispatch> {
+        let mut out = Vec::new();
+        loop {
+            let msg = match self.incoming.lock().expect("lock incoming queue").pop_front() {
+                Some(Ok(m)) => m,
+                Some(Err(e)) => { out.push(Dispatch::ProtocolError(format!("{}", e))); continue; },
+                None => break
+            };
+            if msg.has_key("method") {
+                if msg.has_key("id") {
+                    self.handle_request(msg, &mut out);
+                } else {
+                    out.push(Dispatch::Notification(msg));
+                }
+                continue;
+            }
+            let id = match msg["id"].as_usize() {
+                Some(id) => id,
+                None => { out.push(Dispatch::ProtocolError(format!("response without an id: {}", msg))); continue; }
+            };
+            let p = match self.pending.remove(&id) {
+                Some(p) => p,
+                None => continue // cancelled or timed out already
+            };
+            let result = if msg.has_key("error") {
+                Err(ResponseError::Server {
+                    code: msg["error"]["code"].as_i64().unwrap_or(0),
+                    message: msg["error"]["message"].as_str().unwrap_or("").into()
+                })
+            } else {
+                Ok(msg["result"].clone())
+            };
+            match p.handler {
+                Some(h) => out.push(Dispatch::Response(h, result)),
+                None if p.method == "initialize" => self.initialize(result),
+                None => {}
+            }
+        }
+
+        let now = Instant::now();
+        let expired = self.pending.iter()
+            .filter(|&(_, p)| p.deadline.map_or(false, |d| d <= now))
+            .map(|(&id, _)| id).collect::<Vec<_>>();
+        for id in expired {
+            let p = self.pending.remove(&id).unwrap();
+            let err = if p.cancelled {
+                ResponseError::Cancelled
+            } else {
+                // the server should give up on it too
+                self.notify("$/cancelRequest", object!{ "id" => id });
+                ResponseError::TimedOut
+            };
+            if let Some(h) = p.handler {
+                out.push(Dispatch::Response(h, Err(err)));
+            }
+        }
+
+        // once the server has gone, nothing will be answered
+        if self.exit.is_none() {
+            self.exit = match self.ps {
+                Some(ref mut ps) => ps.try_wait().ok().and_then(|s| s).map(|s| format!("{}", s)),
+                None if self.disconnected.load(Ordering::SeqCst) => Some(String::from("disconnected")),
+                None => None
+            };
+        }
+        if self.exit.is_some() {
+            for (_, p) in self.pending.drain() {
+                if let Some(h) = p.handler { out.push(Dispatch::Response(h, Err(ResponseError::Exited))); }
+            }
+        }
+        out
+    }
+
+    /// how the server went away, if it has: how its process exited, or that the connection to it
+    /// was closed. This is noticed by `poll`
+    pub fn exit_status(&self) -> Option<String> {
+        self.exit.clone()
     }
 
     pub fn document_did_open(&mut self, buf: &buffer::Buffer) {
         let lang_id = self.lang_id.clone();
-        self.send("textDocument/didOpen", object!{
+        self.notify("textDocument/didOpen", object!{
             "textDocument" => object!{
-                "uri" => String::from("file:///") + buf.fs_loc.as_ref().expect("buffer has location").to_str().unwrap(),
+                "uri" => path_to_uri(buf.fs_loc.as_ref().expect("buffer has location")),
                 "languageId" => lang_id,
                 "version" => buf.version,
                 "text" => buf.full_text(),
             }
-        }).unwrap();
+        });
     }
 
-    pub fn document_did_change(&mut self, buf: &mut buffer::Buffer, changes: Vec<((usize,usize), (usize,usize), usize, &str)>) {
-        buf.version += 1;
-        self.send("textDocument/didChange", object!{
+    /// has the server answered initialize, so that its capabilities are known?
+    pub fn is_initialized(&self) -> bool {
+        self.initialized
+    }
+
+    /// send edits that have been made to a buffer, in the order they were made, as the buffer's
+    /// current version. Servers that only do full sync get the whole document instead
+    pub fn document_did_change(&mut self, buf: &buffer::Buffer, changes: Vec<ContentChange>) {
+        let changes = match self.capabilities.sync {
+            SyncKind::None => return,
+            SyncKind::Full => vec![object!{ "text" => buf.full_text() }],
+            SyncKind::Incremental => changes.into_iter().map(|c| object!{
+                "range" => object!{ "start" => position_to_json(c.start), "end" => position_to_json(c.end) },
+                "rangeLength" => c.range_length,
+                "text" => c.text,
+            }).collect::<Vec<_>>()
+        };
+        self.notify("textDocument/didChange", object!{
             "textDocument" => object!{
-                "uri" => String::from("file:///") + buf.fs_loc.as_ref().expect("buffer has location").to_str().unwrap(),
+                "uri" => path_to_uri(buf.fs_loc.as_ref().expect("buffer has location")),
                 "version" => buf.version
             },
-            "contentChanges" => changes.iter().map(|&(start, end, len, text)| object! {
-                "range" => object!{
-                    "start" => object!{ "line" => start.0, "character" => start.1 },
-                    "end"   => object!{ "line" => end.0,   "character" => end.1 },
-                },
-                "rangeLength" => len,
-                "text" => text,
-            }).collect::<Vec<_>>()
-        }).unwrap();
+            "contentChanges" => changes
+        });
     }
 
     pub fn document_did_save(&mut self, buf: &buffer::Buffer) {
-        self.send("textDocument/didSave", object!{
+        if !self.capabilities.save { return; }
+        self.notify("textDocument/didSave", object!{
             "textDocument" => object!{
-                "uri" => String::from("file:///") + buf.fs_loc.as_ref().expect("buffer has location").to_str().unwrap(),
+                "uri" => path_to_uri(buf.fs_loc.as_ref().expect("buffer has location")),
             },
-        }).unwrap();
+        });
     }
 
     pub fn document_did_close(&mut self, buf: &buffer::Buffer) {
-        self.send("textDocument/didClose", object!{
+        self.notify("textDocument/didClose", object!{
             "textDocument" => object!{
-                "uri" => String::from("file:///") + buf.fs_loc.as_ref().expect("buffer has location").to_str().unwrap(),
+                "uri" => path_to_uri(buf.fs_loc.as_ref().expect("buffer has location")),
             },
-        }).unwrap();
+        });
     }
+}
 
-    pub fn process_notifications<F: FnMut(&JsonValue)>(&mut self, mut f: F) {
-        loop {
-            match self.notification_queue.lock().expect("lock notification queue").pop_front() {
-                Some(n) => {
-                    f(&n)
+impl Drop for LanguageServer {
+    fn drop(&mut self) {
+        if self.ps.is_some() {
+            self.request("shutdown", json::Null, |_, _| {});
+            self.notify("exit", json::Null);
+            // nothing will be polled now, so don't make the exit wait for an answer to initialize
+            if !self.initialized {
+                let held = ::std::mem::replace(&mut self.held, Vec::new());
+                self.request_queue.lock().expect("lock queue").extend(held);
+            }
+        } else {
+            // a server that was already running when it was connected to may have other clients,
+            // so it is left running and only the connection is closed
+            self.request_queue.lock().expect("lock queue").push_back(json::Null);
+        }
+        if let Some(ref mut ps) = self.ps {
+            // a server that hasn't exited by the time a request would have timed out, like one
+            // that has hung, is killed
+            let deadline = Instant::now() + self.timeout;
+            while let Ok(None) = ps.try_wait() {
+                if Instant::now() >= deadline {
+                    let _ = ps.kill();
+                    let _ = ps.wait();
+                    break;
                 }
-                None => break
+                thread::sleep(Duration::from_millis(10));
             }
         }
+        if let Some(t) = self.response_thread.take() {
+            t.join().expect("join response thread");
+        }
     }
 }
 
-impl Drop for LanguageServer {
-    fn drop(&mut self) {
-        self.send("shutdown", json::Null).expect("send shutdown");
-        self.send("exit", json::Null).expect("send exit");
-        if let Some(t) = self.response_thread.take() {
-            t.join().expect("join response thread");
+// a server that crashes is started again after this long, doubling with each crash in a row up to
+// the maximum, until it has crashed too many times in a row
+const RESTART_DELAY_MS: u64 = 500;
+const MAX_RESTART_DELAY_MS: u64 = 30_000;
+const MAX_CRASHES: u32 = 8;
+// a server that has run this long has stopped crashing in a row
+const STABLE_RUN_SECS: u64 = 60;
+// how many messages :lsp log keeps, unless the config says otherwise
+const DEFAULT_LOG_SIZE: usize = 1000;
+
+/// a [[language-server]] from the config, and the process running it if there is one
+pub struct ServerSlot {
+    pub name: String,
+    file_types: Regex,
+    config: TomlValue,
+    pub server: Option<Rc<RefCell<LanguageServer>>>,
+    // stopped by :lsp stop or after crashing too often, so only :lsp restart starts it again
+    stopped: bool,
+    crashes: u32,
+    started: Instant,
+    restart_at: Option<Instant>,
+    // why it last went away, for :lsp info
+    last_exit: Option<String>,
+    /// the messages sent to and received from the server, through restarts, for :lsp log
+    pub log: Arc<Mutex<TrafficLog>>
+}
+
+impl ServerSlot {
+    /// a slot for each [[language-server]] in the config. None of them are started yet
+    pub fn from_config(config: Option<&TomlValue>) -> SResult<Vec<ServerSlot>, Box<Error>> {
+        let cfgs = match config.and_then(|c| c.get("language-server")) {
+            Some(c) => c.as_array().ok_or(ConfigError::Invalid("language servers"))?,
+            None => return Ok(Vec::new())
+        };
+        cfgs.iter().map(|cfg| {
+            let file_types = Regex::new(cfg.get("file-extention").ok_or(ConfigError::Missing("language server file extention regex"))?
+                .as_str().ok_or(ConfigError::Invalid("language server file extention regex"))?)?;
+            let name = cfg.get("name").or(cfg.get("cmd")).or(cfg.get("tcp")).or(cfg.get("socket"))
+                .ok_or(ConfigError::Missing("language server command"))?
+                .as_str().ok_or(ConfigError::Invalid("language server name"))?;
+            let log_size = match cfg.get("log-size") {
+                Some(n) => n.as_integer().filter(|&n| n > 0).ok_or(ConfigError::Invalid("language server log size"))? as usize,
+                None => DEFAULT_LOG_SIZE
+            };
+            let trace = match cfg.get("trace-file") {
+                Some(p) => Some(Path::new(p.as_str().ok_or(ConfigError::Invalid("language server trace file"))?)),
+                None => None
+            };
+            Ok(ServerSlot {
+                name: String::from(name), file_types, config: cfg.clone(), server: None,
+                stopped: false, crashes: 0, started: Instant::now(), restart_at: None, last_exit: None,
+                log: Arc::new(Mutex::new(TrafficLog::new(log_size, trace)?))
+            })
+        }).collect()
+    }
+
+    /// is this server for files with this extension?
+    pub fn handles(&self, file_ext: &str) -> bool {
+        self.file_types.is_match(file_ext)
+    }
+
+    /// should the server be started when a file it handles is opened? Not if it was stopped, or
+    /// is waiting to be restarted
+    pub fn can_start(&self) -> bool {
+        self.server.is_none() && !self.stopped && self.restart_at.is_none()
+    }
+
+    /// start the server process, instead of any restart that was waiting
+    pub fn start(&mut self) -> SResult<Rc<RefCell<LanguageServer>>, Box<Error>> {
+        self.restart_at = None;
+        self.stopped = false;
+        // a server that fails to start counts as one that crashed straight away
+        self.started = Instant::now();
+        let ls = Rc::new(RefCell::new(LanguageServer::new(&self.config, self.log.clone())?));
+        self.server = Some(ls.clone());
+        Ok(ls)
+    }
+
+    /// take the server away to be shut down. It stays down until it is started again
+    pub fn stop(&mut self) -> Option<Rc<RefCell<LanguageServer>>> {
+        self.stopped = true;
+        self.restart_at = None;
+        self.crashes = 0;
+        self.server.take()
+    }
+
+    /// count a crash, or a failure to start, and plan when to start the server again
+    pub fn crashed(&mut self, why: String) {
+        if self.started.elapsed() >= Duration::from_secs(STABLE_RUN_SECS) { self.crashes = 0; }
+        self.crashes += 1;
+        self.last_exit = Some(why);
+        if self.crashes >= MAX_CRASHES {
+            self.stopped = true;
+        } else {
+            let delay = (RESTART_DELAY_MS << (self.crashes - 1)).min(MAX_RESTART_DELAY_MS);
+            self.restart_at = Some(Instant::now() + Duration::from_millis(delay));
         }
-        self.ps.wait().expect("server terminates"); // some sort of error if not sucessful?
+    }
+
+    /// if the server has exited by itself, take it away and plan its restart. Returns the server
+    /// that exited, so that it can be taken away from the buffers too
+    pub fn check_exited(&mut self) -> Option<Rc<RefCell<LanguageServer>>> {
+        let status = self.server.as_ref()?.borrow().exit_status()?;
+        self.crashed(status);
+        self.server.take()
+    }
+
+    /// is a restart waiting, and is it time for it?
+    pub fn restart_due(&self) -> bool {
+        self.restart_at.map_or(false, |t| t <= Instant::now())
+    }
+
+    /// what the server is doing, for :lsp info and the status line
+    pub fn status(&self) -> String {
+        let exit = self.last_exit.as_ref().map_or(String::new(), |e| format!(" ({})", e));
+        match (&self.server, self.restart_at) {
+            (&Some(ref ls), _) => String::from(if ls.borrow().is_initialized() { "running" } else { "starting" }),
+            (&None, Some(t)) => {
+                let wait = t.saturating_duration_since(Instant::now());
+                format!("restarting in {}.{}s after crash {}{}", wait.as_secs(), wait.subsec_millis() / 100, self.crashes, exit)
+            },
+            (&None, None) if self.stopped && self.crashes >= MAX_CRASHES => format!("gave up after {} crashes{}", self.crashes, exit),
+            (&None, None) if self.stopped => String::from("stopped"),
+            (&None, None) => String::from("not started")
+        }
+    }
+}
+
+#[cfg(all(test, any(target_os="macos", target_os="linux")))]
+mod tests {
+    use super::*;
+    use toml::value::Table;
+
+    // a language server that answers the way the tests want. It reads each message's headers a
+    // line at a time, then its content with dd. As soon as it is asked to initialize it sends two
+    // requests of its own, the second of which the client doesn't know
+    const FAKE_SERVER: &str = r#"
+cr=$(printf '\r')
+send() { printf 'Content-Length: %d\r\n\r\n%s' "${#1}" "$1"; }
+while IFS= read -r line; do
+    case "$line" in
+        Content-Length:*) len=$(printf '%s' "$line" | tr -dc 0-9) ;;
+        "$cr")
+            msg=$(dd bs=1 count="$len" 2>/dev/null)
+            method=$(printf '%s\n' "$msg" | sed -n 's/.*"method":"\([^"]*\)".*/\1/p')
+            id=$(printf '%s\n' "$msg" | sed -n 's/^{"jsonrpc":"2.0","id":\([0-9]*\),.*/\1/p')
+            params=$(printf '%s\n' "$msg" | sed -n 's/.*"params":\(.*\)}$/\1/p')
+            case "$method" in
+                initialize)
+                    send '{"jsonrpc":"2.0","id":100,"method":"workspace/configuration","params":{"items":[{"section":"fake.width"},{}]}}'
+                    send '{"jsonrpc":"2.0","id":101,"method":"fake/unknown","params":null}'
+                    send "{\"jsonrpc\":\"2.0\",\"id\":$id,\"result\":{\"capabilities\":{\"hoverProvider\":true},\"serverInfo\":{\"name\":\"fake\"}}}" ;;
+                test/echo) send "{\"jsonrpc\":\"2.0\",\"id\":$id,\"result\":$params}" ;;
+                shutdown) send "{\"jsonrpc\":\"2.0\",\"id\":$id,\"result\":null}" ;;
+                exit) exit 0 ;;
+            esac ;;
+    esac
+done
+"#;
+
+    // requests time out after `timeout` milliseconds
+    fn fake_server(timeout: i64) -> (LanguageServer, Arc<Mutex<TrafficLog>>) {
+        start_script(FAKE_SERVER, timeout)
+    }
+
+    // a server that is a shell script
+    fn start_script(script: &str, timeout: i64) -> (LanguageServer, Arc<Mutex<TrafficLog>>) {
+        let mut settings = Table::new();
+        let mut fake = Table::new();
+        fake.insert(String::from("width"), TomlValue::Integer(80));
+        settings.insert(String::from("fake"), TomlValue::Table(fake));
+        let mut config = Table::new();
+        config.insert(String::from("cmd"), TomlValue::String(String::from("sh")));
+        config.insert(String::from("args"), TomlValue::Array(vec![TomlValue::String(String::from("-c")), TomlValue::String(String::from(script))]));
+        config.insert(String::from("language-id"), TomlValue::String(String::from("fake")));
+        config.insert(String::from("timeout"), TomlValue::Integer(timeout));
+        config.insert(String::from("settings"), TomlValue::Table(settings));
+        let log = Arc::new(Mutex::new(TrafficLog::new(1000, None).unwrap()));
+        (LanguageServer::new(&TomlValue::Table(config), log.clone()).expect("start fake server"), log)
+    }
+
+    // poll until `done` is true of something that was dispatched, returning everything dispatched
+    fn poll_until<F: FnMut(&Dispatch) -> bool>(ls: &mut LanguageServer, mut done: F) -> Vec<Dispatch> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut out = Vec::new();
+        while Instant::now() < deadline {
+            for d in ls.poll() {
+                let stop = done(&d);
+                out.push(d);
+                if stop { return out; }
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("gave up waiting for the fake server");
+    }
+
+    fn wait_for_log(log: &Arc<Mutex<TrafficLog>>, text: &str) -> String {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            let t = log.lock().unwrap().text();
+            if t.contains(text) { return t; }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("{:?} never appeared in the log:\n{}", text, log.lock().unwrap().text());
+    }
+
+    fn response(d: &Dispatch) -> Option<&SResult<JsonValue, ResponseError>> {
+        match d { &Dispatch::Response(_, ref r) => Some(r), _ => None }
+    }
+
+    #[test]
+    fn held_until_initialized() {
+        let (mut ls, log) = fake_server(5000);
+        ls.request("test/echo", object!{ "n" => 1 }, |_, _| {});
+        thread::sleep(Duration::from_millis(200));
+        assert!(!log.lock().unwrap().text().contains("test/echo"), "sent before initialize was answered");
+        let out = poll_until(&mut ls, |d| response(d).is_some());
+        assert!(ls.is_initialized());
+        assert!(ls.capabilities.hover);
+        assert_eq!(ls.server_info.as_ref().map(|s| s.as_str()), Some("fake"));
+        assert_eq!(response(out.last().unwrap()).unwrap().as_ref().unwrap(), &object!{ "n" => 1 });
+        let text = log.lock().unwrap().text();
+        assert!(text.find("<-- initialize").unwrap() < text.find("--> test/echo").unwrap());
+    }
+
+    fn initialized(ls: &mut LanguageServer) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !ls.is_initialized() {
+            assert!(Instant::now() < deadline, "the fake server wasn't initialized");
+            ls.poll();
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn responses_go_to_their_requests() {
+        let (mut ls, _log) = fake_server(5000);
+        initialized(&mut ls);
+        ls.request("test/echo", object!{ "n" => 1 }, |_, _| {});
+        ls.request("test/echo", object!{ "n" => 2 }, |_, _| {});
+        let mut n = 0;
+        let out = poll_until(&mut ls, |d| { if response(d).is_some() { n += 1; } n == 2 });
+        let results = out.iter().filter_map(response).map(|r| r.as_ref().unwrap()["n"].as_usize()).collect::<Vec<_>>();
+        assert_eq!(results, vec![Some(1), Some(2)]);
+    }
+
+    #[test]
+    fn hung_server_is_killed() {
+        // never reads anything, so never answers initialize or shutdown
+        let (ls, _log) = start_script("exec sleep 30", 300);
+        let start = Instant::now();
+        drop(ls);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn timed_out() {
+        let (mut ls, log) = fake_server(300);
+        initialized(&mut ls);
+        let id = ls.request("test/unanswered", json::Null, |_, _| {});
+        let start = Instant::now();
+        let out = poll_until(&mut ls, |d| response(d).is_some());
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        match response(out.last().unwrap()) { Some(&Err(ResponseError::TimedOut)) => {}, r => panic!("expected a timeout, got {:?}", r) }
+        // the server is told to give up on it too
+        wait_for_log(&log, &format!("\"method\":\"$/cancelRequest\",\"params\":{{\"id\":{}}}", id));
+    }
+
+    #[test]
+    fn cancelled() {
+        let (mut ls, log) = fake_server(5000);
+        initialized(&mut ls);
+        let id = ls.request("test/unanswered", json::Null, |_, _| {});
+        ls.cancel(id);
+        let out = poll_until(&mut ls, |d| response(d).is_some());
+        match response(out.last().unwrap()) { Some(&Err(ResponseError::Cancelled)) => {}, r => panic!("expected a cancellation, got {:?}", r) }
+        wait_for_log(&log, &format!("\"method\":\"$/cancelRequest\",\"params\":{{\"id\":{}}}", id));
+    }
+
+    #[test]
+    fn connection_closed_without_exit() {
+        use std::net::TcpListener;
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut config = Table::new();
+        config.insert(String::from("tcp"), TomlValue::String(format!("{}", listener.local_addr().unwrap())));
+        config.insert(String::from("language-id"), TomlValue::String(String::from("fake")));
+        let log = Arc::new(Mutex::new(TrafficLog::new(1000, None).unwrap()));
+        let ls = LanguageServer::new(&TomlValue::Table(config), log).expect("connect to fake server");
+        let (mut server, _) = listener.accept().unwrap();
+        drop(ls);
+        // the server sees the connection close, without being told to shut down
+        let mut received = String::new();
+        server.read_to_string(&mut received).unwrap();
+        assert!(received.contains("\"initialize\""));
+        assert!(!received.contains("\"shutdown\"") && !received.contains("\"exit\""), "{}", received);
+    }
+
+    #[test]
+    fn server_requests() {
+        let (mut ls, log) = fake_server(5000);
+        initialized(&mut ls);
+        // workspace/configuration is answered from the settings table, and anything unknown with
+        // an error
+        wait_for_log(&log, r#""id":100,"result":[80,{"fake":{"width":80}}]"#);
+        wait_for_log(&log, r#""id":101,"error":{"code":-32601"#);
     }
 }
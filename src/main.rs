@@ -3,7 +3,6 @@
 #![feature(slice_patterns)]
 extern crate runic;
 extern crate winit;
-extern crate futures;
 extern crate toml;
 #[macro_use]
 extern crate json;
@@ -21,6 +20,17 @@ mod res;
 mod app;
 mod movement;
 mod lsp;
+mod jsonrpc;
+mod traffic;
+mod undo;
+mod rope;
+mod ex;
+mod textobject;
+mod keymap;
+mod completion;
+mod signature;
+mod popup;
+mod highlight;
 //mod fs_util;
 
 use runic::*;
@@ -1,15 +1,19 @@
 
 use super::*;
-use winit::*;
 use std::rc::Rc;
 use std::cell::RefCell;
 use buffer::Buffer;
 use std::path::Path;
+use app::ClipstackId;
+use ex::{Range, Substitute, split_delimited};
+use regex::Regex;
+use lsp::symbol_kind_name;
 
 #[derive(Debug)]
 pub enum CommandError {
     UnknownCommand,
-    InvalidCommand(Option<&'static str>)
+    InvalidCommand(Option<&'static str>),
+    PatternNotFound
 }
 
 impl Error for CommandError {
@@ -17,7 +21,8 @@ impl Error for CommandError {
         use self::CommandError::*;
         match self {
             &UnknownCommand => "Unknown command",
-            &InvalidCommand(ref desc) => desc.unwrap_or("Invalid command") 
+            &InvalidCommand(ref desc) => desc.unwrap_or("Invalid command"),
+            &PatternNotFound => "pattern not found"
         }
     }
 }
@@ -32,6 +37,41 @@ impl fmt::Display for CommandError {
     }
 }
 
+// :s followed by a delimiter, rather than a command that starts with s
+fn is_substitute(cmd: &str) -> bool {
+    cmd.starts_with('s') && cmd[1..].chars().next().map(|c| !c.is_alphanumeric() && !c.is_whitespace()).unwrap_or(false)
+}
+
+// :g/pattern/cmd, :g!/pattern/cmd or :v/pattern/cmd, returning whether matching lines are skipped
+// instead and the rest of the command
+fn global_command(cmd: &str) -> Option<(bool, &str)> {
+    let cmd = cmd.trim_left();
+    let (invert, rest) = if cmd.starts_with("g!") { (true, &cmd[2..]) }
+        else if cmd.starts_with('g') { (false, &cmd[1..]) }
+        else if cmd.starts_with('v') { (true, &cmd[1..]) }
+        else { return None };
+    match rest.chars().next() {
+        Some(c) if !c.is_alphanumeric() && !c.is_whitespace() => Some((invert, rest)),
+        _ => None
+    }
+}
+
+// :normal or :norm followed by keys
+fn normal_command(cmd: &str) -> Option<&str> {
+    let cmd = cmd.trim_left();
+    let word_end = cmd.find(char::is_whitespace).unwrap_or(cmd.len());
+    match &cmd[..word_end] {
+        "normal" | "norm" => Some(cmd[word_end..].trim_left()),
+        _ => None
+    }
+}
+
+fn takes_range(cmd: &str) -> bool {
+    let cmd = cmd.trim();
+    is_substitute(cmd) || normal_command(cmd).is_some() || cmd == "d" || cmd == "y" || cmd == ">" || cmd == "<"
+        || cmd == "codeaction"
+}
+
 pub struct CommandMode {
     inserter: InsertMode
 }
@@ -42,10 +82,240 @@ impl CommandMode {
         CommandMode { inserter: InsertMode::new_with_target(0) }
     }
 
+    /// start command mode with some text already typed in
+    pub fn new_with_text(app: &mut app::State, text: &str) -> CommandMode {
+        let cm = CommandMode::new(app);
+        {
+            let mut cmd = app.bufs[0].borrow_mut();
+            cmd.insert_string(&String::from(text));
+        }
+        cm
+    }
+
+    /// run :s on the lines first..=last as one undoable step
+    fn substitute(args: &str, first: usize, last: usize, app: &mut app::State) -> Result<Option<Box<Mode>>, Box<Error>> {
+        let sub = Substitute::parse(args, app.search_history.last())?;
+        // the pattern becomes the last search, like it would in vim
+        if app.search_history.last() != Some(&sub.pattern) {
+            app.search_history.push(sub.pattern.clone());
+        }
+        app.search_regex = Some(sub.re.clone());
+        app.mutate_buf(|b| b.begin_edit_group());
+        if sub.confirm {
+            return Ok(Some(Box::new(ConfirmMode::new(sub, (0, first), last, app)?)));
+        }
+        let (count, line) = app.mutate_buf(|b| sub.replace_all(b, (0, first), last));
+        app.mutate_buf(|b| {
+            if let Some(line) = line { b.place_cursor(0, line); }
+            b.end_edit_group();
+        });
+        if count == 0 {
+            Err(Box::new(CommandError::PatternNotFound))
+        } else {
+            Ok(Some(Box::new(NormalMode::new())))
+        }
+    }
+
+    /// mark the lines in first..=last that match (or with `invert`, don't match) a pattern, then run
+    /// a command on each of them that is still there, as one undoable step
+    fn global(args: &str, invert: bool, first: usize, last: usize, app: &mut app::State) -> Result<Option<Box<Mode>>, Box<Error>> {
+        let delim = args.chars().next().unwrap();
+        let (pattern, cmd) = split_delimited(&args[delim.len_utf8()..], delim);
+        let pattern = if pattern.len() > 0 { pattern } else {
+            app.search_history.last().cloned().ok_or(Box::new(CommandError::InvalidCommand(Some("no previous search"))))?
+        };
+        let cmd = cmd.trim();
+        if global_command(cmd).is_some() {
+            return Err(Box::new(CommandError::InvalidCommand(Some("cannot nest :g"))));
+        }
+        let re = Regex::new(&pattern)?;
+        // like :s, the pattern becomes the last search
+        if app.search_history.last() != Some(&pattern) {
+            app.search_history.push(pattern.clone());
+        }
+        app.search_regex = Some(re.clone());
+
+        let anchors = app.mutate_buf(|b| {
+            let lines = (first..(last+1)).filter(|&l| re.is_match(&b.line(l)) != invert).collect::<Vec<_>>();
+            lines.into_iter().map(|l| b.add_line_anchor(l)).collect::<Vec<_>>()
+        });
+        app.mutate_buf(|b| b.begin_edit_group());
+        let mut r = Ok(());
+        // whether the command has found what it was looking for on any line
+        let mut found = false;
+        for &id in anchors.iter() {
+            // lines deleted by the command on an earlier line are skipped
+            let line = match app.buf().borrow().line_anchor(id) {
+                Some(l) => l,
+                None => continue
+            };
+            app.mutate_buf(|b| b.place_cursor(0, line));
+            if cmd.len() == 0 { continue; }
+            match CommandMode::run(cmd, app) {
+                Ok(Some(mut m)) => { found = true; m.leave(app) },
+                Ok(None) => found = true,
+                Err(e) => {
+                    // like in vim, a :s with nothing to replace on one line carries on to the next
+                    let not_found = match e.downcast_ref::<CommandError>() { Some(&CommandError::PatternNotFound) => true, _ => false };
+                    if !not_found { r = Err(e); break; }
+                }
+            }
+        }
+        if r.is_ok() && !found && cmd.len() > 0 && anchors.len() > 0 {
+            r = Err(Box::new(CommandError::PatternNotFound) as Box<Error>);
+        }
+        app.mutate_buf(|b| {
+            for &id in anchors.iter() { b.remove_line_anchor(id); }
+            b.end_edit_group();
+        });
+        r.map(|_| Some(Box::new(NormalMode::new()) as Box<Mode>))
+    }
+
+    /// run Normal mode keys with the cursor at the start of each line in first..=last
+    fn normal(keys: &str, first: usize, last: usize, app: &mut app::State) -> Result<Option<Box<Mode>>, Box<Error>> {
+        let anchors = app.mutate_buf(|b| (first..(last+1)).map(|l| b.add_line_anchor(l)).collect::<Vec<_>>());
+        app.mutate_buf(|b| b.begin_edit_group());
+        let mut r = Ok(());
+        for &id in anchors.iter() {
+            let line = match app.buf().borrow().line_anchor(id) {
+                Some(l) => l,
+                None => continue
+            };
+            app.mutate_buf(|b| b.place_cursor(0, line));
+            if let Err(e) = NormalMode::run_keys(keys, app) { r = Err(e); break; }
+        }
+        app.mutate_buf(|b| {
+            for &id in anchors.iter() { b.remove_line_anchor(id); }
+            b.end_edit_group();
+        });
+        r.map(|_| Some(Box::new(NormalMode::new()) as Box<Mode>))
+    }
+
+    /// list the diagnostics of every buffer in a buffer of their own, where Return on a line jumps
+    /// to the problem. The list is reused each time
+    fn diagnostics(app: &mut app::State) -> Result<Option<Box<Mode>>, Box<Error>> {
+        let cd = ::std::env::current_dir()?;
+        let mut text = String::new();
+        let mut locations = Vec::new();
+        for b in app.bufs.iter() {
+            let b = b.borrow();
+            let path = match b.fs_loc { Some(ref p) => p, None => continue };
+            for d in b.diagnostics.iter() {
+                text.push_str(&format!("{}:{}:{}: {}: {}\n", path.strip_prefix(&cd).unwrap_or(path).display(),
+                    d.start.1+1, d.start.0+1, d.severity, d.message.lines().next().unwrap_or("")));
+                locations.push(Some((path.clone(), d.start)));
+            }
+        }
+        if locations.len() == 0 {
+            return Err(Box::new(CommandError::InvalidCommand(Some("no diagnostics"))));
+        }
+        app.show_locations(&text, locations);
+        Ok(Some(Box::new(NormalMode::new())))
+    }
+
+    /// pick one of the current buffer's symbols from its outline, and jump to it
+    fn symbols(app: &mut app::State) -> Result<Option<Box<Mode>>, Box<Error>> {
+        let (items, locs): (Vec<String>, Vec<(usize, usize)>) = {
+            let b = app.buf();
+            let b = b.borrow();
+            b.symbol_outline().iter().map(|&(depth, s)| {
+                let detail = s.detail.as_ref().map_or(String::new(), |d| format!("  {}", d.lines().next().unwrap_or("")));
+                (format!("{}{} {}{}", "  ".repeat(depth), symbol_kind_name(s.kind), s.name, detail), s.name_at)
+            }).unzip()
+        };
+        if items.len() == 0 {
+            return Err(Box::new(CommandError::InvalidCommand(Some("no symbols"))));
+        }
+        Ok(Some(Box::new(PickerMode::new(app, "symbol:", items, Box::new(move |i, app| {
+            app.push_jump();
+            app.mutate_buf(|b| b.place_cursor(locs[i].0, locs[i].1));
+            Ok(None)
+        })))))
+    }
+
+    /// run a command that applies to the lines first..=last
+    fn execute_on_lines(cmd: &str, first: usize, last: usize, app: &mut app::State) -> Result<Option<Box<Mode>>, Box<Error>> {
+        let cmd = cmd.trim();
+        if let Some((invert, args)) = global_command(cmd) {
+            return CommandMode::global(args, invert, first, last, app);
+        }
+        if let Some(keys) = normal_command(cmd) {
+            return CommandMode::normal(keys, first, last, app);
+        }
+        if cmd.len() == 0 { // just a range jumps to its last line
+            app.push_jump();
+            app.mutate_buf(|b| b.place_cursor(0, last));
+            return Ok(Some(Box::new(NormalMode::new())));
+        }
+        if is_substitute(cmd) {
+            return CommandMode::substitute(&cmd[1..], first, last, app);
+        }
+        // these ask the language server, and change the buffer when it answers
+        match cmd {
+            "format" => { app.format(Some((first, last)))?; return Ok(Some(Box::new(NormalMode::new()))); },
+            "codeaction" => { app.code_actions(first, last)?; return Ok(Some(Box::new(NormalMode::new()))); },
+            _ => {}
+        }
+        app.mutate_buf(|b| b.begin_edit_group());
+        let r: Result<(), Box<Error>> = match cmd {
+            "d" => {
+                let v = app.mutate_buf(|b| {
+                    let v = (first..(last+1)).map(|i| b.line(i) + "\n").collect::<String>();
+                    b.delete_lines(first, last+1);
+                    b.place_cursor(0, first);
+                    v
+                });
+                app.push_clip(&ClipstackId('"'), v);
+                Ok(())
+            },
+            "y" => {
+                let v = app.mutate_buf(|b| (first..(last+1)).map(|i| b.line(i) + "\n").collect::<String>());
+                app.push_clip(&ClipstackId('"'), v);
+                Ok(())
+            },
+            ">" => { app.mutate_buf(|b| b.indent_lines(first, last, false)); Ok(()) },
+            "<" => { app.mutate_buf(|b| b.indent_lines(first, last, true)); Ok(()) },
+            _ => Err(Box::new(CommandError::InvalidCommand(Some("command does not take a range"))))
+        };
+        app.mutate_buf(|b| b.end_edit_group());
+        r.map(|_| Some(Box::new(NormalMode::new()) as Box<Mode>))
+    }
+
+    /// run the command that has been typed into the command line
     pub fn execute(&self, app: &mut app::State) -> Result<Option<Box<Mode>>, Box<Error>> {
-        let _cmd = { 
-            app.bufs[0].borrow().lines.last().unwrap().clone()
+        let cmd = {
+            let mut b = app.bufs[0].borrow_mut();
+            let cmd = b.line(b.line_count()-1);
+            b.show_cursor = false;
+            b.clear();
+            cmd
+        };
+        CommandMode::run(&cmd, app)
+    }
+
+    pub fn run(_cmd: &str, app: &mut app::State) -> Result<Option<Box<Mode>>, Box<Error>> {
+        let (range, rest) = Range::parse(_cmd)?;
+        let lines = {
+            let b = app.buf();
+            let b = b.borrow();
+            match range {
+                Some(r) => Some(r.resolve(&b, app.search_history.last())?),
+                None => None
+            }
         };
+        match lines {
+            Some((first, last)) => return CommandMode::execute_on_lines(rest, first, last, app),
+            // :g defaults to the whole buffer, other commands that take a range to the current line
+            None if global_command(rest).is_some() => {
+                let last = app.buf().borrow().line_count()-1;
+                return CommandMode::execute_on_lines(rest, 0, last, app);
+            },
+            None if takes_range(rest) => {
+                let cur = app.buf().borrow().curr_loc().1;
+                return CommandMode::execute_on_lines(rest, cur, cur, app);
+            },
+            None => {}
+        }
         let mut cmd = _cmd.split_whitespace(); 
         let first_word = match cmd.next() {
             Some(s) => s,
@@ -65,6 +335,42 @@ impl CommandMode {
                 app.move_to_buffer(ix);
                 Ok(Some(Box::new(NormalMode::new())))
             },
+            // every file, including hidden ones changed by :rename and the like
+            "wa" => {
+                for b in app.bufs.iter() {
+                    let mut b = b.borrow_mut();
+                    if b.fs_loc.is_some() { b.sync_disk()?; }
+                }
+                Ok(Some(Box::new(NormalMode::new())))
+            },
+            "diagnostics" => CommandMode::diagnostics(app),
+            "symbols" => CommandMode::symbols(app),
+            "wsymbols" => {
+                app.workspace_symbols(&cmd.collect::<Vec<_>>().join(" "))?;
+                Ok(Some(Box::new(NormalMode::new())))
+            },
+            "lsp" => {
+                match (cmd.next(), cmd.next()) {
+                    (Some("info"), None) | (None, None) => app.show_language_servers(),
+                    (Some("restart"), name) => app.restart_language_servers(name)?,
+                    (Some("stop"), name) => app.stop_language_servers(name)?,
+                    (Some("log"), name) => app.show_language_server_log(name)?,
+                    _ => return Err(Box::new(CommandError::InvalidCommand(Some("usage: :lsp info|restart|stop|log [server]"))))
+                }
+                Ok(Some(Box::new(NormalMode::new())))
+            },
+            "rename" => {
+                app.rename(cmd.next().ok_or(Box::new(CommandError::InvalidCommand(Some("missing new name"))))?)?;
+                Ok(Some(Box::new(NormalMode::new())))
+            },
+            "format" => {
+                app.format(None)?;
+                Ok(Some(Box::new(NormalMode::new())))
+            },
+            "noh" | "nohlsearch" => {
+                app.search_regex = None;
+                Ok(Some(Box::new(NormalMode::new())))
+            },
             "cd" => {
                 ::std::env::set_current_dir(cmd.next().ok_or(Box::new(CommandError::InvalidCommand(Some("missing path"))))?)?;
                 Ok(Some(Box::new(NormalMode::new())))
@@ -108,30 +414,48 @@ impl CommandMode {
 }
 
 impl Mode for CommandMode {
-    fn event(&mut self, e: WindowEvent, app: &mut app::State) -> Result<Option<Box<Mode>>, Box<Error>> {
-        match e {
-            WindowEvent::KeyboardInput { input: k, .. } => 
-                match k.virtual_keycode {
-                    Some(VirtualKeyCode::Return) => {
-                        let r = self.execute(app);
-                        let mut buf_ = &app.bufs[0];
-                        let mut buf = buf_.borrow_mut();
-                        let len = buf.lines.len();
-                        buf.show_cursor = false;
-                        buf.clear();
-                        r
-                    }
-                    Some(VirtualKeyCode::Escape) => {
-                        let mut buf_ = &app.bufs[0];
-                        let mut buf = buf_.borrow_mut();
-                        buf.show_cursor = false;
-                        buf.clear();
-                        Ok(Some(Box::new(NormalMode::new())))
-                    }
-                    _ => self.inserter.event(e, app),
-                }
-            _ => self.inserter.event(e, app),
+    fn event(&mut self, k: Key, app: &mut app::State) -> Result<Option<Box<Mode>>, Box<Error>> {
+        match k {
+            Key::Return => self.execute(app),
+            Key::Escape => {
+                let mut buf_ = &app.bufs[0];
+                let mut buf = buf_.borrow_mut();
+                buf.show_cursor = false;
+                buf.clear();
+                Ok(Some(Box::new(NormalMode::new())))
+            }
+            _ => self.inserter.event(k, app),
         }
     }
+    fn leave(&mut self, app: &mut app::State) {
+        let mut buf = app.bufs[0].borrow_mut();
+        buf.show_cursor = false;
+        buf.clear();
+    }
+
     fn status_tag(&self) -> &str { "COMMAND" }
+    fn keymap(&self) -> &str { "command" }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn global_substitute_skips_lines_without_a_match() {
+        let mut app = app::State::with_text("foo bar\nfoo\nfoo bar\nbaz bar");
+        CommandMode::run("g/foo/s/bar/qux/", &mut app).unwrap();
+        assert_eq!(app.buf().borrow().full_text(), "foo qux\nfoo\nfoo qux\nbaz bar\n");
+        assert_eq!(app.search_history.last().map(|s| s.as_str()), Some("bar"));
+        // it's only an error if no line had a match
+        let e = CommandMode::run("g/foo/s/nope/x/", &mut app).err().unwrap();
+        assert_eq!(format!("{}", e), "pattern not found");
+        assert_eq!(app.buf().borrow().full_text(), "foo qux\nfoo\nfoo qux\nbaz bar\n");
+    }
+
+    #[test]
+    fn global_stops_at_other_errors() {
+        let mut app = app::State::with_text("a\na\na");
+        assert!(CommandMode::run("g/a/nosuchcommand", &mut app).is_err());
+    }
 }
@@ -0,0 +1,98 @@
+
+use super::*;
+use ex::Substitute;
+
+// Confirm mode: steps through the matches of a :s command with the c flag
+// y: replace this match
+// n: skip this match
+// a: replace this and every remaining match
+// l: replace this match and stop
+// q, Escape: stop
+// all of the replacements are undone together
+
+pub struct ConfirmMode {
+    sub: Substitute,
+    at: (usize, usize),
+    last: usize,
+    prompt: String
+}
+
+impl ConfirmMode {
+    /// start confirming the matches from `from` to the end of line `last`. The caller opens the edit
+    /// group, which is closed when the substitution is done
+    pub fn new(sub: Substitute, from: (usize, usize), last: usize, app: &mut app::State) -> Result<ConfirmMode, Box<Error>> {
+        let at = app.mutate_buf(|b| sub.next_match(b, from, last));
+        match at {
+            Some(at) => {
+                app.mutate_buf(|b| b.place_cursor(at.0, at.1));
+                let prompt = format!("replace with {} (y/n/a/q/l)?", sub.replacement);
+                Ok(ConfirmMode { sub, at, last, prompt })
+            },
+            None => {
+                app.mutate_buf(|b| b.end_edit_group());
+                Err(Box::new(CommandError::PatternNotFound))
+            }
+        }
+    }
+
+    fn finish(&self, app: &mut app::State) -> Box<Mode> {
+        app.mutate_buf(|b| b.end_edit_group());
+        Box::new(NormalMode::new())
+    }
+
+    // move to the next match from `from`, or leave the mode if there isn't one
+    fn advance(&mut self, from: (usize, usize), app: &mut app::State) -> Option<Box<Mode>> {
+        let (sub, last) = (&self.sub, self.last);
+        match app.mutate_buf(|b| sub.next_match(b, from, last)) {
+            Some(at) => {
+                self.at = at;
+                app.mutate_buf(|b| b.place_cursor(at.0, at.1));
+                None
+            },
+            None => Some(self.finish(app))
+        }
+    }
+}
+
+impl Mode for ConfirmMode {
+    fn event(&mut self, k: Key, app: &mut app::State) -> Result<Option<Box<Mode>>, Box<Error>> {
+        match k {
+            Key::Char(c) => {
+                let at = self.at;
+                match c {
+                    'y' | 'l' => {
+                        let (sub, last) = (&self.sub, &mut self.last);
+                        let next = app.mutate_buf(|b| sub.replace(b, at, last));
+                        if c == 'l' { Ok(Some(self.finish(app))) } else { Ok(self.advance(next, app)) }
+                    },
+                    'n' => {
+                        let sub = &self.sub;
+                        let next = app.mutate_buf(|b| sub.skip(b, at));
+                        Ok(self.advance(next, app))
+                    },
+                    'a' => {
+                        let (sub, last) = (&self.sub, self.last);
+                        app.mutate_buf(|b| {
+                            if let (_, Some(line)) = sub.replace_all(b, at, last) { b.place_cursor(0, line); }
+                        });
+                        Ok(Some(self.finish(app)))
+                    },
+                    'q' => Ok(Some(self.finish(app))),
+                    _ => Ok(None)
+                }
+            },
+            Key::Escape => {
+                Ok(Some(self.finish(app)))
+            },
+            _ => Ok(None)
+        }
+    }
+
+    fn leave(&mut self, app: &mut app::State) {
+        app.mutate_buf(|b| b.end_edit_group());
+    }
+
+    fn status_tag(&self) -> &str { "CONFIRM" }
+
+    fn pending_command(&self) -> Option<&str> { Some(&self.prompt) }
+}
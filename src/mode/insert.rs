@@ -1,65 +1,243 @@
 
 use super::*;
-use winit::*;
+use runic::{RenderContext, Rect};
 use movement::*;
+use buffer::Buffer;
+use std::rc::Rc;
+use std::cell::RefCell;
+use completion::Completion;
+use signature::SignatureHelp;
 
 pub struct InsertMode {
-    target_buffer: Option<usize>
+    target_buffer: Option<usize>,
+    block: Option<BlockInsert>,
+    // what has been typed, if this session is part of a change that `.` can repeat
+    typed: Option<String>,
+    // how many chars just before the cursor were typed this session, and so can be taken back off
+    // `typed` by Backspace
+    typed_here: usize,
+    completion: Option<Completion>,
+    signature: Option<SignatureHelp>
 }
 
-impl InsertMode {
-    pub fn new() -> InsertMode { InsertMode { target_buffer: None } }
-    pub fn new_with_target(target: usize) -> InsertMode { InsertMode { target_buffer: Some(target) } }
+/// an insert started from a block selection, which gets repeated on the rest of the block's lines
+/// when Insert mode ends
+struct BlockInsert {
+    first_line: usize,
+    last_line: usize,
+    col: usize, // in chars
+    start: usize // in bytes, on the first line
 }
 
-impl Mode for InsertMode {
-    fn event(&mut self, e: WindowEvent, app: &mut app::State) -> Result<Option<Box<Mode>>, Box<Error>> {
-        let mut buf_ = match self.target_buffer {
+impl InsertMode {
+    pub fn new() -> InsertMode { InsertMode { target_buffer: None, block: None, typed: None, typed_here: 0, completion: None, signature: None } }
+    pub fn new_with_target(target: usize) -> InsertMode { InsertMode { target_buffer: Some(target), block: None, typed: None, typed_here: 0, completion: None, signature: None } }
+    pub fn new_block(first_line: usize, last_line: usize, col: usize, start: usize) -> InsertMode {
+        InsertMode { target_buffer: None, block: Some(BlockInsert { first_line, last_line, col, start }), typed: None, typed_here: 0, completion: None, signature: None }
+    }
+    /// an insert session started by a change in Normal mode, which remembers what was typed so
+    /// that `.` can type it again
+    pub fn new_recorded() -> InsertMode { InsertMode { target_buffer: None, block: None, typed: Some(String::new()), typed_here: 0, completion: None, signature: None } }
+
+    fn target(&self, app: &app::State) -> Rc<RefCell<Buffer>> {
+        match self.target_buffer {
             Some(target) => app.bufs[target].clone(),
             None => app.buf(),
-        };
+        }
+    }
+
+    fn record(&mut self, app: &mut app::State) {
+        if let Some(typed) = self.typed.take() {
+            if let Some(ref mut change) = app.last_change {
+                change.inserted = Some(typed);
+            }
+        }
+    }
+
+    fn close_completion(&mut self) {
+        if let Some(c) = self.completion.take() { c.close(); }
+    }
+
+    fn close_signature(&mut self) {
+        if let Some(s) = self.signature.take() { s.close(); }
+    }
+
+    // end the insert session, which is one undoable edit
+    fn finish(&mut self, buf: &mut Buffer) {
+        self.close_completion();
+        self.close_signature();
+        let cloc = buf.curr_loc();
+        if let Some(ref b) = self.block {
+            // only simple inserts on the first line get copied to the rest of the block
+            if cloc.1 == b.first_line && cloc.0 > b.start {
+                let text = String::from(&buf.line(cloc.1)[(b.start)..(cloc.0)]);
+                for line in (b.first_line+1)..(b.last_line+1) {
+                    if let Some(col) = buf.byte_col(line, b.col) {
+                        buf.insert_text((col, line), &text);
+                    }
+                }
+            }
+        }
+        buf.end_edit_group();
+    }
+}
+
+impl Mode for InsertMode {
+    fn event(&mut self, k: Key, app: &mut app::State) -> Result<Option<Box<Mode>>, Box<Error>> {
+        let mut buf_ = self.target(app);
         let mut buf = buf_.borrow_mut();
         let cloc = buf.curr_loc();
 
-        match e {
-            WindowEvent::ReceivedCharacter(c) => {
-                if c.is_control() || ((c as u32) >= 0xf700 && (c as u32) < 0xf7ff) { Ok(None) } else {
-                    buf.insert_char(c);
-                    Ok(None)
+        // the completion menu takes some keys while it has something to pick
+        if let Some(mut c) = self.completion.take() {
+            if !c.update(&buf) {
+                c.close();
+            } else if c.has_matches() {
+                match k {
+                    Key::Ctrl('n') | Key::Down => { c.select(true); self.completion = Some(c); return Ok(None); },
+                    Key::Ctrl('p') | Key::Up => { c.select(false); self.completion = Some(c); return Ok(None); },
+                    Key::Tab | Key::Return => {
+                        c.accept(&mut buf);
+                        // like moving the cursor, only what is typed after this gets repeated
+                        if let Some(ref mut t) = self.typed { t.clear(); }
+                        self.typed_here = 0;
+                        return Ok(None);
+                    },
+                    Key::Ctrl('e') => { c.close(); return Ok(None); },
+                    _ => self.completion = Some(c)
+                }
+            } else {
+                self.completion = Some(c);
+            }
+        }
+
+        match k {
+            Key::Char(c) => {
+                buf.insert_char(c);
+                if let Some(ref mut t) = self.typed { t.push(c); }
+                self.typed_here += 1;
+                if Completion::is_trigger(&buf, c) {
+                    self.close_completion();
+                    self.completion = Completion::request(&mut buf, Some(c));
+                } else if let Some(comp) = self.completion.take() {
+                    self.completion = if c.is_alphanumeric() || c == '_' { comp.refresh(&mut buf) } else { comp.close(); None };
+                }
+                if SignatureHelp::is_trigger(&buf, c) {
+                    self.close_signature();
+                    self.signature = SignatureHelp::request(&mut buf, Some(c));
                 }
+                Ok(None)
             },
-            WindowEvent::KeyboardInput { input: KeyboardInput { virtual_keycode: Some(k), state: ElementState::Pressed, .. }, .. } => {
-                match k {
-                    VirtualKeyCode::Return => {
-                        buf.break_line();
-                        Ok(None)
-                    }
-                    VirtualKeyCode::Delete => {
-                        buf.delete_char();
-                        Ok(None)
-                    }
-                    VirtualKeyCode::Back => {
-                        if cloc.0 != 0 {
-                            buf.move_cursor((-1, 0));
-                            buf.delete_char();
-                        }
-                        Ok(None)
-                    }
-                    VirtualKeyCode::Tab => {
-                        buf.insert_tab();
-                        Ok(None)
+            Key::Ctrl(' ') => {
+                self.close_completion();
+                self.completion = Completion::request(&mut buf, None);
+                Ok(None)
+            },
+            Key::Return => {
+                buf.break_line();
+                if let Some(ref mut t) = self.typed { t.push('\n'); }
+                // the new line's indent wasn't typed
+                self.typed_here = 0;
+                Ok(None)
+            }
+            Key::Delete => {
+                buf.delete_char();
+                Ok(None)
+            }
+            Key::Backspace => {
+                if cloc.0 != 0 {
+                    buf.move_cursor((-1, 0));
+                    buf.delete_char();
+                    if self.typed_here > 0 {
+                        if let Some(ref mut t) = self.typed { t.pop(); }
+                        self.typed_here -= 1;
+                    } else if let Some(ref mut t) = self.typed {
+                        // text from before this session can't be typed again, so like moving,
+                        // only what is typed after this gets repeated
+                        t.clear();
                     }
-                    VirtualKeyCode::Up => { buf.make_movement(Movement::Line(true, Inclusion::Linewise)); Ok(None) }
-                    VirtualKeyCode::Down => { buf.make_movement(Movement::Line(false, Inclusion::Linewise)); Ok(None) }
-                    VirtualKeyCode::Left => { buf.make_movement(Movement::Char(false)); Ok(None) }
-                    VirtualKeyCode::Right => { buf.make_movement(Movement::Char(true)); Ok(None) }
-                    VirtualKeyCode::Escape => { Ok(Some(Box::new(NormalMode::new()))) }
-                    _ => Ok(None)
                 }
+                Ok(None)
+            }
+            Key::Tab => {
+                buf.insert_tab();
+                // a tab can be several spaces, each of which Backspace deletes on its own
+                let tab = String::from(&buf.line(cloc.1)[cloc.0..buf.curr_loc().0]);
+                self.typed_here += tab.chars().count();
+                if let Some(ref mut t) = self.typed { t.push_str(&tab); }
+                Ok(None)
+            }
+            Key::Up | Key::Down | Key::Left | Key::Right => {
+                buf.make_movement(match k {
+                    Key::Up => Movement::Line(true, Inclusion::Linewise),
+                    Key::Down => Movement::Line(false, Inclusion::Linewise),
+                    Key::Left => Movement::Char(false),
+                    _ => Movement::Char(true)
+                });
+                // like in vim, only what is typed after moving gets repeated
+                if let Some(ref mut t) = self.typed { t.clear(); }
+                self.typed_here = 0;
+                Ok(None)
+            }
+            Key::Escape => {
+                self.finish(&mut buf);
+                self.record(app);
+                Ok(Some(Box::new(NormalMode::new())))
             }
             _ => Ok(None)
         }
     }
 
+    fn leave(&mut self, app: &mut app::State) {
+        let buf = self.target(app);
+        self.finish(&mut buf.borrow_mut());
+        self.record(app);
+    }
+
+    fn paint(&mut self, rx: &mut RenderContext, app: &app::State, cursor: Option<Rect>) {
+        let buf = self.target(app);
+        let signature_open = match self.signature {
+            Some(ref mut s) => s.update(&buf.borrow()),
+            None => false
+        };
+        if !signature_open {
+            self.close_signature();
+        } else if let (Some(s), Some(cursor)) = (self.signature.as_ref(), cursor) {
+            s.paint(rx, app.res.borrow().font(), cursor);
+        }
+        let open = match self.completion {
+            Some(ref mut c) => c.update(&buf.borrow()),
+            None => return
+        };
+        if !open {
+            self.close_completion();
+        } else if let (Some(c), Some(cursor)) = (self.completion.as_ref(), cursor) {
+            c.paint(rx, app.res.borrow().font(), cursor);
+        }
+    }
+
     fn status_tag(&self) -> &str { "INSERT" }
+    fn keymap(&self) -> &str { "insert" }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn typed(text: &str, keys: &[Key]) -> String {
+        let mut app = app::State::with_text(text);
+        NormalMode::feed_keys(keys, &mut app).unwrap();
+        let text = app.buf().borrow().full_text();
+        text
+    }
+
+    #[test]
+    fn backspace() {
+        let (c, esc, ret, bs) = (Key::Char, Key::Escape, Key::Return, Key::Backspace);
+        // deleting what was typed takes it back off what `.` types
+        assert_eq!(typed("ab\nab", &[c('i'), c('x'), c('y'), bs, esc, c('j'), c('^'), c('.')]), "xab\nxab\n");
+        // but deleting a char from before, or the indent of a new line, leaves only what's typed after
+        assert_eq!(typed("ab\nab", &[c('$'), c('a'), bs, c('x'), esc, c('j'), c('^'), c('.')]), "ax\naxb\n");
+        assert_eq!(typed("  ab\nab", &[c('$'), c('a'), c('c'), ret, bs, c('x'), esc, c('j'), c('^'), c('.')]), "  abc\nx\naxb\n");
+    }
 }
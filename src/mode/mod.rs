@@ -2,17 +2,33 @@
 use runic;
 use winit;
 use app;
+use keymap::Key;
 use std::error::Error;
 
 pub trait Mode {
-    fn event(&mut self, e: winit::WindowEvent, app: &mut app::State) -> Result<Option<Box<Mode>>, Box<Error>>;
+    fn event(&mut self, k: Key, app: &mut app::State) -> Result<Option<Box<Mode>>, Box<Error>>;
     fn status_tag(&self) -> &str;
     fn pending_command(&self) -> Option<&str> { None }
+    /// leave the mode as if Escape had been pressed, for when keys are run by a command (:normal)
+    fn leave(&mut self, _app: &mut app::State) {}
+    /// which of the keymaps from config.toml applies in this mode, if any
+    fn keymap(&self) -> &str { "" }
+    /// draw anything the mode shows over the buffer, like a popup by the cursor. `cursor` is where
+    /// the cursor was drawn, if it was
+    fn paint(&mut self, _rx: &mut runic::RenderContext, _app: &app::State, _cursor: Option<runic::Rect>) {}
 }
 
 mod normal;
 mod insert;
 mod command;
-pub use self::normal::NormalMode;
+mod visual;
+mod search;
+mod confirm;
+mod picker;
+pub use self::normal::{NormalMode, LastChange};
 pub use self::insert::InsertMode;
-pub use self::command::CommandMode;
+pub use self::command::{CommandMode, CommandError};
+pub use self::visual::VisualMode;
+pub use self::search::SearchMode;
+pub use self::confirm::ConfirmMode;
+pub use self::picker::PickerMode;
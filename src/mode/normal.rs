@@ -1,7 +1,7 @@
 
 use super::*;
-use winit::{WindowEvent};
-use movement::Movement;
+use movement::{Movement, Parse, parse_count};
+use buffer::SelectionKind;
 use app::ClipstackId;
 
 //Normal Mode
@@ -17,55 +17,166 @@ pub struct NormalMode {
 // r[char]: replace char
 // [reg]y[mov]: yank (copy) text into reg
 // [reg]p: put text out of reg
+// [count].: repeat the last change, with a new count if one is given
+// q[reg]: record the keys typed into reg, until q is pressed again in Normal mode
+// [count]@[reg]: play the keys in reg back, as if they were typed. @@ plays the last one again
+// m[mark]: set a mark at the cursor. a-z are local to the buffer, A-Z are global
+// [count]Ctrl-O, [count]Ctrl-I (or Tab): go back/forward through the jump list
+// Return: in a list buffer like :diagnostics, go to the place the line refers to
+// gd, gD: go to the definition/declaration of the symbol under the cursor, from the language server
+// gr: list the references to the symbol under the cursor
+// K: show the language server's hover information for the symbol under the cursor
+// u: undo
+// Ctrl-R: redo
+// v, V, Ctrl-V: Visual mode (charwise, linewise, blockwise)
+// /, ?: search, also usable as a movement after d/c/y
+// d/c/y followed by i or a and a text object (diw, ca(, yit, ...) operate on that object
 // reg: '"' followed with a register name (one char)
 //    special registers:
 //        "* => the system clipboard
 //        "" => the register that gets the last yanked/deleted movement by default
 
 
-#[derive(Debug)]
-enum Action {
+#[derive(Debug, Clone)]
+pub enum Action {
     Move(Movement),
     Delete(Movement, ClipstackId),
     Change(Movement, ClipstackId),
     Insert, InsertLine, Append, Command,
     Replace(char),
     Yank(Movement, ClipstackId),
-    Put(ClipstackId, bool /* copy or pop */)
+    Put(ClipstackId, bool /* copy or pop */),
+    Undo, Redo,
+    Visual(SelectionKind),
+    Repeat(Option<usize>),
+    Record(ClipstackId),
+    SetMark(char),
+    Jump(bool /*back/forward*/, usize),
+    Definition(bool /* declaration */), References, Hover,
+    Play(Option<ClipstackId> /* None for @@ */, usize)
 }
 
+/// the last action that changed the buffer, for `.`
+#[derive(Debug, Clone)]
+pub struct LastChange {
+    pub action: Action,
+    /// the text typed in the Insert mode session the action started, once it has ended
+    pub inserted: Option<String>
+}
+
+// the same movement repeated `count` times, replacing any count it already had
+fn with_count(mv: &Movement, count: usize) -> Movement {
+    match mv {
+        &Movement::Rep(_, ref m) => Movement::Rep(count, m.clone()),
+        _ => Movement::Rep(count, Box::new(mv.clone()))
+    }
+}
+
+// how many macros can be playing at once, so one that plays itself can't run forever
+const MAX_MACRO_DEPTH: usize = 100;
+
 impl Action {
-    fn parse(s: &str) -> Option<Action> {
-        let mut cs = s.trim().char_indices().peekable();
+    /// parse `[count]["reg][count]op[count]motion`, or an action that doesn't take a motion.
+    /// All of the counts multiply together
+    fn parse(s: &str) -> Parse<Action> {
+        let (count1, s) = match parse_count(s) {
+            Some(c) => c,
+            None => return Parse::Invalid
+        };
         let mut reg = ClipstackId('"'); //default register is ""
-        if let Some(&(_, '"')) = cs.peek() {
-            if cs.next().is_none() { return None }
-            reg = match cs.next() {
-                Some((_, c)) => ClipstackId(c),
-                None => return None
-            };
-        }
-        match cs.next() {
-            Some((i, c)) => {
-                //println!("i,c {} {}", i, c);
+        let s = if s.starts_with('"') {
+            match s[1..].chars().next() {
+                Some(c) => { reg = ClipstackId(c); &s[1+c.len_utf8()..] },
+                None => return Parse::Incomplete
+            }
+        } else { s };
+        let (count2, s) = match parse_count(s) {
+            Some(c) => c,
+            None => return Parse::Invalid
+        };
+        let count = match (count1, count2) {
+            (Some(a), Some(b)) => match a.checked_mul(b) {
+                Some(n) => Some(n),
+                None => return Parse::Invalid
+            },
+            (a, b) => a.or(b)
+        };
+        // None if the counts multiply to more than fits
+        let counted = |mv: Movement| match count {
+            Some(n) => mv.times(n),
+            None => Some(mv)
+        };
+
+        let mut cs = s.chars();
+        let c = match cs.next() {
+            Some(c) => c,
+            None => return Parse::Incomplete
+        };
+        Parse::Complete(match c {
+            'd' | 'c' | 'y' => {
+                let mv = match Movement::parse(&s[1..], Some(c)) {
+                    Parse::Complete(mv) => match counted(mv) {
+                        Some(mv) => mv,
+                        None => return Parse::Invalid
+                    },
+                    Parse::Incomplete => return Parse::Incomplete,
+                    Parse::Invalid => return Parse::Invalid
+                };
                 match c {
-                    'i' => Some(Action::Insert),
-                    'a' => Some(Action::Append),
-                    'o' => Some(Action::InsertLine),
-                    ';' => Some(Action::Command),
-                    ':' => Some(Action::Command),
-                    'x' => Some(Action::Delete(Movement::Char(true), reg)),
-                    'd' => Movement::parse(s.split_at(i+1).1, false).map(|m| Action::Delete(m,reg)),
-                    'c' => Movement::parse(s.split_at(i+1).1, false).map(|m| Action::Change(m,reg)),
-                    'y' => Movement::parse(s.split_at(i+1).1, false).map(|m| Action::Yank(m,reg)),
-                    'p' => Some(Action::Put(reg, false)),
-                    'P' => Some(Action::Put(reg, true)),
-                    'r' => cs.next().map(|(_,c)| Action::Replace(c)),
-                    _ => Movement::parse(s, true).map(Action::Move),
+                    'd' => Action::Delete(mv, reg),
+                    'c' => Action::Change(mv, reg),
+                    _ => Action::Yank(mv, reg)
                 }
             },
-            None => None
-        }
+            'x' => match counted(Movement::Char(true)) {
+                Some(mv) => Action::Delete(mv, reg),
+                None => return Parse::Invalid
+            },
+            '.' => Action::Repeat(count),
+            'i' => Action::Insert,
+            'a' => Action::Append,
+            'o' => Action::InsertLine,
+            ';' | ':' => Action::Command,
+            'p' => Action::Put(reg, false),
+            'P' => Action::Put(reg, true),
+            'r' => match cs.next() {
+                Some(c) => Action::Replace(c),
+                None => return Parse::Incomplete
+            },
+            'q' => match cs.next() {
+                Some(c) => Action::Record(ClipstackId(c)),
+                None => return Parse::Incomplete
+            },
+            '@' => match cs.next() {
+                Some('@') => Action::Play(None, count.unwrap_or(1)),
+                Some(c) => Action::Play(Some(ClipstackId(c)), count.unwrap_or(1)),
+                None => return Parse::Incomplete
+            },
+            'm' => match cs.next() {
+                Some(c) if c.is_ascii_alphabetic() => Action::SetMark(c),
+                Some(_) => return Parse::Invalid,
+                None => return Parse::Incomplete
+            },
+            'g' => match cs.next() {
+                Some('d') => Action::Definition(false),
+                Some('D') => Action::Definition(true),
+                Some('r') => Action::References,
+                Some(_) => return Parse::Invalid,
+                None => return Parse::Incomplete
+            },
+            'K' => Action::Hover,
+            'u' => Action::Undo,
+            'v' => Action::Visual(SelectionKind::Char),
+            'V' => Action::Visual(SelectionKind::Line),
+            _ => match Movement::parse(s, None) {
+                Parse::Complete(mv) => match counted(mv) {
+                    Some(mv) => Action::Move(mv),
+                    None => return Parse::Invalid
+                },
+                Parse::Incomplete => return Parse::Incomplete,
+                Parse::Invalid => return Parse::Invalid
+            }
+        })
     }
 
     fn execute(&self, app: &mut app::State) -> Result<Option<Box<Mode>>, Box<Error>> {
@@ -73,35 +184,51 @@ impl Action {
         // context this function is called in right now
         match self {
             &Action::Move(ref mv) => {
-                app.mutate_buf(|b| b.make_movement(mv.clone())); Ok(None)
+                // global marks can take the cursor to another buffer
+                let elsewhere = match mv {
+                    &Movement::Mark(c, _) => app.mark_buffer(c).and_then(|ix| if ix != app.current_buffer { Some(ix) } else { None }),
+                    _ => None
+                };
+                let mv = match elsewhere {
+                    Some(ix) => { app.move_to_buffer(ix); app.resolve_movement(mv)? },
+                    None => {
+                        let mv = app.resolve_movement(mv)?;
+                        if mv.is_jump() { app.push_jump(); }
+                        mv
+                    }
+                };
+                app.mutate_buf(|b| b.make_movement(mv)); Ok(None)
             },
             &Action::Delete(ref mv, ref r) => {
-                let v = app.mutate_buf(|b| b.delete_movement(mv.clone()));
+                let mv = app.resolve_movement(mv)?;
+                let v = app.mutate_buf(|b| b.delete_movement(mv));
                 app.push_clip(r, v);
                 Ok(None)
             },
             &Action::Change(ref mv, ref r) => {
-                let v = app.mutate_buf(|b| b.delete_movement(mv.clone())); 
+                let mv = app.resolve_movement(mv)?;
+                let v = app.mutate_buf(|b| b.delete_movement(mv)); 
                 app.push_clip(r, v);
-                Ok(Some(Box::new(InsertMode::new())))
+                Ok(Some(Box::new(InsertMode::new_recorded())))
             },
             &Action::Replace(c) => app.mutate_buf(|b| {
                 b.delete_char();
                 b.insert_char(c);
                 Ok(None)
             }),
-            &Action::Insert => Ok(Some(Box::new(InsertMode::new()))),
+            &Action::Insert => Ok(Some(Box::new(InsertMode::new_recorded()))),
             &Action::Command => Ok(Some(Box::new(CommandMode::new(app)))),
             &Action::Append => {
                 app.mutate_buf(|b| b.move_cursor((1,0)));
-                Ok(Some(Box::new(InsertMode::new())))
+                Ok(Some(Box::new(InsertMode::new_recorded())))
             },
             &Action::InsertLine => {
                 app.mutate_buf(|b| { b.insert_line(None) });
-                Ok(Some(Box::new(InsertMode::new())))
+                Ok(Some(Box::new(InsertMode::new_recorded())))
             },
             &Action::Yank(ref mv, ref r) => {
-                let v = app.mutate_buf(|b| b.yank_movement(mv.clone()));
+                let mv = app.resolve_movement(mv)?;
+                let v = app.mutate_buf(|b| b.yank_movement(mv));
                 app.push_clip(r, v);
                 Ok(None)
             },
@@ -118,35 +245,309 @@ impl Action {
                 }
                 Ok(None)
             },
-            _ => { Ok(None) }
+            &Action::Undo => {
+                app.mutate_buf(|b| b.undo());
+                Ok(None)
+            },
+            &Action::Redo => {
+                app.mutate_buf(|b| b.redo());
+                Ok(None)
+            },
+            &Action::Visual(kind) => Ok(Some(Box::new(VisualMode::new(app, kind)))),
+            &Action::Repeat(count) => {
+                let change = match app.last_change.clone() {
+                    Some(c) => c,
+                    None => return Ok(None)
+                };
+                let (action, times) = match (&change.action, count) {
+                    (&Action::Delete(ref mv, ref r), Some(n)) => (Action::Delete(with_count(mv, n), r.clone()), 1),
+                    (&Action::Change(ref mv, ref r), Some(n)) => (Action::Change(with_count(mv, n), r.clone()), 1),
+                    (a, n) => (a.clone(), n.unwrap_or(1))
+                };
+                if action.enters_insert_mode() {
+                    // instead of going into Insert mode, type what was typed last time
+                    action.execute(app)?;
+                    let text = change.inserted.unwrap_or(String::new());
+                    app.mutate_buf(|b| for _ in 0..times {
+                        for c in text.chars() {
+                            match c {
+                                '\n' => b.break_line(),
+                                '\t' => b.insert_tab(),
+                                c => b.insert_char(c)
+                            }
+                        }
+                    });
+                } else {
+                    for _ in 0..times { action.execute(app)?; }
+                }
+                // a new count is remembered for the next `.`
+                if count.is_some() {
+                    if let Some(ref mut c) = app.last_change {
+                        c.action = action;
+                    }
+                }
+                Ok(None)
+            },
+            &Action::SetMark(c) => {
+                app.set_mark(c);
+                Ok(None)
+            },
+            &Action::Jump(back, count) => {
+                app.jump(back, count);
+                Ok(None)
+            },
+            &Action::Definition(declaration) => {
+                app.goto_definition(declaration)?;
+                Ok(None)
+            },
+            &Action::References => {
+                app.find_references()?;
+                Ok(None)
+            },
+            &Action::Hover => {
+                app.hover()?;
+                Ok(None)
+            },
+            &Action::Record(ref r) => {
+                app.recording = Some((r.clone(), Vec::new()));
+                Ok(None)
+            },
+            &Action::Play(ref r, count) => {
+                let r = match r.clone().or(app.last_macro.clone()) {
+                    Some(r) => r,
+                    None => return Err(Box::new(CommandError::InvalidCommand(Some("no previous macro"))))
+                };
+                let text = app.top_clip(&r).ok_or(CommandError::InvalidCommand(Some("register is empty")))?;
+                let keys = if app.recorded.contains(&r) {
+                    Key::parse_seq(&text, &[])?
+                } else {
+                    // yanked text is typed as it is
+                    text.chars().map(|c| match c {
+                        '\n' => Key::Return,
+                        '\t' => Key::Tab,
+                        c => Key::Char(c)
+                    }).collect()
+                };
+                app.last_macro = Some(r);
+                if app.macro_depth >= MAX_MACRO_DEPTH {
+                    return Err(Box::new(CommandError::InvalidCommand(Some("macros nested too deeply"))));
+                }
+                app.macro_depth += 1;
+                // each time round carries on in the mode the last one finished in, and an error
+                // stops the rest
+                let mut r = Ok(Box::new(NormalMode::new()) as Box<Mode>);
+                for _ in 0..count {
+                    r = match r {
+                        Ok(mode) => NormalMode::feed_keys_to(mode, &keys, app),
+                        Err(_) => break
+                    };
+                }
+                app.macro_depth -= 1;
+                // stay in whatever mode the macro finished in
+                r.map(Some)
+            }
         }
     }
+
+    /// does this action change the buffer, so that `.` should repeat it?
+    fn is_change(&self) -> bool {
+        match self {
+            &Action::Delete(_, _) | &Action::Change(_, _) | &Action::Insert | &Action::InsertLine
+                | &Action::Append | &Action::Replace(_) | &Action::Put(_, _) => true,
+            _ => false
+        }
+    }
+
+    /// does this action leave the editor in Insert mode? If so, its undo group stays open until
+    /// the Insert mode session ends
+    fn enters_insert_mode(&self) -> bool {
+        match self {
+            &Action::Change(_, _) | &Action::Insert | &Action::InsertLine | &Action::Append => true,
+            _ => false
+        }
+    }
+
+    fn run(&self, app: &mut app::State) -> Result<Option<Box<Mode>>, Box<Error>> {
+        // the action might switch buffers, so the group is closed in the one it was opened in
+        let buf = app.buf();
+        buf.borrow_mut().begin_edit_group();
+        let r = self.execute(app);
+        if r.is_err() || !self.enters_insert_mode() {
+            buf.borrow_mut().end_edit_group();
+        }
+        // a change that failed, like a delete with a movement that went nowhere, isn't repeated
+        if r.is_ok() && self.is_change() {
+            app.last_change = Some(LastChange { action: self.clone(), inserted: None });
+        }
+        r
+    }
 }
 
 impl NormalMode {
     pub fn new() -> NormalMode {
         NormalMode { buf: String::new() }
     }
+
+    /// feed keys through the modes as if they had been typed, starting in Normal mode, and return
+    /// the mode they end up in
+    pub fn feed_keys(keys: &[Key], app: &mut app::State) -> Result<Box<Mode>, Box<Error>> {
+        NormalMode::feed_keys_to(Box::new(NormalMode::new()), keys, app)
+    }
+
+    /// feed keys to `mode`, returning the mode they end up in
+    fn feed_keys_to(mut mode: Box<Mode>, keys: &[Key], app: &mut app::State) -> Result<Box<Mode>, Box<Error>> {
+        for &k in keys {
+            match mode.event(k, app) {
+                Ok(Some(next)) => mode = next,
+                Ok(None) => {},
+                Err(e) => { mode.leave(app); return Err(e); }
+            }
+        }
+        Ok(mode)
+    }
+
+    /// run keys as if they had been typed in Normal mode, leaving whatever mode they end up in
+    /// afterwards (so `A;` appends a semicolon and returns to Normal mode)
+    pub fn run_keys(keys: &str, app: &mut app::State) -> Result<(), Box<Error>> {
+        let keys = keys.chars().map(Key::Char).collect::<Vec<_>>();
+        let mut mode = NormalMode::feed_keys(&keys, app)?;
+        mode.leave(app);
+        Ok(())
+    }
+
+    /// parse and run a complete action, like the ones SearchMode builds once the pattern is known
+    pub fn run_action(s: &str, app: &mut app::State) -> Result<Option<Box<Mode>>, Box<Error>> {
+        match Action::parse(s) {
+            Parse::Complete(a) => a.run(app),
+            _ => Err(Box::new(CommandError::InvalidCommand(Some("invalid action"))))
+        }
+    }
 }
 
 impl Mode for NormalMode {
-    fn event(&mut self, e: WindowEvent, app: &mut app::State) -> Result<Option<Box<Mode>>, Box<Error>> {
-        match e {
-            WindowEvent::ReceivedCharacter(c) => {
-                if !c.is_control() { self.buf.push(c); } 
-                if let Some(a) = Action::parse(&self.buf) {
-                    self.buf.clear();
-                    a.execute(app)
-                } else { Ok(None) }
+    fn event(&mut self, k: Key, app: &mut app::State) -> Result<Option<Box<Mode>>, Box<Error>> {
+        match k {
+            Key::Char('q') if self.buf.len() == 0 && app.recording.is_some() => {
+                let (r, mut keys) = app.recording.take().unwrap();
+                // the q that stopped the recording was recorded too
+                keys.pop();
+                app.push_clip(&r, keys.iter().map(|k| format!("{}", k)).collect::<String>());
+                app.recorded.insert(r);
+                Ok(None)
+            },
+            Key::Char(c) => {
+                self.buf.push(c);
+                match Action::parse(&self.buf) {
+                    Parse::Complete(a) => {
+                        self.buf.clear();
+                        a.run(app)
+                    },
+                    Parse::Incomplete if self.buf.ends_with('/') || self.buf.ends_with('?') => {
+                        // the pattern gets typed into the command line, then the whole action runs
+                        let forward = self.buf.ends_with('/');
+                        let prefix = String::from(&self.buf[0..self.buf.len()-1]);
+                        self.buf.clear();
+                        Ok(Some(Box::new(SearchMode::new(app, prefix, forward))))
+                    },
+                    Parse::Incomplete => Ok(None),
+                    // don't let keys that can't mean anything pile up
+                    Parse::Invalid => { self.buf.clear(); Ok(None) }
+                }
             }
-            WindowEvent::KeyboardInput { input: winit::KeyboardInput { virtual_keycode: Some(winit::VirtualKeyCode::Escape), .. }, .. } => {
+            Key::Ctrl('r') => {
+                self.buf.clear();
+                Action::Redo.run(app)
+            }
+            Key::Ctrl('o') | Key::Ctrl('i') | Key::Tab => {
+                let count = parse_count(&self.buf).map(|c| c.0.unwrap_or(1));
+                self.buf.clear();
+                match count {
+                    Some(n) => Action::Jump(k == Key::Ctrl('o'), n).run(app),
+                    None => Ok(None)
+                }
+            }
+            Key::Ctrl('v') => {
+                self.buf.clear();
+                Action::Visual(SelectionKind::Block).run(app)
+            }
+            Key::Return => {
+                // list buffers jump to the line's location
+                self.buf.clear();
+                app.follow_location().map(|_| None)
+            }
+            Key::Escape => {
                 self.buf.clear(); Ok(None)
             }
             _ => { Ok(None) }
         }
     }
     fn status_tag(&self) -> &str { "NORMAL" }
+    fn keymap(&self) -> &str { "normal" }
     fn pending_command(&self) -> Option<&str> { if self.buf.len() > 0 { Some(&self.buf) } else { None } }
 }
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use movement::Inclusion;
+
+    fn parsed(s: &str) -> String {
+        format!("{:?}", Action::parse(s))
+    }
+
+    fn complete(a: Action) -> String {
+        format!("{:?}", Parse::Complete(a))
+    }
+
+    #[test]
+    fn counts_multiply() {
+        assert_eq!(parsed("3d2w"), complete(Action::Delete(Movement::Rep(6, Box::new(Movement::Word(true, Inclusion::Exclusive))), ClipstackId('"'))));
+        assert_eq!(parsed("\"a3yy"), complete(Action::Yank(Movement::Rep(3, Box::new(Movement::Line(false, Inclusion::Inclusive))), ClipstackId('a'))));
+        assert_eq!(parsed("2\"b2x"), complete(Action::Delete(Movement::Rep(4, Box::new(Movement::Char(true))), ClipstackId('b'))));
+    }
+
+    #[test]
+    fn incomplete() {
+        for s in &["2", "2\"", "d", "\"a3", "3d2", "df", "g"] {
+            assert_eq!(parsed(s), "Incomplete", "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        for s in &["Z", "dZ", "m1", "gz", "d]x"] {
+            assert_eq!(parsed(s), "Invalid", "{:?}", s);
+        }
+    }
+
+    fn play(text: &str, register: &str, recorded: bool, keys: &str) -> String {
+        let mut app = app::State::with_text(text);
+        app.push_clip(&ClipstackId('a'), String::from(register));
+        if recorded { app.recorded.insert(ClipstackId('a')); }
+        let keys = keys.chars().map(Key::Char).collect::<Vec<_>>();
+        let r = NormalMode::feed_keys(&keys, &mut app);
+        let text = app.buf().borrow().full_text();
+        format!("{}{}", text, if r.is_err() { "error" } else { "" })
+    }
+
+    #[test]
+    fn macros() {
+        assert_eq!(play("abcdef", "x", false, "3@a"), "def\n");
+        // recorded keys are written like <Esc>, yanked text isn't
+        assert_eq!(play("ab", "ihi<Esc>x", true, "@a"), "hib\n");
+        assert_eq!(play("ab", "r<lr>", false, "@a"), "<>\n");
+        assert_eq!(play("a", "o\tb", false, "@a"), "a\n\tb\n");
+        // an error stops the rest, without making every copy of the keys first
+        assert_eq!(play("abcdef", "x'z", false, "999999999@a"), "bcdef\nerror");
+    }
+
+    #[test]
+    fn overflowing_counts() {
+        assert_eq!(parsed("99999999999999999999999dd"), "Invalid");
+        assert_eq!(parsed("3d99999999999999999999999w"), "Invalid");
+        assert_eq!(parsed("99999999999d99999999999w"), "Invalid");
+        assert_eq!(format!("{:?}", Movement::parse("99999999999999999999999j", None)), "Invalid");
+    }
+}
@@ -0,0 +1,120 @@
+use super::*;
+use runic::*;
+use completion::fuzzy_match;
+
+// Picker mode: choose one of a list of items, typing into the command line to narrow them down
+// Down, Ctrl-N, Tab: select the next matching item
+// Up, Ctrl-P: select the previous matching item
+// Return: choose the selected item
+// Escape: cancel
+
+/// what to do with the index of the item that was picked. Returning no mode goes back to Normal mode
+pub type PickAction = Box<FnMut(usize, &mut app::State) -> Result<Option<Box<Mode>>, Box<Error>>>;
+
+// how many items are shown at once
+const PICKER_ROWS: usize = 12;
+
+pub struct PickerMode {
+    inserter: InsertMode,
+    prompt: String,
+    items: Vec<String>,
+    // indices of the items that match what has been typed so far
+    matches: Vec<usize>,
+    selected: usize,
+    on_pick: PickAction
+}
+
+impl PickerMode {
+    pub fn new(app: &mut app::State, prompt: &str, items: Vec<String>, on_pick: PickAction) -> PickerMode {
+        {
+            let mut cmd = app.bufs[0].borrow_mut();
+            cmd.clear();
+            cmd.show_cursor = true;
+        }
+        let matches = (0..items.len()).collect();
+        PickerMode { inserter: InsertMode::new_with_target(0), prompt: String::from(prompt), items, matches, selected: 0, on_pick }
+    }
+
+    fn close(&self, app: &mut app::State) {
+        let mut cmd = app.bufs[0].borrow_mut();
+        cmd.show_cursor = false;
+        cmd.clear();
+    }
+
+    // narrow the items down to the ones that match what is typed in the command line
+    fn filter(&mut self, app: &app::State) {
+        let query = {
+            let cmd = app.bufs[0].borrow();
+            cmd.line(cmd.line_count()-1)
+        };
+        let items = &self.items;
+        self.matches = (0..items.len()).filter(|&i| fuzzy_match(&query, &items[i])).collect();
+        self.selected = 0;
+    }
+}
+
+impl Mode for PickerMode {
+    fn event(&mut self, k: Key, app: &mut app::State) -> Result<Option<Box<Mode>>, Box<Error>> {
+        let n = self.matches.len();
+        match k {
+            Key::Return => {
+                self.close(app);
+                let picked = match self.matches.get(self.selected) {
+                    Some(&i) => (self.on_pick)(i, app)?,
+                    None => None
+                };
+                Ok(Some(picked.unwrap_or_else(|| Box::new(NormalMode::new()))))
+            },
+            Key::Escape => {
+                self.close(app);
+                Ok(Some(Box::new(NormalMode::new())))
+            },
+            Key::Down | Key::Ctrl('n') | Key::Tab => {
+                if n > 0 { self.selected = (self.selected + 1) % n; }
+                Ok(None)
+            },
+            Key::Up | Key::Ctrl('p') => {
+                if n > 0 { self.selected = (self.selected + n - 1) % n; }
+                Ok(None)
+            },
+            _ => {
+                let r = self.inserter.event(k, app);
+                self.filter(app);
+                r
+            }
+        }
+    }
+
+    fn leave(&mut self, app: &mut app::State) {
+        self.close(app);
+    }
+
+    fn status_tag(&self) -> &str { "PICK" }
+    fn pending_command(&self) -> Option<&str> { Some(&self.prompt) }
+    fn keymap(&self) -> &str { "picker" }
+
+    // the matching items go in a list above the status line
+    fn paint(&mut self, rx: &mut RenderContext, app: &app::State, _cursor: Option<Rect>) {
+        let res = app.res.borrow();
+        let bnd = rx.bounds();
+        let first = if self.selected >= PICKER_ROWS { self.selected + 1 - PICKER_ROWS } else { 0 };
+        let rows = self.matches.iter().enumerate().skip(first).take(PICKER_ROWS)
+            .filter_map(|(n, &i)| rx.new_text_layout(&self.items[i], res.font(), bnd.w, 100.0).ok().map(|l| (n, l)))
+            .collect::<Vec<_>>();
+        let line_h = match rx.new_text_layout("M", res.font(), bnd.w, 100.0) { Ok(l) => l.bounds().h, Err(_) => return };
+        let h = rows.iter().map(|&(_, ref l)| l.bounds().h).sum::<f32>();
+        let mut y = bnd.h - line_h*2.2 - h;
+        rx.set_color(Color::rgb(0.18, 0.18, 0.2));
+        rx.fill_rect(Rect::xywh(0.0, y, bnd.w, h));
+        for (n, l) in rows {
+            let lh = l.bounds().h;
+            if n == self.selected {
+                rx.set_color(Color::rgb(0.3, 0.4, 0.6));
+                rx.fill_rect(Rect::xywh(0.0, y, bnd.w, lh));
+            }
+            rx.set_color(Color::rgb(0.9, 0.9, 0.9));
+            rx.draw_text_layout(Point::xy(4.0, y), &l);
+            y += lh;
+        }
+    }
+}
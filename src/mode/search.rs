@@ -0,0 +1,132 @@
+
+use super::*;
+use regex::Regex;
+use movement::{Movement, Parse};
+use buffer::SelectionKind;
+
+// Search mode: the pattern is typed into the command line, then the search runs as a movement,
+// either by itself or after an operator like d, c or y
+// Return: search for the pattern, or the last pattern if it is empty
+// Up/Down: step through previous searches
+// Escape: cancel
+// From Visual mode the search extends the selection, and Visual mode carries on afterwards
+
+pub struct SearchMode {
+    inserter: InsertMode,
+    forward: bool,
+    prompt: String, // the part of the action typed before the pattern, including / or ?
+    history_pos: usize,
+    // the kind of selection being made, when the search was started in Visual mode
+    visual: Option<SelectionKind>
+}
+
+impl SearchMode {
+    pub fn new(app: &mut app::State, prefix: String, forward: bool) -> SearchMode {
+        app.bufs[0].borrow_mut().show_cursor = true;
+        let prompt = format!("{}{}", prefix, if forward { '/' } else { '?' });
+        SearchMode { inserter: InsertMode::new_with_target(0), forward, prompt, history_pos: app.search_history.len(), visual: None }
+    }
+
+    /// a search that moves the cursor end of a Visual mode selection of this kind
+    pub fn new_visual(app: &mut app::State, prefix: String, forward: bool, kind: SelectionKind) -> SearchMode {
+        SearchMode { visual: Some(kind), ..SearchMode::new(app, prefix, forward) }
+    }
+
+    fn execute(&self, pattern: String, app: &mut app::State) -> Result<Option<Box<Mode>>, Box<Error>> {
+        let r = self.search(pattern, app);
+        // the mode goes back to Normal after an error, so the selection goes too
+        if r.is_err() && self.visual.is_some() { VisualMode::finish(app); }
+        r
+    }
+
+    fn search(&self, pattern: String, app: &mut app::State) -> Result<Option<Box<Mode>>, Box<Error>> {
+        let pattern = if pattern.len() == 0 {
+            match app.search_history.last() {
+                Some(p) => p.clone(),
+                None => return Err(Box::new(CommandError::InvalidCommand(Some("no previous search"))))
+            }
+        } else { pattern };
+        let re = Regex::new(&pattern)?;
+        if app.search_history.last() != Some(&pattern) {
+            app.search_history.push(pattern.clone());
+        }
+        app.search_regex = Some(re);
+        app.search_forward = self.forward;
+        let action = format!("{}{}\n", self.prompt, pattern);
+        match self.visual {
+            Some(kind) => {
+                let mv = match Movement::parse(&action, None) {
+                    Parse::Complete(mv) => app.resolve_movement(&mv)?,
+                    _ => return Err(Box::new(CommandError::InvalidCommand(Some("invalid search"))))
+                };
+                app.mutate_buf(|b| b.make_movement(mv));
+                Ok(Some(Box::new(VisualMode::resume(kind))))
+            },
+            None => NormalMode::run_action(&action, app)
+        }
+    }
+
+    fn show_history(&mut self, app: &mut app::State) {
+        let mut cmd = app.bufs[0].borrow_mut();
+        cmd.clear();
+        if let Some(p) = app.search_history.get(self.history_pos) {
+            cmd.insert_string(p);
+        }
+    }
+}
+
+impl Mode for SearchMode {
+    fn event(&mut self, k: Key, app: &mut app::State) -> Result<Option<Box<Mode>>, Box<Error>> {
+        match k {
+            Key::Return => {
+                let pattern = {
+                    let mut cmd = app.bufs[0].borrow_mut();
+                    let p = cmd.line(cmd.line_count()-1);
+                    cmd.show_cursor = false;
+                    cmd.clear();
+                    p
+                };
+                self.execute(pattern, app)
+            }
+            Key::Escape => {
+                let mut cmd = app.bufs[0].borrow_mut();
+                cmd.show_cursor = false;
+                cmd.clear();
+                match self.visual {
+                    Some(kind) => Ok(Some(Box::new(VisualMode::resume(kind)))),
+                    None => Ok(Some(Box::new(NormalMode::new())))
+                }
+            }
+            Key::Up => {
+                if self.history_pos > 0 {
+                    self.history_pos -= 1;
+                    self.show_history(app);
+                }
+                Ok(None)
+            }
+            Key::Down => {
+                if self.history_pos < app.search_history.len() {
+                    self.history_pos += 1;
+                    self.show_history(app);
+                }
+                Ok(None)
+            }
+            _ => self.inserter.event(k, app)
+        }
+    }
+
+    fn leave(&mut self, app: &mut app::State) {
+        {
+            let mut cmd = app.bufs[0].borrow_mut();
+            cmd.show_cursor = false;
+            cmd.clear();
+        }
+        if self.visual.is_some() { VisualMode::finish(app); }
+    }
+
+    fn status_tag(&self) -> &str { "SEARCH" }
+
+    fn keymap(&self) -> &str { "command" }
+
+    fn pending_command(&self) -> Option<&str> { Some(&self.prompt) }
+}
@@ -0,0 +1,227 @@
+
+use super::*;
+use movement::{Movement, Parse};
+use buffer::{SelectionKind, Selection};
+use app::ClipstackId;
+
+// Visual mode: movements extend a selection from where the mode started to the cursor
+// operators:
+// [reg]d, [reg]x: delete selection
+// [reg]c: change selection
+// [reg]y: yank selection
+// >, <: indent/outdent selected lines
+// ~: swap case
+// :: run a command on the selected lines ('<,'>)
+// o: move cursor to the other end of the selection
+// /, ?: search, extending the selection to the match
+// I, A: (block only) insert/append on every line of the block
+// v, V, Ctrl-V: switch selection kind, or leave Visual mode if it is already that kind
+
+pub struct VisualMode {
+    kind: SelectionKind,
+    buf: String
+}
+
+impl VisualMode {
+    pub fn new(app: &mut app::State, kind: SelectionKind) -> VisualMode {
+        app.mutate_buf(|b| {
+            let cur = b.curr_loc();
+            b.visual_anchor = Some((kind, cur));
+        });
+        VisualMode { kind, buf: String::new() }
+    }
+
+    /// come back to Visual mode with the selection that was being made, as after a search
+    pub fn resume(kind: SelectionKind) -> VisualMode {
+        VisualMode { kind, buf: String::new() }
+    }
+
+    fn switch_kind(&mut self, app: &mut app::State, kind: SelectionKind) -> Result<Option<Box<Mode>>, Box<Error>> {
+        if kind == self.kind {
+            return Ok(Some(self.exit(app)));
+        }
+        self.kind = kind;
+        app.mutate_buf(|b| {
+            if let Some((_, anchor)) = b.visual_anchor {
+                b.visual_anchor = Some((kind, anchor));
+            }
+        });
+        Ok(None)
+    }
+
+    /// leave Visual mode, remembering the selection for '<,'>
+    pub fn finish(app: &mut app::State) -> Option<Selection> {
+        app.mutate_buf(|b| {
+            let sel = b.selection();
+            if sel.is_some() { b.last_selection = sel; }
+            b.visual_anchor = None;
+            sel
+        })
+    }
+
+    fn exit(&mut self, app: &mut app::State) -> Box<Mode> {
+        VisualMode::finish(app);
+        Box::new(NormalMode::new())
+    }
+
+    fn operate(&mut self, op: char, reg: ClipstackId, app: &mut app::State) -> Result<Option<Box<Mode>>, Box<Error>> {
+        let sel = match VisualMode::finish(app) {
+            Some(s) => s,
+            None => return Ok(Some(Box::new(NormalMode::new())))
+        };
+        match op {
+            ':' => return Ok(Some(Box::new(CommandMode::new_with_text(app, "'<,'>")))),
+            'y' => {
+                let v = app.mutate_buf(|b| b.yank_selection(&sel));
+                app.push_clip(&reg, v);
+                app.mutate_buf(|b| b.place_cursor(sel.start.0, sel.start.1));
+                return Ok(Some(Box::new(NormalMode::new())));
+            },
+            _ => {}
+        }
+        app.mutate_buf(|b| b.begin_edit_group());
+        let next: Box<Mode> = match op {
+            'd' | 'x' => {
+                let v = app.mutate_buf(|b| b.delete_selection(&sel));
+                app.push_clip(&reg, v);
+                Box::new(NormalMode::new())
+            },
+            'c' => {
+                let v = app.mutate_buf(|b| b.delete_selection(&sel));
+                app.push_clip(&reg, v);
+                if sel.kind == SelectionKind::Block {
+                    let start = app.buf().borrow().cursor_col;
+                    return Ok(Some(Box::new(InsertMode::new_block(sel.start.1, sel.end.1, sel.start.0, start))));
+                } else if sel.kind == SelectionKind::Line {
+                    // keep an empty line to type into
+                    app.mutate_buf(|b| {
+                        let last = b.line_count()-1;
+                        if sel.start.1 <= last {
+                            if last > 0 || b.line_len(0) > 0 { b.insert_text((0, sel.start.1), "\n"); }
+                        } else {
+                            let len = b.line_len(last);
+                            b.insert_text((len, last), "\n");
+                        }
+                        b.place_cursor(0, sel.start.1);
+                    });
+                }
+                return Ok(Some(Box::new(InsertMode::new())));
+            },
+            'I' | 'A' if sel.kind == SelectionKind::Block => {
+                let col = if op == 'I' { sel.start.0 } else { sel.end.0 + 1 };
+                let start = app.mutate_buf(|b| {
+                    let start = b.byte_col(sel.start.1, col).unwrap_or(b.line_len(sel.start.1));
+                    b.place_cursor(start, sel.start.1);
+                    start
+                });
+                return Ok(Some(Box::new(InsertMode::new_block(sel.start.1, sel.end.1, col, start))));
+            },
+            '>' | '<' => {
+                app.mutate_buf(|b| b.indent_lines(sel.start.1, sel.end.1, op == '<'));
+                Box::new(NormalMode::new())
+            },
+            '~' => {
+                app.mutate_buf(|b| b.toggle_case_selection(&sel));
+                Box::new(NormalMode::new())
+            },
+            _ => Box::new(NormalMode::new())
+        };
+        app.mutate_buf(|b| b.end_edit_group());
+        Ok(Some(next))
+    }
+}
+
+impl Mode for VisualMode {
+    fn event(&mut self, k: Key, app: &mut app::State) -> Result<Option<Box<Mode>>, Box<Error>> {
+        match k {
+            Key::Char(c) => {
+                self.buf.push(c);
+                let mut cs = self.buf.chars();
+                let mut reg = ClipstackId('"');
+                let mut first = cs.next();
+                if first == Some('"') {
+                    match cs.next() {
+                        Some(r) => reg = ClipstackId(r),
+                        None => return Ok(None)
+                    }
+                    first = cs.next();
+                }
+                let op = match first {
+                    Some(op) => op,
+                    None => return Ok(None)
+                };
+                match op {
+                    'd' | 'x' | 'c' | 'y' | '>' | '<' | '~' | ':' | 'I' | 'A' => {
+                        if (op == 'I' || op == 'A') && self.kind != SelectionKind::Block {
+                            // not an operator outside of block selections
+                        } else {
+                            self.buf.clear();
+                            return self.operate(op, reg, app);
+                        }
+                    },
+                    'o' => {
+                        self.buf.clear();
+                        app.mutate_buf(|b| {
+                            if let Some((kind, anchor)) = b.visual_anchor {
+                                let cur = b.curr_loc();
+                                b.visual_anchor = Some((kind, cur));
+                                b.place_cursor(anchor.0, anchor.1);
+                            }
+                        });
+                        return Ok(None);
+                    },
+                    'v' => { self.buf.clear(); return self.switch_kind(app, SelectionKind::Char); },
+                    'V' => { self.buf.clear(); return self.switch_kind(app, SelectionKind::Line); },
+                    _ => {}
+                }
+                match Movement::parse(&self.buf, None) {
+                    Parse::Complete(mv) => {
+                        self.buf.clear();
+                        let mv = app.resolve_movement(&mv)?;
+                        app.mutate_buf(|b| b.make_movement(mv));
+                    },
+                    Parse::Incomplete if self.buf.ends_with('/') || self.buf.ends_with('?') => {
+                        // the pattern is typed into the command line, then the selection is
+                        // extended to the match
+                        let forward = self.buf.ends_with('/');
+                        let prefix = String::from(&self.buf[0..self.buf.len()-1]);
+                        self.buf.clear();
+                        return Ok(Some(Box::new(SearchMode::new_visual(app, prefix, forward, self.kind))));
+                    },
+                    Parse::Incomplete => {},
+                    Parse::Invalid => self.buf.clear()
+                }
+                Ok(None)
+            },
+            Key::Ctrl('v') => {
+                self.buf.clear();
+                self.switch_kind(app, SelectionKind::Block)
+            },
+            Key::Escape => {
+                if self.buf.len() > 0 {
+                    self.buf.clear();
+                    Ok(None)
+                } else {
+                    Ok(Some(self.exit(app)))
+                }
+            },
+            _ => Ok(None)
+        }
+    }
+
+    fn leave(&mut self, app: &mut app::State) {
+        VisualMode::finish(app);
+    }
+
+    fn status_tag(&self) -> &str {
+        match self.kind {
+            SelectionKind::Char => "VISUAL",
+            SelectionKind::Line => "VISUAL LINE",
+            SelectionKind::Block => "VISUAL BLOCK"
+        }
+    }
+
+    fn pending_command(&self) -> Option<&str> { if self.buf.len() > 0 { Some(&self.buf) } else { None } }
+
+    fn keymap(&self) -> &str { "visual" }
+}
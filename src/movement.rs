@@ -7,7 +7,16 @@
 // t[char]/T[char]: scan forward/backward for char, place cursor before/after it
 // $: end of line
 // ^: start of line
+// /[pattern]\n, ?[pattern]\n: search forward/backward for a regex, wrapping around the buffer
+// '[mark]/`[mark]: the line of a mark set with m (linewise), or exactly where it is
+// n/N: repeat the last search in the same/opposite direction
+// ]d/[d: the next/previous diagnostic from the language server
+// i[obj]/a[obj]: (after an operator) inner/around text object, see textobject.rs
 // <number>[mov]: repeated movement n times
+// [mov] after an operator: also dd/cc/yy for whole lines, and text objects
+
+use regex::Regex;
+use textobject::TextObject;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Inclusion {
@@ -26,6 +35,13 @@ pub enum Movement {
     },
     StartOfLine,
     EndOfLine,
+    Search { pattern: String, forward: bool },
+    SearchNext(bool /*reverse direction*/),
+    /// a search once its pattern has been compiled, by State::resolve_movement
+    Match(Regex, bool /*forward/backward*/),
+    Object(TextObject, bool /*inner/around*/),
+    Mark(char, bool /*exact position/line*/),
+    Diagnostic(bool /*forward/backward*/),
     Rep(usize, Box<Movement>)
 }
 
@@ -38,52 +54,128 @@ impl Movement {
             &Movement::CharScan { inclusion: i, .. } => i,
             &Movement::StartOfLine => Inclusion::Exclusive,
             &Movement::EndOfLine => Inclusion::Inclusive,
+            &Movement::Search { .. } => Inclusion::Exclusive,
+            &Movement::SearchNext(_) => Inclusion::Exclusive,
+            &Movement::Match(_, _) => Inclusion::Exclusive,
+            &Movement::Object(_, _) => Inclusion::Exclusive,
+            &Movement::Mark(_, exact) => if exact { Inclusion::Exclusive } else { Inclusion::Linewise },
+            &Movement::Diagnostic(_) => Inclusion::Exclusive,
             &Movement::Rep(_, ref mv) => mv.inclusion_mode()
         }
     }
-    pub fn parse(s: &str, first: bool) -> Option<Movement> {
-        //println!("parse movment {}", s);
+
+    /// charwise movements that can cross lines cover exactly the text between the start and the
+    /// end, rather than the whole lines in between
+    pub fn is_charwise(&self) -> bool {
+        match self {
+            &Movement::Search { .. } | &Movement::SearchNext(_) | &Movement::Match(_, _)
+                | &Movement::Mark(_, true) | &Movement::Diagnostic(_) => true,
+            &Movement::Object(obj, _) => !obj.is_linewise(),
+            &Movement::Rep(_, ref mv) => mv.is_charwise(),
+            _ => false
+        }
+    }
+    /// is this a jump that should be remembered in the jump list?
+    pub fn is_jump(&self) -> bool {
+        match self {
+            &Movement::Search { .. } | &Movement::SearchNext(_) | &Movement::Match(_, _)
+                | &Movement::Mark(_, _) => true,
+            &Movement::Rep(_, ref mv) => mv.is_jump(),
+            _ => false
+        }
+    }
+
+    /// the same movement done `count` more times, so counts multiply (2d3w deletes 6 words).
+    /// None if the count is too big
+    pub fn times(self, count: usize) -> Option<Movement> {
+        match self {
+            Movement::Rep(n, mv) => n.checked_mul(count).map(|n| Movement::Rep(n, mv)),
+            mv => Some(Movement::Rep(count, Box::new(mv)))
+        }
+    }
+
+    /// parse a movement, which may start with a count. `op` is the operator it follows, if any,
+    /// which enables text objects and doubled operators (dd, cc, yy) for whole lines
+    pub fn parse(s: &str, op: Option<char>) -> Parse<Movement> {
         use self::Movement::*;
+        let (count, s) = match parse_count(s) {
+            Some(c) => c,
+            None => return Parse::Invalid
+        };
         let mut cs = s.char_indices();
-        match cs.next() {
-            Some((i, c)) => {
-                if c.is_digit(10) {
-                    let start = i; let mut end = 0;
-                    for (j,c) in cs {
-                        if !c.is_digit(10) { end = j; break; }
-                    }
-                    let sp = s.split_at(end);
-                    sp.0.parse::<usize>().ok().and_then(|n| Movement::parse(sp.1,false).map(|m| Rep(n,Box::new(m)))) 
-                } else {
-                    match c {
-                        'h' => Some(Char(false)),
-                        'j' => Some(Line(false, Inclusion::Linewise)),
-                        'k' => Some(Line(true, Inclusion::Linewise)),
-                        'l' => Some(Char(true)),
-                        'w' => Some(Word(true, Inclusion::Exclusive)),
-                        'b' => Some(Word(false, Inclusion::Exclusive)),
-                        'e' => Some(Word(false, Inclusion::Inclusive)),
-                        '^' => Some(StartOfLine),
-                        'J' => Some(Line(false, Inclusion::Inclusive)),
-                        '$' => Some(EndOfLine),
-                        't' => cs.next().map(|(_,q)| CharScan { query: q, inclusion: Inclusion::Inclusive, direction: true, place_to_side: true }),
-                        'T' => cs.next().map(|(_,q)| CharScan { query: q, inclusion: Inclusion::Exclusive, direction: false, place_to_side: true }),
-                        'f' => cs.next().map(|(_,q)| CharScan { query: q, inclusion: Inclusion::Inclusive, direction: true, place_to_side: false }),
-                        'F' => cs.next().map(|(_,q)| CharScan { query: q, inclusion: Inclusion::Exclusive, direction: false, place_to_side: false }),
-                        _ => if !first {
-                            match c {
-                                'd' => Some(Line(false, Inclusion::Inclusive)),
-                                'c' => Some(Line(false, Inclusion::Inclusive)),
-                                'y' => Some(Line(false, Inclusion::Inclusive)),
-                                _ => None
-                            }
-                        } else { None }
-                    }
-                }
+        let mv = match cs.next() {
+            Some((i, c)) => match c {
+                'h' => Char(false),
+                'j' => Line(false, Inclusion::Linewise),
+                'k' => Line(true, Inclusion::Linewise),
+                'l' => Char(true),
+                'w' => Word(true, Inclusion::Exclusive),
+                'b' => Word(false, Inclusion::Exclusive),
+                'e' => Word(false, Inclusion::Inclusive),
+                '^' => StartOfLine,
+                'J' => Line(false, Inclusion::Inclusive),
+                '$' => EndOfLine,
+                'n' => SearchNext(false),
+                'N' => SearchNext(true),
+                '/' | '?' => match s[i+1..].find('\n') {
+                    Some(end) => Search { pattern: String::from(&s[i+1..i+1+end]), forward: c == '/' },
+                    None => return Parse::Incomplete
+                },
+                '\'' | '`' => match cs.next() {
+                    Some((_, m)) if m.is_ascii_alphabetic() => Mark(m, c == '`'),
+                    Some(_) => return Parse::Invalid,
+                    None => return Parse::Incomplete
+                },
+                ']' | '[' => match cs.next() {
+                    Some((_, 'd')) => Diagnostic(c == ']'),
+                    Some(_) => return Parse::Invalid,
+                    None => return Parse::Incomplete
+                },
+                't' | 'T' | 'f' | 'F' => match cs.next() {
+                    Some((_, q)) => CharScan {
+                        query: q,
+                        inclusion: if c == 't' || c == 'f' { Inclusion::Inclusive } else { Inclusion::Exclusive },
+                        direction: c == 't' || c == 'f',
+                        place_to_side: c == 't' || c == 'T'
+                    },
+                    None => return Parse::Incomplete
+                },
+                'i' | 'a' if op.is_some() => match cs.next() {
+                    Some((_, o)) => match TextObject::parse(o) {
+                        Some(o) => Object(o, c == 'i'),
+                        None => return Parse::Invalid
+                    },
+                    None => return Parse::Incomplete
+                },
+                c if Some(c) == op => Line(false, Inclusion::Inclusive),
+                _ => return Parse::Invalid
             },
-            None => None
-        }
+            None => return Parse::Incomplete
+        };
+        Parse::Complete(match count {
+            Some(n) => Movement::Rep(n, Box::new(mv)),
+            None => mv
+        })
     }
 }
 
+/// the result of parsing keys that may not all have been typed yet
+#[derive(Debug)]
+pub enum Parse<T> {
+    Complete(T),
+    /// could become valid with more keys
+    Incomplete,
+    /// can't become valid, however many keys are added
+    Invalid
+}
+
+/// split a count off the front of some keys. Counts can't start with 0. None if the count is too
+/// big to fit
+pub fn parse_count(s: &str) -> Option<(Option<usize>, &str)> {
+    if s.starts_with('0') { return Some((None, s)); }
+    let end = s.find(|c: char| !c.is_digit(10)).unwrap_or(s.len());
+    if end == 0 { return Some((None, s)); }
+    s[..end].parse::<usize>().ok().map(|n| (Some(n), &s[end..]))
+}
+
 
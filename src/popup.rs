@@ -0,0 +1,14 @@
+// panels of text drawn over the buffer by the cursor, like hover text and signature help
+
+use runic::*;
+
+/// draw a text layout on a panel with its top left corner at `at`. Returns the panel's bounds
+pub fn draw_text_panel(rx: &mut RenderContext, l: &TextLayout, at: Point, color: Color) -> Rect {
+    let b = l.bounds();
+    let panel = Rect::xywh(at.x, at.y, b.w + 8.0, b.h + 4.0);
+    rx.set_color(Color::rgb(0.22, 0.22, 0.24));
+    rx.fill_rect(panel);
+    rx.set_color(color);
+    rx.draw_text_layout(Point::xy(at.x + 4.0, at.y + 2.0), l);
+    panel
+}
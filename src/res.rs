@@ -7,7 +7,8 @@ use toml::Value;
 
 pub struct Resources {
     pub config: Option<Value>,
-    pub font: Font
+    // None only in tests, which have no window to make a font with
+    font: Option<Font>
 }
 
 impl Resources {
@@ -17,7 +18,16 @@ impl Resources {
         let font_size = font.and_then(|f| f.get("size").and_then(Value::as_float)).unwrap_or(14.0);
         Ok(Resources {
             config: config.clone(),
-            font: rx.new_font(font_name, font_size as f32, FontWeight::Regular, FontStyle::Normal)?,
+            font: Some(rx.new_font(font_name, font_size as f32, FontWeight::Regular, FontStyle::Normal)?),
         })
     }
+
+    #[cfg(test)]
+    pub fn without_font(config: Option<Value>) -> Resources {
+        Resources { config, font: None }
+    }
+
+    pub fn font(&self) -> &Font {
+        self.font.as_ref().expect("font loaded")
+    }
 }
@@ -0,0 +1,615 @@
+// A rope for storing buffer text. The text is kept in chunks of at most a few kilobytes, each of
+// which is a node in a treap ordered by position in the text. Every node also keeps a summary of
+// its whole subtree (bytes, chars, UTF-16 code units and newlines), so finding a line or converting
+// between kinds of offsets is a walk from the root, and inserting or removing text is a split and a
+// merge. All of those are O(log n) in the number of chunks.
+
+use std::ops::{Add, Range};
+
+const MAX_CHUNK: usize = 1024;
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub bytes: usize,
+    pub chars: usize,
+    pub utf16: usize,
+    pub newlines: usize
+}
+
+impl Summary {
+    fn of(s: &str) -> Summary {
+        let mut sm = Summary { bytes: s.len(), chars: 0, utf16: 0, newlines: 0 };
+        for c in s.chars() {
+            sm.chars += 1;
+            sm.utf16 += c.len_utf16();
+            if c == '\n' { sm.newlines += 1; }
+        }
+        sm
+    }
+}
+
+impl Add for Summary {
+    type Output = Summary;
+    fn add(self, o: Summary) -> Summary {
+        Summary {
+            bytes: self.bytes + o.bytes,
+            chars: self.chars + o.chars,
+            utf16: self.utf16 + o.utf16,
+            newlines: self.newlines + o.newlines
+        }
+    }
+}
+
+type Link = Option<Box<Node>>;
+
+#[derive(Debug)]
+struct Node {
+    text: String,
+    priority: u32,
+    own: Summary,
+    total: Summary,
+    left: Link,
+    right: Link
+}
+
+impl Node {
+    fn new(text: String, priority: u32) -> Box<Node> {
+        let own = Summary::of(&text);
+        Box::new(Node { text, priority, own, total: own, left: None, right: None })
+    }
+
+    fn update(&mut self) {
+        self.total = total(&self.left) + self.own + total(&self.right);
+    }
+}
+
+fn total(t: &Link) -> Summary {
+    t.as_ref().map_or(Summary::default(), |n| n.total)
+}
+
+/// split a tree into everything before the byte offset `at` and everything after it. A chunk that
+/// straddles `at` is cut in two
+fn split(t: Link, at: usize) -> (Link, Link) {
+    match t {
+        None => (None, None),
+        Some(mut n) => {
+            let lb = total(&n.left).bytes;
+            if at <= lb {
+                let (a, b) = split(n.left.take(), at);
+                n.left = b;
+                n.update();
+                (a, Some(n))
+            } else if at >= lb + n.text.len() {
+                let (a, b) = split(n.right.take(), at - lb - n.text.len());
+                n.right = a;
+                n.update();
+                (Some(n), b)
+            } else {
+                let tail = n.text.split_off(at - lb);
+                let mut m = Node::new(tail, n.priority);
+                m.right = n.right.take();
+                m.update();
+                n.own = Summary::of(&n.text);
+                n.update();
+                (Some(n), Some(m))
+            }
+        }
+    }
+}
+
+/// join two trees, where everything in `a` comes before everything in `b`
+fn merge(a: Link, b: Link) -> Link {
+    match (a, b) {
+        (None, b) => b,
+        (a, None) => a,
+        (Some(mut a), Some(mut b)) => {
+            if a.priority >= b.priority {
+                a.right = merge(a.right.take(), Some(b));
+                a.update();
+                Some(a)
+            } else {
+                b.left = merge(Some(a), b.left.take());
+                b.update();
+                Some(b)
+            }
+        }
+    }
+}
+
+// most edits are a few characters inside one chunk, so try to make those without restructuring
+fn insert_in_place(t: &mut Link, at: usize, text: &str) -> bool {
+    match *t {
+        None => false,
+        Some(ref mut n) => {
+            let lb = total(&n.left).bytes;
+            let done = if at < lb {
+                insert_in_place(&mut n.left, at, text)
+            } else if at <= lb + n.text.len() {
+                if n.text.len() + text.len() > MAX_CHUNK {
+                    false
+                } else {
+                    n.text.insert_str(at - lb, text);
+                    n.own = Summary::of(&n.text);
+                    true
+                }
+            } else {
+                insert_in_place(&mut n.right, at - lb - n.text.len(), text)
+            };
+            if done { n.update(); }
+            done
+        }
+    }
+}
+
+fn remove_in_place(t: &mut Link, r: Range<usize>) -> bool {
+    match *t {
+        None => false,
+        Some(ref mut n) => {
+            let lb = total(&n.left).bytes;
+            let done = if r.end <= lb {
+                remove_in_place(&mut n.left, r)
+            } else if r.start >= lb + n.text.len() {
+                let off = lb + n.text.len();
+                remove_in_place(&mut n.right, (r.start - off)..(r.end - off))
+            } else if r.start >= lb && r.end <= lb + n.text.len() && r.end - r.start < n.text.len() {
+                n.text.drain((r.start - lb)..(r.end - lb));
+                n.own = Summary::of(&n.text);
+                true
+            } else {
+                false
+            };
+            if done { n.update(); }
+            done
+        }
+    }
+}
+
+/// the summary of all the text before the byte offset `at`
+fn summary_before(t: &Link, at: usize) -> Summary {
+    match *t {
+        None => Summary::default(),
+        Some(ref n) => {
+            let lb = total(&n.left).bytes;
+            if at <= lb {
+                summary_before(&n.left, at)
+            } else if at <= lb + n.text.len() {
+                total(&n.left) + Summary::of(&n.text[..(at - lb)])
+            } else {
+                total(&n.left) + n.own + summary_before(&n.right, at - lb - n.text.len())
+            }
+        }
+    }
+}
+
+/// the byte offset where `target` units of the metric picked out by `metric` have gone by.
+/// `unit` gives the size of a single character in the same metric
+fn byte_at_metric<M, U>(t: &Link, target: usize, metric: &M, unit: &U) -> usize
+    where M: Fn(&Summary) -> usize, U: Fn(char) -> usize
+{
+    match *t {
+        None => 0,
+        Some(ref n) => {
+            let lm = metric(&total(&n.left));
+            let lb = total(&n.left).bytes;
+            if target <= lm {
+                byte_at_metric(&n.left, target, metric, unit)
+            } else if target <= lm + metric(&n.own) {
+                let mut m = lm;
+                for (i, c) in n.text.char_indices() {
+                    if m >= target { return lb + i; }
+                    m += unit(c);
+                }
+                lb + n.text.len()
+            } else {
+                lb + n.text.len() + byte_at_metric(&n.right, target - lm - metric(&n.own), metric, unit)
+            }
+        }
+    }
+}
+
+fn collect(t: &Link, offset: usize, r: &Range<usize>, out: &mut String) {
+    if let Some(ref n) = *t {
+        let lb = offset + total(&n.left).bytes;
+        let rb = lb + n.text.len();
+        if r.start < lb { collect(&n.left, offset, r, out); }
+        if r.start < rb && r.end > lb {
+            let s = if r.start > lb { r.start - lb } else { 0 };
+            let e = if r.end < rb { r.end - lb } else { n.text.len() };
+            out.push_str(&n.text[s..e]);
+        }
+        if r.end > rb { collect(&n.right, rb, r, out); }
+    }
+}
+
+/// cut a string into pieces no longer than a chunk, without splitting any characters
+fn chunk_str(mut s: &str) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    while s.len() > MAX_CHUNK {
+        let mut i = MAX_CHUNK;
+        while !s.is_char_boundary(i) { i -= 1; }
+        let (a, b) = s.split_at(i);
+        chunks.push(a);
+        s = b;
+    }
+    if s.len() > 0 { chunks.push(s); }
+    chunks
+}
+
+#[derive(Debug)]
+pub struct Rope {
+    root: Link,
+    seed: u32
+}
+
+impl Rope {
+    pub fn new() -> Rope {
+        Rope { root: None, seed: 0x2545_f491 }
+    }
+
+    pub fn from_str(s: &str) -> Rope {
+        let mut b = RopeBuilder::new();
+        b.push_str(s);
+        b.finish()
+    }
+
+    // xorshift, which is plenty random enough to keep the treap balanced
+    fn next_priority(&mut self) -> u32 {
+        let mut x = self.seed;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.seed = x;
+        x
+    }
+
+    pub fn len_bytes(&self) -> usize { total(&self.root).bytes }
+    pub fn len_chars(&self) -> usize { total(&self.root).chars }
+    pub fn len_utf16(&self) -> usize { total(&self.root).utf16 }
+    pub fn len_lines(&self) -> usize { total(&self.root).newlines + 1 }
+
+    /// insert text at a byte offset, which must be on a character boundary
+    pub fn insert(&mut self, at: usize, text: &str) {
+        if text.len() == 0 || insert_in_place(&mut self.root, at, text) { return; }
+        let (l, r) = split(self.root.take(), at);
+        let mut mid = None;
+        for c in chunk_str(text) {
+            let p = self.next_priority();
+            mid = merge(mid, Some(Node::new(String::from(c), p)));
+        }
+        self.root = merge(merge(l, mid), r);
+    }
+
+    /// remove the text in a range of byte offsets
+    pub fn remove(&mut self, r: Range<usize>) {
+        if r.start >= r.end || remove_in_place(&mut self.root, r.clone()) { return; }
+        let (l, rest) = split(self.root.take(), r.start);
+        let (_, rest) = split(rest, r.end - r.start);
+        self.root = merge(l, rest);
+    }
+
+    /// copy out the text in a range of byte offsets
+    pub fn slice(&self, r: Range<usize>) -> String {
+        let mut s = String::with_capacity(r.end.saturating_sub(r.start));
+        collect(&self.root, 0, &r, &mut s);
+        s
+    }
+
+    /// the byte offset where a line starts. Lines past the end start at the end of the text
+    pub fn line_to_byte(&self, line: usize) -> usize {
+        if line == 0 { return 0; }
+        if line > total(&self.root).newlines { return self.len_bytes(); }
+        byte_at_metric(&self.root, line, &|s: &Summary| s.newlines, &|c| if c == '\n' { 1 } else { 0 })
+    }
+
+    pub fn byte_to_line(&self, at: usize) -> usize {
+        summary_before(&self.root, at).newlines
+    }
+
+    pub fn byte_to_char(&self, at: usize) -> usize {
+        summary_before(&self.root, at).chars
+    }
+
+    pub fn char_to_byte(&self, c: usize) -> usize {
+        byte_at_metric(&self.root, c, &|s: &Summary| s.chars, &|_| 1)
+    }
+
+    pub fn byte_to_utf16(&self, at: usize) -> usize {
+        summary_before(&self.root, at).utf16
+    }
+
+    pub fn utf16_to_byte(&self, u: usize) -> usize {
+        byte_at_metric(&self.root, u, &|s: &Summary| s.utf16, &char::len_utf16)
+    }
+
+    /// the byte range of a line, not including its line break
+    pub fn line_range(&self, line: usize) -> Range<usize> {
+        let start = self.line_to_byte(line);
+        let end = if line + 1 < self.len_lines() { self.line_to_byte(line + 1) - 1 } else { self.len_bytes() };
+        start..end
+    }
+
+    /// the text of a line, without its line break
+    pub fn line(&self, line: usize) -> String {
+        self.slice(self.line_range(line))
+    }
+
+    pub fn line_len(&self, line: usize) -> usize {
+        let r = self.line_range(line);
+        r.end - r.start
+    }
+
+    /// iterate over the chunks of text in order
+    pub fn chunks(&self) -> Chunks {
+        let mut c = Chunks { stack: Vec::new() };
+        c.push_left(&self.root);
+        c
+    }
+
+    pub fn to_string(&self) -> String {
+        let mut s = String::with_capacity(self.len_bytes());
+        for c in self.chunks() { s.push_str(c); }
+        s
+    }
+}
+
+pub struct Chunks<'r> {
+    stack: Vec<&'r Node>
+}
+
+impl<'r> Chunks<'r> {
+    fn push_left(&mut self, mut t: &'r Link) {
+        while let Some(ref n) = *t {
+            self.stack.push(n);
+            t = &n.left;
+        }
+    }
+}
+
+impl<'r> Iterator for Chunks<'r> {
+    type Item = &'r str;
+    fn next(&mut self) -> Option<&'r str> {
+        match self.stack.pop() {
+            Some(n) => {
+                self.push_left(&n.right);
+                Some(&n.text)
+            },
+            None => None
+        }
+    }
+}
+
+/// builds a rope from text that arrives a piece at a time, like a file being read, in time linear
+/// in the length of the text
+pub struct RopeBuilder {
+    rope: Rope,
+    // right spine of the tree built so far, from the root down
+    spine: Vec<Box<Node>>,
+    pending: String
+}
+
+impl RopeBuilder {
+    pub fn new() -> RopeBuilder {
+        RopeBuilder { rope: Rope::new(), spine: Vec::new(), pending: String::new() }
+    }
+
+    pub fn push_str(&mut self, s: &str) {
+        self.pending.push_str(s);
+        while self.pending.len() >= MAX_CHUNK {
+            let mut i = MAX_CHUNK;
+            while !self.pending.is_char_boundary(i) { i -= 1; }
+            let rest = self.pending.split_off(i);
+            let chunk = ::std::mem::replace(&mut self.pending, rest);
+            self.push_chunk(chunk);
+        }
+    }
+
+    // the usual linear time Cartesian tree construction: the new node takes everything on the
+    // spine with a lower priority as its left child
+    fn push_chunk(&mut self, text: String) {
+        let p = self.rope.next_priority();
+        let mut n = Node::new(text, p);
+        let mut last: Link = None;
+        while self.spine.last().map_or(false, |top| top.priority < p) {
+            let mut top = self.spine.pop().unwrap();
+            top.right = last;
+            top.update();
+            last = Some(top);
+        }
+        n.left = last;
+        n.update();
+        self.spine.push(n);
+    }
+
+    pub fn finish(mut self) -> Rope {
+        if self.pending.len() > 0 {
+            let chunk = ::std::mem::replace(&mut self.pending, String::new());
+            self.push_chunk(chunk);
+        }
+        let mut last: Link = None;
+        while let Some(mut top) = self.spine.pop() {
+            top.right = last;
+            top.update();
+            last = Some(top);
+        }
+        self.rope.root = last;
+        self.rope
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // xorshift again, for text and edits that are the same every run
+    struct Rng(u32);
+
+    impl Rng {
+        fn next(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0 as usize % n
+        }
+
+        // text of one to four byte characters, with some line breaks
+        fn text(&mut self, chars: usize) -> String {
+            let cs = ['a', 'b', ' ', '\n', 'é', '中', '😀'];
+            (0..chars).map(|_| cs[self.next(cs.len())]).collect()
+        }
+
+        // a character boundary in `s`
+        fn boundary(&mut self, s: &str) -> usize {
+            let mut i = self.next(s.len() + 1);
+            while !s.is_char_boundary(i) { i -= 1; }
+            i
+        }
+    }
+
+    // check the summaries, chunk sizes and priorities of a tree
+    fn check_tree(t: &Link) -> Summary {
+        match *t {
+            None => Summary::default(),
+            Some(ref n) => {
+                assert!(n.text.len() <= MAX_CHUNK);
+                assert_eq!(n.own, Summary::of(&n.text));
+                for c in n.left.iter().chain(n.right.iter()) { assert!(c.priority <= n.priority); }
+                let sum = check_tree(&n.left) + n.own + check_tree(&n.right);
+                assert_eq!(n.total, sum);
+                sum
+            }
+        }
+    }
+
+    // the byte offsets where chunks meet, which is where edits are most likely to go wrong
+    fn chunk_boundaries(r: &Rope) -> Vec<usize> {
+        r.chunks().scan(0, |at, c| { *at += c.len(); Some(*at) }).collect()
+    }
+
+    // check a rope against the string it should hold, converting at every character boundary
+    fn check(r: &Rope, model: &str) {
+        check_tree(&r.root);
+        assert_eq!(r.to_string(), model);
+        assert_eq!(r.len_bytes(), model.len());
+        assert_eq!(r.len_chars(), model.chars().count());
+        assert_eq!(r.len_utf16(), model.encode_utf16().count());
+        assert_eq!(r.len_lines(), model.split('\n').count());
+        let (mut chars, mut utf16, mut lines) = (0, 0, 0);
+        for (i, c) in model.char_indices().chain(Some((model.len(), '\0'))) {
+            assert_eq!(r.byte_to_char(i), chars);
+            assert_eq!(r.char_to_byte(chars), i);
+            assert_eq!(r.byte_to_utf16(i), utf16);
+            assert_eq!(r.utf16_to_byte(utf16), i);
+            assert_eq!(r.byte_to_line(i), lines);
+            chars += 1;
+            utf16 += c.len_utf16();
+            if c == '\n' { lines += 1; }
+        }
+        let mut start = 0;
+        for (n, line) in model.split('\n').enumerate() {
+            assert_eq!(r.line_to_byte(n), start);
+            assert_eq!(r.line(n), line);
+            start += line.len() + 1;
+        }
+    }
+
+    #[test]
+    fn metrics() {
+        let r = Rope::from_str("aé\n中😀\nx");
+        assert_eq!((r.len_bytes(), r.len_chars(), r.len_utf16(), r.len_lines()), (13, 7, 8, 3));
+        assert_eq!(r.byte_to_utf16(7), 4);
+        assert_eq!(r.utf16_to_byte(6), 11);
+        assert_eq!(r.char_to_byte(5), 11);
+        assert_eq!(r.line_range(1), 4..11);
+        assert_eq!(r.line_to_byte(5), 13);
+        check(&r, "aé\n中😀\nx");
+        check(&Rope::new(), "");
+    }
+
+    #[test]
+    fn builder() {
+        let mut rng = Rng(1);
+        let text = rng.text(3000);
+        check(&Rope::from_str(&text), &text);
+        // pieces that cut characters in half are fine as long as they come back together
+        let mut b = RopeBuilder::new();
+        let bytes = text.as_bytes();
+        let mut i = 0;
+        while i < bytes.len() {
+            let mut e = (i + 1 + rng.next(700)).min(bytes.len());
+            while !text.is_char_boundary(e) { e += 1; }
+            b.push_str(&text[i..e]);
+            i = e;
+        }
+        check(&b.finish(), &text);
+    }
+
+    #[test]
+    fn split_and_merge() {
+        let mut rng = Rng(2);
+        let text = rng.text(2000);
+        let mut r = Rope::from_str(&text);
+        let mut at = chunk_boundaries(&r);
+        at.extend((0..20).map(|_| rng.boundary(&text)));
+        at.push(0);
+        for a in at {
+            let (left, right) = split(r.root.take(), a);
+            let (mut l, mut rt) = (String::new(), String::new());
+            collect(&left, 0, &(0..text.len()), &mut l);
+            collect(&right, 0, &(0..text.len()), &mut rt);
+            assert_eq!((check_tree(&left).bytes, l.as_str()), (a, &text[..a]));
+            assert_eq!(rt, &text[a..]);
+            r.root = merge(left, right);
+            check(&r, &text);
+        }
+    }
+
+    #[test]
+    fn in_place() {
+        let mut rng = Rng(3);
+        let text = rng.text(2000);
+        let mut r = Rope::from_str(&text);
+        let mut model = text.clone();
+        // where the first chunk ends, which is full, so only the next one can take more
+        let end = chunk_boundaries(&r)[0];
+        assert!(!insert_in_place(&mut r.root, end, &"x".repeat(MAX_CHUNK)));
+        let next = |s: &str, i: usize| i + s[i..].chars().next().map_or(0, char::len_utf8);
+        let inside = next(&model, end);
+        assert!(insert_in_place(&mut r.root, 0, "") && insert_in_place(&mut r.root, inside, "é"));
+        model.insert_str(inside, "é");
+        check(&r, &model);
+        // removing text across two chunks, or a whole chunk, isn't done in place
+        let mut before = end - 1;
+        while !model.is_char_boundary(before) { before -= 1; }
+        assert!(!remove_in_place(&mut r.root, before..inside));
+        let second = chunk_boundaries(&r)[1];
+        assert!(!remove_in_place(&mut r.root, end..second));
+        let to = next(&model, next(&model, inside));
+        assert!(remove_in_place(&mut r.root, inside..to));
+        model.drain(inside..to);
+        check(&r, &model);
+    }
+
+    #[test]
+    fn edits() {
+        let mut rng = Rng(4);
+        let mut model = rng.text(1000);
+        let mut r = Rope::from_str(&model);
+        for n in 0..150 {
+            let chunks = chunk_boundaries(&r);
+            let at = if n % 3 == 0 { chunks[rng.next(chunks.len())] } else { rng.boundary(&model) };
+            if rng.next(2) == 0 {
+                // mostly a few characters, sometimes more than a chunk
+                let len = if rng.next(10) == 0 { 600 } else { 1 + rng.next(5) };
+                let t = rng.text(len);
+                r.insert(at, &t);
+                model.insert_str(at, &t);
+            } else {
+                let mut end = (at + if rng.next(10) == 0 { 1500 } else { 1 + rng.next(8) }).min(model.len());
+                while !model.is_char_boundary(end) { end += 1; }
+                r.remove(at..end);
+                model.drain(at..end);
+            }
+            check(&r, &model);
+        }
+    }
+}
@@ -0,0 +1,145 @@
+// the signature help panel shown above the cursor in Insert mode, filled by
+// textDocument/signatureHelp. It opens when ( or , or one of the server's trigger characters is
+// typed inside a call, follows the cursor through the call's arguments, and closes once the
+// call's parentheses are balanced
+
+use runic::*;
+use json::JsonValue;
+
+use buffer::Buffer;
+use lsp::{self, PendingResponse};
+use popup;
+
+// how far back to look for the ( that opens the call the cursor is in
+const MAX_CALL_LINES: usize = 50;
+
+pub struct SignatureHelp {
+    response: PendingResponse,
+    help: JsonValue,
+    // where the ( of the call is
+    open: (usize, usize),
+    // which argument the cursor is in, counted from the ( to the cursor
+    argument: usize
+}
+
+// the ( of the innermost call that `to` is inside, looking back a limited number of lines
+fn open_paren(buf: &Buffer, to: (usize, usize)) -> Option<(usize, usize)> {
+    let mut depth = 0;
+    let first = to.1.saturating_sub(MAX_CALL_LINES);
+    for line in (first..(to.1+1)).rev() {
+        let text = buf.line(line);
+        let end = if line == to.1 { to.0.min(text.len()) } else { text.len() };
+        for (i, c) in text[..end].char_indices().rev() {
+            match c {
+                ')' => depth += 1,
+                '(' if depth == 0 => return Some((i, line)),
+                '(' => depth -= 1,
+                _ => {}
+            }
+        }
+    }
+    None
+}
+
+// how many commas there are at the top level of a call between its ( and `to`, or None if the
+// call is closed before `to`
+fn argument_at(buf: &Buffer, open: (usize, usize), to: (usize, usize)) -> Option<usize> {
+    if (to.1, to.0) <= (open.1, open.0) { return None; }
+    let mut depth = 0;
+    let mut argument = 0;
+    for line in open.1..(to.1+1) {
+        let text = buf.line(line);
+        let start = if line == open.1 { open.0 + 1 } else { 0 };
+        let end = if line == to.1 { to.0.min(text.len()) } else { text.len() };
+        for c in text[start.min(end)..end].chars() {
+            match c {
+                '(' | '[' | '{' => depth += 1,
+                ')' if depth == 0 => return None,
+                ')' | ']' | '}' => depth -= 1,
+                ',' if depth == 0 => argument += 1,
+                _ => {}
+            }
+        }
+    }
+    Some(argument)
+}
+
+impl SignatureHelp {
+    /// ask the buffer's first language server that can help with signatures about the call the
+    /// cursor is in. `trigger` is the character that caused the request, if any
+    pub fn request(buf: &mut Buffer, trigger: Option<char>) -> Option<SignatureHelp> {
+        let server = buf.language_server(|c| c.signature_help.is_some())?;
+        let uri = buf.uri()?;
+        let cur = buf.curr_loc();
+        let open = open_paren(buf, cur)?;
+        buf.sync_language_server(true);
+        let response = PendingResponse::request(server, "textDocument/signatureHelp", object!{
+            "textDocument" => object!{ "uri" => uri },
+            "position" => lsp::position_to_json(buf.lsp_position(cur)),
+            "context" => match trigger {
+                Some(c) => object!{ "triggerKind" => 2, "triggerCharacter" => c.to_string(), "isRetrigger" => false },
+                None => object!{ "triggerKind" => 1, "isRetrigger" => false }
+            }
+        }, "signature help");
+        Some(SignatureHelp { response, help: JsonValue::Null, open, argument: 0 })
+    }
+
+    /// should typing `c` ask for signature help? ( and , always do, as well as the server's own
+    /// trigger characters
+    pub fn is_trigger(buf: &Buffer, c: char) -> bool {
+        let ls = match buf.language_server(|caps| caps.signature_help.is_some()) { Some(ls) => ls, None => return false };
+        if c == '(' || c == ',' { return true; }
+        let ls = ls.borrow();
+        lsp::is_trigger(&ls.capabilities.signature_help, c)
+    }
+
+    /// take in the response if it has arrived, and follow the cursor through the arguments.
+    /// Returns false once the panel should close
+    pub fn update(&mut self, buf: &Buffer) -> bool {
+        if let Some(r) = self.response.take() {
+            // the server has nothing to say about this spot
+            if r["signatures"].len() == 0 { return false; }
+            self.help = r;
+        }
+        match argument_at(buf, self.open, buf.curr_loc()) {
+            Some(a) => { self.argument = a; true },
+            None => false
+        }
+    }
+
+    /// stop waiting for the server
+    pub fn close(self) {
+        self.response.cancel();
+    }
+
+    pub fn paint(&self, rx: &mut RenderContext, font: &Font, cursor: Rect) {
+        let sigs = &self.help["signatures"];
+        if sigs.len() == 0 { return; }
+        let sig = &sigs[self.help["activeSignature"].as_usize().unwrap_or(0).min(sigs.len()-1)];
+        let label = sig["label"].as_str().unwrap_or("");
+        let params = &sig["parameters"];
+        // the active parameter comes from the cursor. Past the last one, as with variadic
+        // functions, the last one stays active
+        let active = if params.len() > 0 { self.argument.min(params.len()-1) } else { self.argument };
+        let range = match params[active]["label"] {
+            JsonValue::Array(ref r) if r.len() == 2 => {
+                // UTF-16 offsets into the label
+                let (s, e) = (r[0].as_usize().unwrap_or(0), r[1].as_usize().unwrap_or(0));
+                Some(lsp::utf16_to_byte_col(label, s)..lsp::utf16_to_byte_col(label, e))
+            },
+            ref p => p.as_str().and_then(|p| label.find(p).map(|s| s..(s + p.len())))
+        };
+        let doc = params[active]["documentation"].as_str().or(params[active]["documentation"]["value"].as_str())
+            .or(sig["documentation"].as_str()).or(sig["documentation"]["value"].as_str());
+        let text = match doc {
+            Some(d) => format!("{}\n{}", label, d.lines().next().unwrap_or("")),
+            None => String::from(label)
+        };
+        let l = match rx.new_text_layout(&text, font, 600.0, 200.0) { Ok(l) => l, Err(_) => return };
+        if let Some(r) = range {
+            l.color_range(rx, (r.start as u32)..(r.end as u32), Color::rgb(0.9, 0.7, 0.3));
+        }
+        let y = (cursor.y - l.bounds().h - 4.0).max(0.0);
+        popup::draw_text_panel(rx, &l, Point::xy(cursor.x, y), Color::rgb(0.85, 0.85, 0.85));
+    }
+}
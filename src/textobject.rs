@@ -0,0 +1,355 @@
+// text objects, which select a piece of text around the cursor for an operator, like `diw` or `ca(`.
+// The i (inner) forms leave out the surrounding whitespace/delimiters, the a (around) forms
+// include them:
+// w, W: word, WORD (anything between whitespace)
+// s: sentence
+// p: paragraph (whole lines)
+// ( ) b, [ ], { } B, < >: bracket pairs
+// " ' `: quotes, on the current line
+// t: XML/HTML tag pair
+
+use std::ops::Range;
+use regex::Regex;
+
+thread_local! {
+    // an opening, closing or self-closing tag, for t
+    static TAG: Regex = Regex::new(r"<(/?)([A-Za-z][^\s/>]*)[^>]*?(/?)>").unwrap();
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TextObject {
+    Word,
+    BigWord,
+    Sentence,
+    Paragraph,
+    Pair(char, char),
+    Quote(char),
+    Tag
+}
+
+#[derive(PartialEq, Eq)]
+enum CharClass { Space, Word, Punct }
+
+fn class(c: char, big: bool) -> CharClass {
+    if c.is_whitespace() { CharClass::Space }
+    else if big || c.is_alphanumeric() || c == '_' { CharClass::Word }
+    else { CharClass::Punct }
+}
+
+// the start of the char before byte `i`
+fn prev_char(s: &str, i: usize) -> Option<(usize, char)> {
+    s[..i].char_indices().next_back()
+}
+
+// extend from `i` while chars satisfy `pred`, returning the first byte that doesn't (or the end)
+fn scan_forward<P: Fn(char)->bool>(s: &str, i: usize, pred: P) -> usize {
+    s[i..].char_indices().find(|&(_, c)| !pred(c)).map(|(j, _)| i + j).unwrap_or(s.len())
+}
+
+// extend back from `i` while chars satisfy `pred`, returning the start of the run
+fn scan_backward<P: Fn(char)->bool>(s: &str, i: usize, pred: P) -> usize {
+    s[..i].char_indices().rev().find(|&(_, c)| !pred(c)).map(|(j, c)| j + c.len_utf8()).unwrap_or(0)
+}
+
+fn is_blank_line(l: &str) -> bool {
+    l.chars().all(char::is_whitespace)
+}
+
+impl TextObject {
+    /// the object for the character typed after i/a
+    pub fn parse(c: char) -> Option<TextObject> {
+        use self::TextObject::*;
+        match c {
+            'w' => Some(Word),
+            'W' => Some(BigWord),
+            's' => Some(Sentence),
+            'p' => Some(Paragraph),
+            '(' | ')' | 'b' => Some(Pair('(', ')')),
+            '[' | ']' => Some(Pair('[', ']')),
+            '{' | '}' | 'B' => Some(Pair('{', '}')),
+            '<' | '>' => Some(Pair('<', '>')),
+            '"' | '\'' | '`' => Some(Quote(c)),
+            't' => Some(Tag),
+            _ => None
+        }
+    }
+
+    /// is the object made of whole lines?
+    pub fn is_linewise(&self) -> bool {
+        *self == TextObject::Paragraph
+    }
+
+    /// the byte range of the object around `pos` in `text`. Paragraphs go from the start of their
+    /// first line to the start of the line after their last one (or the end of the text)
+    pub fn find(&self, text: &str, pos: usize, inner: bool) -> Option<Range<usize>> {
+        if pos > text.len() { return None; }
+        match *self {
+            TextObject::Word => TextObject::word(text, pos, inner, false),
+            TextObject::BigWord => TextObject::word(text, pos, inner, true),
+            TextObject::Sentence => TextObject::sentence(text, pos, inner),
+            TextObject::Paragraph => TextObject::paragraph(text, pos, inner),
+            TextObject::Pair(open, close) => TextObject::pair(text, pos, inner, open, close),
+            TextObject::Quote(q) => TextObject::quote(text, pos, inner, q),
+            TextObject::Tag => TextObject::tag(text, pos, inner)
+        }
+    }
+
+    fn word(text: &str, pos: usize, inner: bool, big: bool) -> Option<Range<usize>> {
+        let line_start = text[..pos].rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let line_end = text[pos..].find('\n').map(|i| pos + i).unwrap_or(text.len());
+        let line = &text[line_start..line_end];
+        let p = pos - line_start;
+        let c = match line[p..].chars().next() {
+            Some(c) => c,
+            None => return None
+        };
+        let cls = class(c, big);
+        let mut start = scan_backward(line, p, |c| class(c, big) == cls);
+        let mut end = scan_forward(line, p, |c| class(c, big) == cls);
+        if !inner {
+            if cls == CharClass::Space {
+                // the whitespace and the word after it
+                if let Some(n) = line[end..].chars().next() {
+                    let ncls = class(n, big);
+                    end = scan_forward(line, end, |c| class(c, big) == ncls);
+                }
+            } else {
+                let trailing = scan_forward(line, end, char::is_whitespace);
+                if trailing > end {
+                    end = trailing;
+                } else {
+                    start = scan_backward(line, start, char::is_whitespace);
+                }
+            }
+        }
+        Some((line_start + start)..(line_start + end))
+    }
+
+    fn sentence(text: &str, pos: usize, inner: bool) -> Option<Range<usize>> {
+        // does a sentence end just before byte i?
+        let ends_at = |i: usize| -> bool {
+            let before = scan_backward(text, i, |c| c == ')' || c == ']' || c == '"' || c == '\'');
+            match prev_char(text, before) {
+                Some((_, c)) => (c == '.' || c == '!' || c == '?') && text[i..].chars().next().map(char::is_whitespace).unwrap_or(true),
+                None => false
+            }
+        };
+
+        // back to the end of the previous sentence or paragraph
+        let mut start = pos;
+        while start > 0 && !ends_at(start) && !text[..start].ends_with("\n\n") {
+            start = prev_char(text, start).map(|(i, _)| i).unwrap_or(0);
+        }
+        start = scan_forward(text, start, char::is_whitespace).min(pos);
+
+        let mut end = start;
+        while end < text.len() && !text[end..].starts_with("\n\n") {
+            end += text[end..].chars().next().map(|c| c.len_utf8()).unwrap_or(1);
+            if ends_at(end) { break; }
+        }
+        if !inner {
+            let trailing = scan_forward(text, end, |c| c == ' ' || c == '\t' || c == '\n');
+            // don't run into the next paragraph
+            end = text[end..trailing].find("\n\n").map(|i| end + i).unwrap_or(trailing);
+        }
+        Some(start..end)
+    }
+
+    fn paragraph(text: &str, pos: usize, inner: bool) -> Option<Range<usize>> {
+        let lines = text.split('\n').collect::<Vec<_>>();
+        // the byte each line starts at
+        let mut starts = Vec::with_capacity(lines.len() + 1);
+        let mut b = 0;
+        for l in lines.iter() {
+            starts.push(b);
+            b += l.len() + 1;
+        }
+        let line = text[..pos].matches('\n').count();
+        let blank = is_blank_line(lines[line]);
+        let mut first = line;
+        while first > 0 && is_blank_line(lines[first-1]) == blank { first -= 1; }
+        let mut last = line;
+        while last + 1 < lines.len() && is_blank_line(lines[last+1]) == blank { last += 1; }
+        if !inner {
+            // take the blank lines after the paragraph, or before it if there are none after
+            if last + 1 < lines.len() {
+                last += 1;
+                while last + 1 < lines.len() && is_blank_line(lines[last+1]) != blank { last += 1; }
+            } else {
+                while first > 0 && is_blank_line(lines[first-1]) != blank { first -= 1; }
+            }
+        }
+        let end = if last + 1 < lines.len() { starts[last+1] } else { text.len() };
+        Some(starts[first]..end)
+    }
+
+    fn pair(text: &str, pos: usize, inner: bool, open: char, close: char) -> Option<Range<usize>> {
+        // find the unmatched open bracket at or before the cursor
+        let mut depth = 0;
+        let mut open_at = None;
+        let mut i = pos + text[pos..].chars().next().map(|c| c.len_utf8()).unwrap_or(0);
+        while let Some((j, c)) = prev_char(text, i) {
+            if c == close && j != pos {
+                depth += 1;
+            } else if c == open {
+                if depth == 0 { open_at = Some(j); break; }
+                depth -= 1;
+            }
+            i = j;
+        }
+        let open_at = match open_at { Some(o) => o, None => return None };
+
+        let mut depth = 0;
+        let body = open_at + open.len_utf8();
+        for (j, c) in text[body..].char_indices() {
+            if c == open {
+                depth += 1;
+            } else if c == close {
+                if depth == 0 {
+                    let close_at = body + j;
+                    return Some(if inner { body..close_at } else { open_at..(close_at + close.len_utf8()) });
+                }
+                depth -= 1;
+            }
+        }
+        None
+    }
+
+    fn quote(text: &str, pos: usize, inner: bool, q: char) -> Option<Range<usize>> {
+        let line_start = text[..pos].rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let line_end = text[pos..].find('\n').map(|i| pos + i).unwrap_or(text.len());
+        let line = &text[line_start..line_end];
+        let p = pos - line_start;
+
+        let mut quotes = Vec::new();
+        let mut escaped = false;
+        for (i, c) in line.char_indices() {
+            if escaped { escaped = false; continue; }
+            if c == '\\' { escaped = true; }
+            else if c == q { quotes.push(i); }
+        }
+        // quotes pair up from the start of the line. Use the pair around the cursor, or else the
+        // first one after it
+        let (open, close) = match quotes.chunks(2).filter(|c| c.len() == 2).find(|c| p <= c[1]) {
+            Some(c) => (c[0], c[1]),
+            None => return None
+        };
+        let (mut start, mut end) = (open, close + q.len_utf8());
+        if inner {
+            start += q.len_utf8();
+            end = close;
+        } else {
+            let trailing = scan_forward(line, end, char::is_whitespace);
+            if trailing > end {
+                end = trailing;
+            } else {
+                start = scan_backward(line, start, char::is_whitespace);
+            }
+        }
+        Some((line_start + start)..(line_start + end))
+    }
+
+    fn tag(text: &str, pos: usize, inner: bool) -> Option<Range<usize>> {
+        TAG.with(|re| TextObject::tag_with(re, text, pos, inner))
+    }
+
+    fn tag_with(re: &Regex, text: &str, pos: usize, inner: bool) -> Option<Range<usize>> {
+        let mut open_tags: Vec<(String, Range<usize>)> = Vec::new();
+        for caps in re.captures_iter(text) {
+            let tag = caps.get(0).unwrap();
+            let (closing, self_closing) = (&caps[1] == "/", &caps[3] == "/");
+            if self_closing { continue; }
+            if !closing {
+                open_tags.push((String::from(&caps[2]), tag.start()..tag.end()));
+                continue;
+            }
+            // anything opened since the matching tag was never closed (like <br>), so drop it
+            let k = match open_tags.iter().rposition(|&(ref name, _)| name == &caps[2]) {
+                Some(k) => k,
+                None => continue
+            };
+            let open = open_tags[k].1.clone();
+            open_tags.truncate(k);
+            // inner pairs close first, so the first pair around the cursor is the innermost one
+            if open.start <= pos && pos < tag.end() {
+                return Some(if inner { open.end..tag.start() } else { open.start..tag.end() });
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::TextObject::*;
+
+    // the inner and around ranges of an object with the cursor at `pos`
+    fn both(o: TextObject, text: &str, pos: usize) -> (Option<Range<usize>>, Option<Range<usize>>) {
+        (o.find(text, pos, true), o.find(text, pos, false))
+    }
+
+    #[test]
+    fn words() {
+        let text = "foo.bar  baz";
+        assert_eq!(both(Word, text, 1), (Some(0..3), Some(0..3)));
+        // punctuation is a word of its own
+        assert_eq!(both(Word, text, 3), (Some(3..4), Some(3..4)));
+        assert_eq!(both(Word, text, 4), (Some(4..7), Some(4..9)));
+        // on whitespace, aw takes the word after it
+        assert_eq!(both(Word, text, 7), (Some(7..9), Some(7..12)));
+        // with nothing after the last word, aw takes the whitespace before it
+        assert_eq!(both(Word, text, 10), (Some(9..12), Some(7..12)));
+        assert_eq!(both(BigWord, text, 3), (Some(0..7), Some(0..9)));
+        assert_eq!(both(Word, "ab\ncd", 4), (Some(3..5), Some(3..5)));
+    }
+
+    #[test]
+    fn sentences() {
+        let text = "One two. Three four!  Five.\n\nNext para.";
+        assert_eq!(both(Sentence, text, 11), (Some(9..20), Some(9..22)));
+        // the spaces after the last sentence of a paragraph don't go into the next one
+        assert_eq!(both(Sentence, text, 23), (Some(22..27), Some(22..27)));
+        assert_eq!(both(Sentence, text, 30), (Some(29..39), Some(29..39)));
+    }
+
+    #[test]
+    fn paragraphs() {
+        let text = "a\nb\n\nc\nd";
+        assert_eq!(both(Paragraph, text, 0), (Some(0..4), Some(0..5)));
+        // at the end of the text, ap takes the blank lines before
+        assert_eq!(both(Paragraph, text, 5), (Some(5..8), Some(4..8)));
+        assert_eq!(both(Paragraph, text, 4), (Some(4..5), Some(4..8)));
+    }
+
+    #[test]
+    fn nested_pairs() {
+        let text = "f(a, (b), c)";
+        let parens = Pair('(', ')');
+        assert_eq!(both(parens, text, 6), (Some(6..7), Some(5..8)));
+        // on a bracket, the pair it belongs to
+        assert_eq!(both(parens, text, 5), (Some(6..7), Some(5..8)));
+        assert_eq!(both(parens, text, 7), (Some(6..7), Some(5..8)));
+        assert_eq!(both(parens, text, 8), (Some(2..11), Some(1..12)));
+        assert_eq!(both(parens, text, 0), (None, None));
+    }
+
+    #[test]
+    fn escaped_quotes() {
+        let text = "say \"a \\\"b\\\" c\" x";
+        assert_eq!(both(Quote('"'), text, 9), (Some(5..14), Some(4..16)));
+        // before any quotes, the first pair on the line
+        assert_eq!(both(Quote('"'), text, 0), (Some(5..14), Some(4..16)));
+        assert_eq!(both(Quote('\''), text, 9), (None, None));
+    }
+
+    #[test]
+    fn tags() {
+        // <br> is never closed, so it doesn't get in the way of the div
+        let text = "<div>a<br>b</div>";
+        assert_eq!(both(Tag, text, 10), (Some(5..11), Some(0..17)));
+        let text = "<p><b>x</b> y</p>";
+        assert_eq!(both(Tag, text, 6), (Some(6..7), Some(3..11)));
+        assert_eq!(both(Tag, text, 12), (Some(3..13), Some(0..17)));
+    }
+}
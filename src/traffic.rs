@@ -0,0 +1,155 @@
+// a record of the JSON-RPC traffic with a language server, for :lsp log. The latest entries are
+// kept in memory, and all of them can be mirrored to a trace file too. The log belongs to the
+// server's config rather than its process, so it lives through restarts
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use json::JsonValue;
+
+// how much of a message is kept in memory. The trace file gets all of it
+const MAX_ENTRY_LEN: usize = 2048;
+// requests that haven't been answered, past which they are given up on
+const MAX_OPEN_REQUESTS: usize = 1000;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Sent,
+    Received
+}
+
+pub struct Entry {
+    pub at: SystemTime,
+    pub direction: Direction,
+    /// the method of a request or notification, or of the request a response answers
+    pub method: Option<String>,
+    pub text: String
+}
+
+pub struct TrafficLog {
+    entries: VecDeque<Entry>,
+    capacity: usize,
+    // the methods of requests each way that haven't been answered yet, by id
+    requests: HashMap<(Direction, String), String>,
+    trace: Option<File>
+}
+
+// the UTC time of day, to the millisecond
+fn time_of_day(t: SystemTime) -> String {
+    let d = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let s = d.as_secs() % (24*60*60);
+    format!("{:02}:{:02}:{:02}.{:03}", s / 3600, s / 60 % 60, s % 60, d.subsec_millis())
+}
+
+impl Entry {
+    // one line of the log
+    fn line(&self, text: &str) -> String {
+        format!("{} {} {} {}", time_of_day(self.at), match self.direction { Direction::Sent => "-->", Direction::Received => "<--" },
+            self.method.as_ref().map_or("-", |m| m.as_str()), text)
+    }
+}
+
+impl TrafficLog {
+    /// a log that keeps the last `capacity` entries, appending all of them to `trace` if it is given
+    pub fn new(capacity: usize, trace: Option<&Path>) -> io::Result<TrafficLog> {
+        let trace = match trace {
+            Some(p) => Some(OpenOptions::new().create(true).append(true).open(p)?),
+            None => None
+        };
+        Ok(TrafficLog { entries: VecDeque::new(), capacity, requests: HashMap::new(), trace })
+    }
+
+    /// record a message going to or coming from the server
+    pub fn message(&mut self, direction: Direction, msg: &JsonValue) {
+        let id = if msg.has_key("id") { Some(msg["id"].dump()) } else { None };
+        let method = match (msg["method"].as_str(), id) {
+            (Some(m), Some(id)) => {
+                if self.requests.len() >= MAX_OPEN_REQUESTS { self.requests.clear(); }
+                self.requests.insert((direction, id), String::from(m));
+                Some(String::from(m))
+            },
+            (Some(m), None) => Some(String::from(m)),
+            // a response, which went the other way from its request
+            (None, Some(id)) => {
+                let request = if direction == Direction::Sent { Direction::Received } else { Direction::Sent };
+                self.requests.remove(&(request, id))
+            },
+            (None, None) => None
+        };
+        self.push(Entry { at: SystemTime::now(), direction, method, text: msg.dump() });
+    }
+
+    /// record something that isn't a message, like a protocol error or the connection closing
+    pub fn note(&mut self, direction: Direction, text: &str) {
+        self.push(Entry { at: SystemTime::now(), direction, method: None, text: String::from(text) });
+    }
+
+    fn push(&mut self, mut e: Entry) {
+        // a trace file that can't be written to any more is given up on
+        let traced = match self.trace {
+            Some(ref mut f) => writeln!(f, "{}", e.line(&e.text)).is_ok(),
+            None => true
+        };
+        if !traced { self.trace = None; }
+        if e.text.len() > MAX_ENTRY_LEN {
+            let mut end = MAX_ENTRY_LEN;
+            while !e.text.is_char_boundary(end) { end -= 1; }
+            e.text.truncate(end);
+            e.text.push_str(" …");
+        }
+        if self.entries.len() >= self.capacity { self.entries.pop_front(); }
+        self.entries.push_back(e);
+    }
+
+    /// the log as text, one line for each entry
+    pub fn text(&self) -> String {
+        self.entries.iter().map(|e| e.line(&e.text)).collect::<Vec<_>>().join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn methods(log: &TrafficLog) -> Vec<(Direction, Option<&str>)> {
+        log.entries.iter().map(|e| (e.direction, e.method.as_ref().map(|m| m.as_str()))).collect()
+    }
+
+    #[test]
+    fn keeps_the_latest() {
+        let mut log = TrafficLog::new(2, None).unwrap();
+        for t in &["one", "two", "three"] { log.note(Direction::Sent, t); }
+        assert_eq!(log.entries.iter().map(|e| e.text.as_str()).collect::<Vec<_>>(), vec!["two", "three"]);
+        assert_eq!(log.text().lines().count(), 2);
+    }
+
+    #[test]
+    fn responses_get_their_request_method() {
+        let mut log = TrafficLog::new(10, None).unwrap();
+        log.message(Direction::Sent, &object!{ "id" => 1, "method" => "textDocument/hover" });
+        log.message(Direction::Received, &object!{ "id" => 1, "method" => "workspace/configuration" });
+        log.message(Direction::Received, &object!{ "method" => "window/logMessage" });
+        log.message(Direction::Sent, &object!{ "id" => 1, "result" => array![] });
+        log.message(Direction::Received, &object!{ "id" => 1, "result" => json::Null });
+        // answered requests are forgotten
+        log.message(Direction::Received, &object!{ "id" => 1, "result" => json::Null });
+        assert_eq!(methods(&log), vec![
+            (Direction::Sent, Some("textDocument/hover")), (Direction::Received, Some("workspace/configuration")),
+            (Direction::Received, Some("window/logMessage")), (Direction::Sent, Some("workspace/configuration")),
+            (Direction::Received, Some("textDocument/hover")), (Direction::Received, None)]);
+        assert!(log.text().lines().nth(3).unwrap().contains(" --> workspace/configuration {\"id\":1,\"result\":[]}"));
+    }
+
+    #[test]
+    fn long_entries_cut_at_a_char_boundary() {
+        let mut log = TrafficLog::new(10, None).unwrap();
+        // the limit falls in the middle of an é
+        let text = format!("a{}", "é".repeat(MAX_ENTRY_LEN));
+        log.note(Direction::Received, &text);
+        let e = &log.entries[0].text;
+        assert!(e.ends_with(" …"));
+        assert_eq!(&e[..e.len() - " …".len()], &text[..MAX_ENTRY_LEN - 1]);
+    }
+}
@@ -0,0 +1,210 @@
+// undo history: every change to a buffer's text is recorded as an Edit, which knows how to undo
+// itself. Edits are collected into groups, one per Normal mode action or Insert mode session, and
+// those groups are what `u` and Ctrl-R walk back and forth through.
+
+/// A single reversible change to the text of a buffer. Locations are (col, line) like the rest of
+/// Buffer, and the text may contain newlines.
+#[derive(Debug, Clone)]
+pub enum Edit {
+    Insert { at: (usize, usize), text: String },
+    Delete { at: (usize, usize), text: String }
+}
+
+impl Edit {
+    /// the edit that exactly reverses this one
+    pub fn inverse(&self) -> Edit {
+        match self {
+            &Edit::Insert { at, ref text } => Edit::Delete { at, text: text.clone() },
+            &Edit::Delete { at, ref text } => Edit::Insert { at, text: text.clone() }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EditGroup {
+    pub edits: Vec<Edit>,
+    pub cursor_before: (usize, usize),
+    pub cursor_after: (usize, usize)
+}
+
+#[derive(Debug)]
+pub struct History {
+    undo_stack: Vec<EditGroup>,
+    redo_stack: Vec<EditGroup>,
+    open: Option<EditGroup>,
+    depth: usize // how many begin_group calls are waiting for their end_group
+}
+
+impl History {
+    pub fn new() -> History {
+        History { undo_stack: Vec::new(), redo_stack: Vec::new(), open: None, depth: 0 }
+    }
+
+    /// start collecting edits into one group. Groups nest, so a command that runs other commands
+    /// (like :g) is still undone in one step: only the outermost end_group closes the group
+    pub fn begin_group(&mut self, cursor: (usize, usize)) {
+        self.depth += 1;
+        if self.open.is_none() {
+            self.open = Some(EditGroup { edits: Vec::new(), cursor_before: cursor, cursor_after: cursor });
+        }
+    }
+
+    /// close the current group, dropping it if nothing was actually changed
+    pub fn end_group(&mut self, cursor: (usize, usize)) {
+        if self.depth > 1 {
+            self.depth -= 1;
+        } else {
+            self.close_group(cursor);
+        }
+    }
+
+    fn close_group(&mut self, cursor: (usize, usize)) {
+        self.depth = 0;
+        if let Some(mut g) = self.open.take() {
+            if g.edits.len() > 0 {
+                g.cursor_after = cursor;
+                self.undo_stack.push(g);
+            }
+        }
+    }
+
+    pub fn is_group_open(&self) -> bool {
+        self.open.is_some()
+    }
+
+    /// record an edit that has just been applied. `cursor` is where the cursor was before the edit,
+    /// which is used if this edit ends up in a group by itself
+    pub fn record(&mut self, e: Edit, cursor: (usize, usize)) {
+        self.redo_stack.clear();
+        match self.open {
+            Some(ref mut g) => g.edits.push(e),
+            None => self.undo_stack.push(EditGroup { edits: vec![e], cursor_before: cursor, cursor_after: cursor })
+        }
+    }
+
+    /// take the most recent group to be undone. The caller is responsible for applying the inverse
+    /// of its edits and then handing it back with `push_redo`
+    pub fn pop_undo(&mut self, cursor: (usize, usize)) -> Option<EditGroup> {
+        self.close_group(cursor);
+        self.undo_stack.pop()
+    }
+
+    pub fn pop_redo(&mut self, cursor: (usize, usize)) -> Option<EditGroup> {
+        self.close_group(cursor);
+        self.redo_stack.pop()
+    }
+
+    pub fn push_undo(&mut self, g: EditGroup) {
+        self.undo_stack.push(g);
+    }
+
+    pub fn push_redo(&mut self, g: EditGroup) {
+        self.redo_stack.push(g);
+    }
+
+    pub fn clear(&mut self) {
+        self.undo_stack.clear();
+        self.redo_stack.clear();
+        self.open = None;
+        self.depth = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ins(col: usize, text: &str) -> Edit {
+        Edit::Insert { at: (col, 0), text: String::from(text) }
+    }
+
+    fn texts(g: &EditGroup) -> Vec<String> {
+        g.edits.iter().map(|e| match e {
+            &Edit::Insert { ref text, .. } => format!("+{}", text),
+            &Edit::Delete { ref text, .. } => format!("-{}", text)
+        }).collect()
+    }
+
+    #[test]
+    fn inverse() {
+        match ins(1, "ab").inverse().inverse() {
+            Edit::Insert { at, text } => assert_eq!((at, text.as_str()), ((1, 0), "ab")),
+            e => panic!("{:?}", e)
+        }
+    }
+
+    #[test]
+    fn grouping() {
+        let mut h = History::new();
+        // outside a group every edit is its own group
+        h.record(ins(0, "a"), (0, 0));
+        h.record(ins(1, "b"), (1, 0));
+        h.begin_group((2, 0));
+        h.record(ins(2, "c"), (2, 0));
+        h.record(ins(3, "d"), (3, 0));
+        assert!(h.is_group_open());
+        h.end_group((4, 0));
+        assert!(!h.is_group_open());
+        // a group with nothing in it isn't kept
+        h.begin_group((4, 0));
+        h.end_group((4, 0));
+        let g = h.pop_undo((4, 0)).unwrap();
+        assert_eq!(texts(&g), ["+c", "+d"]);
+        assert_eq!((g.cursor_before, g.cursor_after), ((2, 0), (4, 0)));
+        let g = h.pop_undo((2, 0)).unwrap();
+        assert_eq!(texts(&g), ["+b"]);
+        assert_eq!((g.cursor_before, g.cursor_after), ((1, 0), (1, 0)));
+        assert_eq!(texts(&h.pop_undo((1, 0)).unwrap()), ["+a"]);
+        assert!(h.pop_undo((0, 0)).is_none());
+    }
+
+    #[test]
+    fn nested_groups() {
+        let mut h = History::new();
+        h.begin_group((0, 0));
+        h.record(ins(0, "a"), (0, 0));
+        h.begin_group((1, 0));
+        h.record(ins(1, "b"), (1, 0));
+        h.end_group((2, 0));
+        // only the outermost end_group closes it
+        assert!(h.is_group_open());
+        h.record(ins(2, "c"), (2, 0));
+        h.end_group((3, 0));
+        assert!(!h.is_group_open());
+        let g = h.pop_undo((3, 0)).unwrap();
+        assert_eq!(texts(&g), ["+a", "+b", "+c"]);
+        assert_eq!((g.cursor_before, g.cursor_after), ((0, 0), (3, 0)));
+        assert!(h.pop_undo((0, 0)).is_none());
+    }
+
+    #[test]
+    fn undo_closes_an_open_group() {
+        let mut h = History::new();
+        h.begin_group((0, 0));
+        h.record(ins(0, "a"), (0, 0));
+        let g = h.pop_undo((1, 0)).unwrap();
+        assert_eq!(g.cursor_after, (1, 0));
+        assert!(!h.is_group_open());
+        // the end_group of the interrupted group does nothing
+        h.end_group((5, 0));
+        assert!(h.pop_undo((0, 0)).is_none());
+    }
+
+    #[test]
+    fn redo() {
+        let mut h = History::new();
+        h.record(ins(0, "a"), (0, 0));
+        h.record(ins(1, "b"), (1, 0));
+        let g = h.pop_undo((2, 0)).unwrap();
+        h.push_redo(g);
+        let g = h.pop_redo((1, 0)).unwrap();
+        assert_eq!(texts(&g), ["+b"]);
+        h.push_undo(g);
+        let g = h.pop_undo((2, 0)).unwrap();
+        h.push_redo(g);
+        // a new edit throws away what could have been redone
+        h.record(ins(1, "c"), (1, 0));
+        assert!(h.pop_redo((2, 0)).is_none());
+        assert_eq!(texts(&h.pop_undo((2, 0)).unwrap()), ["+c"]);
+    }
+}